@@ -0,0 +1,402 @@
+// ===== Block Cache =====
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+
+use log::trace;
+
+use crate::err::SdError;
+
+use super::{BlockDevice, check_range};
+
+/// Geometry and policy of a `BlockCache`
+#[derive(Debug, Clone, Copy)]
+pub struct CacheConfig {
+    chunk_blocks: usize,
+    chunks: usize,
+    read_ahead: usize,
+    write_back: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            chunk_blocks: 8,
+            chunks: 64,
+            read_ahead: 4,
+            write_back: false,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Blocks per cache line, at most 64
+    pub fn with_chunk_blocks(mut self, blocks: usize) -> Self {
+        self.chunk_blocks = blocks.clamp(1, 64);
+        self
+    }
+
+    /// Number of cache lines kept
+    pub fn with_chunks(mut self, chunks: usize) -> Self {
+        self.chunks = chunks.max(1);
+        self
+    }
+
+    /// Chunks fetched ahead once reads are found to be sequential, 0 disables
+    pub fn with_read_ahead(mut self, chunks: usize) -> Self {
+        self.read_ahead = chunks;
+        self
+    }
+
+    /// Keep writes in the cache until flushed or evicted instead of writing
+    /// through. Off by default, dirty blocks are lost if the cache is dropped
+    /// without `flush` or `into_inner`.
+    pub fn with_write_back(mut self, enable: bool) -> Self {
+        self.write_back = enable;
+        self
+    }
+}
+
+struct Chunk {
+    data: Vec<u8>,
+    // One bit per block not yet written to the device
+    dirty: u64,
+    last_used: u64,
+}
+
+/// LRU cache of aligned chunks in front of a block device.
+///
+/// Writes go through to the device unless write-back is enabled in the
+/// config. Dirty blocks are then written back on `flush`, on eviction and by
+/// `into_inner`, with adjacent blocks merged into one multi-block write.
+/// Dirty data still cached when the cache is dropped is lost.
+pub struct BlockCache<D: BlockDevice> {
+    dev: D,
+    config: CacheConfig,
+    chunks: BTreeMap<u64, Chunk>,
+    tick: u64,
+    // Block following the last read, to detect sequential access
+    next_read: u64,
+}
+
+impl<D: BlockDevice> BlockCache<D> {
+    pub fn new(dev: D, config: CacheConfig) -> Self {
+        Self {
+            dev,
+            config,
+            chunks: BTreeMap::new(),
+            tick: 0,
+            next_read: u64::MAX,
+        }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    /// Write back dirty blocks and hand the device back
+    pub fn into_inner(mut self) -> Result<D, SdError> {
+        self.write_back()?;
+        Ok(self.dev)
+    }
+
+    /// Drop all cached data, dirty blocks included
+    pub fn invalidate(&mut self) {
+        self.chunks.clear();
+    }
+
+    /// Write all dirty blocks to the device, merging adjacent blocks. Blocks
+    /// stay dirty until the write covering them has succeeded.
+    pub fn write_back(&mut self) -> Result<(), SdError> {
+        let block_size = self.dev.block_size();
+        let chunk_blocks = self.config.chunk_blocks as u64;
+
+        // Dirty blocks in ascending order, as chunk and block within the chunk
+        let dirty: Vec<(u64, usize)> = self
+            .chunks
+            .iter()
+            .flat_map(|(index, chunk)| {
+                (0..chunk.data.len() / block_size)
+                    .filter(|i| chunk.dirty & (1 << i) != 0)
+                    .map(|i| (*index, i))
+            })
+            .collect();
+        let block_of = |(index, i): (u64, usize)| index * chunk_blocks + i as u64;
+
+        let mut rest = &dirty[..];
+        while let Some(&first) = rest.first() {
+            let run_start = block_of(first);
+            let len = rest
+                .iter()
+                .enumerate()
+                .take_while(|(n, b)| block_of(**b) == run_start + *n as u64)
+                .count();
+            let (run, tail) = rest.split_at(len);
+
+            let mut data = Vec::with_capacity(len * block_size);
+            for &(index, i) in run {
+                data.extend_from_slice(&self.chunks[&index].data[i * block_size..][..block_size]);
+            }
+
+            trace!("Cache write back: {} blocks at {}", len, run_start);
+            self.dev.write_blocks(run_start, &data)?;
+
+            for &(index, i) in run {
+                if let Some(chunk) = self.chunks.get_mut(&index) {
+                    chunk.dirty &= !(1 << i);
+                }
+            }
+            rest = tail;
+        }
+
+        Ok(())
+    }
+
+    // First block and length in blocks of chunk `index`, the last chunk may
+    // be cut short by the end of the device
+    fn chunk_span(&self, index: u64) -> (u64, usize) {
+        let start = index * self.config.chunk_blocks as u64;
+        let blocks = (self.dev.num_blocks() - start).min(self.config.chunk_blocks as u64);
+        (start, blocks as usize)
+    }
+
+    fn touch(&mut self, index: u64) -> Option<&mut Chunk> {
+        self.tick += 1;
+        let tick = self.tick;
+        let chunk = self.chunks.get_mut(&index)?;
+        chunk.last_used = tick;
+        Some(chunk)
+    }
+
+    // Make room for `count` more chunks
+    fn reserve(&mut self, count: usize) -> Result<(), SdError> {
+        while self.chunks.len() + count > self.config.chunks {
+            let Some((&victim, chunk)) = self.chunks.iter().min_by_key(|(_, c)| c.last_used) else {
+                break;
+            };
+
+            if chunk.dirty != 0 {
+                // Writing everything back lets neighbours of the victim share a command
+                self.write_back()?;
+            }
+            self.chunks.remove(&victim);
+        }
+
+        Ok(())
+    }
+
+    fn insert(&mut self, index: u64, data: Vec<u8>) {
+        self.tick += 1;
+        self.chunks.insert(
+            index,
+            Chunk {
+                data,
+                dirty: 0,
+                last_used: self.tick,
+            },
+        );
+    }
+
+    // Bring chunk `index` into the cache, with read-ahead for sequential reads
+    fn fetch(&mut self, index: u64, read_ahead: bool) -> Result<(), SdError> {
+        if self.touch(index).is_some() {
+            return Ok(());
+        }
+
+        let block_size = self.dev.block_size();
+        let last = self
+            .dev
+            .num_blocks()
+            .div_ceil(self.config.chunk_blocks as u64);
+
+        // Extend over following chunks that are not cached yet
+        let mut count = 1;
+        if read_ahead {
+            let max = (1 + self.config.read_ahead).min(self.config.chunks);
+            while count < max
+                && index + (count as u64) < last
+                && !self.chunks.contains_key(&(index + count as u64))
+            {
+                count += 1;
+            }
+        }
+
+        self.reserve(count)?;
+
+        let (start, _) = self.chunk_span(index);
+        let (end_start, end_blocks) = self.chunk_span(index + count as u64 - 1);
+        let blocks = (end_start - start) as usize + end_blocks;
+
+        let mut buf = vec![0u8; blocks * block_size];
+        self.dev.read_blocks(start, &mut buf)?;
+        trace!("Cache fill: {} blocks at {}", blocks, start);
+
+        let chunk_len = self.config.chunk_blocks * block_size;
+        for (i, data) in buf.chunks(chunk_len).enumerate() {
+            self.insert(index + i as u64, data.to_vec());
+        }
+
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for BlockCache<D> {
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.dev.num_blocks()
+    }
+
+    fn read_only(&self) -> bool {
+        self.dev.read_only()
+    }
+
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), SdError> {
+        let blocks = check_range(self, block_id, buf.len())?;
+        let block_size = self.dev.block_size();
+        let chunk_blocks = self.config.chunk_blocks as u64;
+
+        let read_ahead = block_id == self.next_read && self.config.read_ahead > 0;
+        self.next_read = block_id + blocks;
+
+        let mut block = block_id;
+        let mut done = 0;
+        while done < buf.len() {
+            let index = block / chunk_blocks;
+            let offset = (block % chunk_blocks) as usize;
+            self.fetch(index, read_ahead)?;
+
+            let chunk = &self.chunks[&index];
+            let n = (chunk.data.len() - offset * block_size).min(buf.len() - done);
+            buf[done..done + n].copy_from_slice(&chunk.data[offset * block_size..][..n]);
+
+            done += n;
+            block += (n / block_size) as u64;
+        }
+
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> Result<(), SdError> {
+        check_range(self, block_id, buf.len())?;
+        if self.read_only() {
+            return Err(SdError::WriteProtected);
+        }
+
+        let block_size = self.dev.block_size();
+        let chunk_blocks = self.config.chunk_blocks as u64;
+
+        if !self.config.write_back {
+            self.dev.write_blocks(block_id, buf)?;
+        }
+
+        let mut block = block_id;
+        let mut done = 0;
+        while done < buf.len() {
+            let index = block / chunk_blocks;
+            let offset = (block % chunk_blocks) as usize;
+            let (_, span) = self.chunk_span(index);
+            let n = ((span - offset) * block_size).min(buf.len() - done);
+
+            if self.touch(index).is_none() {
+                if !self.config.write_back {
+                    // Write-through does not allocate
+                    done += n;
+                    block += (n / block_size) as u64;
+                    continue;
+                }
+
+                if n == span * block_size {
+                    // The whole chunk is overwritten, no need to read it first
+                    self.reserve(1)?;
+                    self.insert(index, vec![0u8; n]);
+                } else {
+                    self.fetch(index, false)?;
+                }
+            }
+
+            let write_back = self.config.write_back;
+            let chunk = self.chunks.get_mut(&index).ok_or(SdError::IoError)?;
+            chunk.data[offset * block_size..][..n].copy_from_slice(&buf[done..done + n]);
+            if write_back {
+                for i in offset..offset + n / block_size {
+                    chunk.dirty |= 1 << i;
+                }
+            }
+
+            done += n;
+            block += (n / block_size) as u64;
+        }
+
+        Ok(())
+    }
+
+    fn write_blocks_fua(&mut self, block_id: u64, buf: &[u8]) -> Result<(), SdError> {
+        check_range(self, block_id, buf.len())?;
+        if self.read_only() {
+            return Err(SdError::WriteProtected);
+        }
+
+        let block_size = self.dev.block_size();
+        let chunk_blocks = self.config.chunk_blocks as u64;
+
+        // Update cached copies, they no longer need writing back
+        let mut block = block_id;
+        let mut done = 0;
+        while done < buf.len() {
+            let index = block / chunk_blocks;
+            let offset = (block % chunk_blocks) as usize;
+            let (_, span) = self.chunk_span(index);
+            let n = ((span - offset) * block_size).min(buf.len() - done);
+
+            if let Some(chunk) = self.touch(index) {
+                chunk.data[offset * block_size..][..n].copy_from_slice(&buf[done..done + n]);
+                for i in offset..offset + n / block_size {
+                    chunk.dirty &= !(1 << i);
+                }
+            }
+
+            done += n;
+            block += (n / block_size) as u64;
+        }
+
+        self.dev.write_blocks_fua(block_id, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), SdError> {
+        self.write_back()?;
+        self.dev.flush()
+    }
+
+    fn discard(&mut self, block_id: u64, blocks: u64) -> Result<(), SdError> {
+        let end = block_id
+            .checked_add(blocks)
+            .ok_or(SdError::InvalidArgument)?;
+        if end > self.num_blocks() {
+            return Err(SdError::InvalidArgument);
+        }
+
+        // Cached blocks in the range are dropped, pending writes to them too
+        let chunk_blocks = self.config.chunk_blocks as u64;
+        if blocks > 0 {
+            let first = block_id / chunk_blocks;
+            let last = (end - 1) / chunk_blocks;
+            self.chunks.retain(|index, chunk| {
+                if *index < first || *index > last {
+                    return true;
+                }
+
+                let start = index * chunk_blocks;
+                for i in 0..chunk_blocks {
+                    if (block_id..end).contains(&(start + i)) {
+                        chunk.dirty &= !(1 << i);
+                    }
+                }
+                chunk.dirty != 0
+            });
+        }
+
+        self.dev.discard(block_id, blocks)
+    }
+}
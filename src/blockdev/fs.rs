@@ -0,0 +1,310 @@
+// ===== Read-only Boot Filesystem =====
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{error::Error, fmt};
+
+use embedded_sdmmc::{Mode, RawFile, RawVolume, TimeSource, Timestamp, VolumeIdx, VolumeManager};
+use ext4_view::{Ext4, Ext4Error, Ext4Read};
+use log::debug;
+
+use crate::err::SdError;
+
+use super::{
+    BlockDevice, SdmmcCompat, check_range,
+    partition::{Partition, PartitionDevice},
+};
+
+const EXT4_SUPERBLOCK_OFFSET: usize = 1024;
+const EXT4_MAGIC_OFFSET: usize = EXT4_SUPERBLOCK_OFFSET + 56;
+const EXT4_MAGIC: u16 = 0xEF53;
+// Filesystem type strings of the FAT12/16 and FAT32 boot sectors
+const FAT_TYPE_OFFSETS: [usize; 2] = [54, 82];
+// Bytes read to recognise the filesystem
+const PROBE_LEN: usize = 2048;
+
+const MBR_TYPE_FAT32_LBA: u8 = 0x0C;
+
+type FatManager<D> = VolumeManager<SdmmcCompat<FatVolume<D>>, NoClock>;
+
+#[derive(Debug)]
+pub enum FsError {
+    Device(SdError),
+    UnknownFilesystem,
+    InvalidPath,
+    Fat(embedded_sdmmc::Error<SdError>),
+    Ext4(Ext4Error),
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::Device(err) => write!(f, "Device error: {}", err),
+            FsError::UnknownFilesystem => write!(f, "No FAT or ext4 filesystem found"),
+            FsError::InvalidPath => write!(f, "Invalid path"),
+            FsError::Fat(err) => write!(f, "FAT error: {:?}", err),
+            FsError::Ext4(err) => write!(f, "ext4 error: {}", err),
+        }
+    }
+}
+
+impl Error for FsError {}
+
+impl From<SdError> for FsError {
+    fn from(err: SdError) -> Self {
+        FsError::Device(err)
+    }
+}
+
+impl From<embedded_sdmmc::Error<SdError>> for FsError {
+    fn from(err: embedded_sdmmc::Error<SdError>) -> Self {
+        FsError::Fat(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsType {
+    Fat,
+    Ext4,
+}
+
+enum Mounted<D: BlockDevice + 'static> {
+    Fat(Box<FatManager<D>>),
+    Ext4(Ext4),
+}
+
+/// Filesystem mounted read-only for loading kernels, device trees and the
+/// like. FAT goes through embedded-sdmmc, which only opens 8.3 names, and
+/// ext4 through ext4-view.
+pub struct BootFs<D: BlockDevice + 'static> {
+    inner: Mounted<D>,
+}
+
+impl<D: BlockDevice + 'static> BootFs<D> {
+    /// Mount the filesystem that starts at block 0 of `dev`
+    pub fn mount(mut dev: D) -> Result<Self, FsError> {
+        let inner = match probe(&mut dev)? {
+            FsType::Fat => {
+                let dev = SdmmcCompat::new(FatVolume { dev })?;
+                Mounted::Fat(Box::new(VolumeManager::new(dev, NoClock)))
+            }
+            FsType::Ext4 => {
+                let reader = Ext4Reader {
+                    block: vec![0u8; dev.block_size()],
+                    dev,
+                };
+                Mounted::Ext4(Ext4::load(Box::new(reader)).map_err(FsError::Ext4)?)
+            }
+        };
+
+        Ok(Self { inner })
+    }
+
+    pub fn fs_type(&self) -> FsType {
+        match self.inner {
+            Mounted::Fat(_) => FsType::Fat,
+            Mounted::Ext4(_) => FsType::Ext4,
+        }
+    }
+
+    /// Read a whole file, `path` is absolute with `/` separators
+    pub fn read(&mut self, path: &str) -> Result<Vec<u8>, FsError> {
+        debug!("Reading {} from {:?}", path, self.fs_type());
+
+        match &mut self.inner {
+            Mounted::Fat(mgr) => fat_read(mgr, path),
+            Mounted::Ext4(fs) => fs.read(path).map_err(FsError::Ext4),
+        }
+    }
+}
+
+impl<D: BlockDevice + 'static> BootFs<PartitionDevice<D>> {
+    /// Mount a partition found by `read_partitions`
+    pub fn mount_partition(dev: D, part: &Partition) -> Result<Self, FsError> {
+        Self::mount(PartitionDevice::new(dev, part)?)
+    }
+}
+
+fn probe<D: BlockDevice>(dev: &mut D) -> Result<FsType, FsError> {
+    let block_size = dev.block_size();
+    let mut buf = vec![0u8; PROBE_LEN.div_ceil(block_size) * block_size];
+    check_range(dev, 0, buf.len()).map_err(|_| FsError::UnknownFilesystem)?;
+    dev.read_blocks(0, &mut buf)?;
+
+    let magic = u16::from_le_bytes([buf[EXT4_MAGIC_OFFSET], buf[EXT4_MAGIC_OFFSET + 1]]);
+    if magic == EXT4_MAGIC {
+        return Ok(FsType::Ext4);
+    }
+
+    if buf[510..512] == [0x55, 0xAA]
+        && FAT_TYPE_OFFSETS
+            .iter()
+            .any(|off| &buf[*off..*off + 3] == b"FAT")
+    {
+        return Ok(FsType::Fat);
+    }
+
+    Err(FsError::UnknownFilesystem)
+}
+
+fn fat_read<D: BlockDevice>(mgr: &mut FatManager<D>, path: &str) -> Result<Vec<u8>, FsError> {
+    let mut components: Vec<&str> = path
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect();
+    let name = components.pop().ok_or(FsError::InvalidPath)?;
+
+    let volume = mgr.open_raw_volume(VolumeIdx(0))?;
+    let result = fat_open(mgr, volume, &components, name);
+    let _ = mgr.close_volume(volume);
+
+    let file = result?;
+    let result = fat_read_file(mgr, file);
+    let _ = mgr.close_file(file);
+    result
+}
+
+// Walk down to the file, closing each directory after use
+fn fat_open<D: BlockDevice>(
+    mgr: &mut FatManager<D>,
+    volume: RawVolume,
+    dirs: &[&str],
+    name: &str,
+) -> Result<RawFile, FsError> {
+    let mut dir = mgr.open_root_dir(volume)?;
+    for component in dirs {
+        let next = mgr.open_dir(dir, *component);
+        let _ = mgr.close_dir(dir);
+        dir = next?;
+    }
+
+    let file = mgr.open_file_in_dir(dir, name, Mode::ReadOnly);
+    let _ = mgr.close_dir(dir);
+    Ok(file?)
+}
+
+fn fat_read_file<D: BlockDevice>(
+    mgr: &mut FatManager<D>,
+    file: RawFile,
+) -> Result<Vec<u8>, FsError> {
+    let mut data = vec![0u8; mgr.file_length(file)? as usize];
+
+    let mut done = 0;
+    while done < data.len() {
+        let n = mgr.read(file, &mut data[done..])?;
+        if n == 0 {
+            return Err(FsError::Device(SdError::IoError));
+        }
+        done += n;
+    }
+
+    Ok(data)
+}
+
+// embedded-sdmmc only opens volumes listed in an MBR, so the filesystem is
+// presented behind a one-entry MBR at block 0
+struct FatVolume<D> {
+    dev: D,
+}
+
+impl<D: BlockDevice> BlockDevice for FatVolume<D> {
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.dev.num_blocks() + 1
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), SdError> {
+        check_range(self, block_id, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        if block_id > 0 {
+            return self.dev.read_blocks(block_id - 1, buf);
+        }
+
+        let (mbr, rest) = buf.split_at_mut(self.dev.block_size());
+        mbr.fill(0);
+        let sectors = u32::try_from(self.dev.num_blocks()).unwrap_or(u32::MAX);
+        mbr[446 + 4] = MBR_TYPE_FAT32_LBA;
+        mbr[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+        mbr[446 + 12..446 + 16].copy_from_slice(&sectors.to_le_bytes());
+        mbr[510] = 0x55;
+        mbr[511] = 0xAA;
+
+        if rest.is_empty() {
+            return Ok(());
+        }
+        self.dev.read_blocks(0, rest)
+    }
+
+    fn write_blocks(&mut self, _block_id: u64, _buf: &[u8]) -> Result<(), SdError> {
+        Err(SdError::WriteProtected)
+    }
+}
+
+// Nothing is written, so no real clock is needed
+struct NoClock;
+
+impl TimeSource for NoClock {
+    fn get_timestamp(&self) -> Timestamp {
+        Timestamp {
+            year_since_1970: 0,
+            zero_indexed_month: 0,
+            zero_indexed_day: 0,
+            hours: 0,
+            minutes: 0,
+            seconds: 0,
+        }
+    }
+}
+
+// Byte addressed reads for ext4-view
+struct Ext4Reader<D> {
+    dev: D,
+    block: Vec<u8>,
+}
+
+impl<D: BlockDevice> Ext4Read for Ext4Reader<D> {
+    fn read(
+        &mut self,
+        start_byte: u64,
+        dst: &mut [u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        let block_size = self.block.len();
+        let mut pos = start_byte;
+        let mut done = 0;
+
+        while done < dst.len() {
+            let block_id = pos / block_size as u64;
+            let offset = (pos % block_size as u64) as usize;
+            let remaining = dst.len() - done;
+
+            let n = if offset == 0 && remaining >= block_size {
+                // Whole blocks go straight to the caller
+                let n = remaining / block_size * block_size;
+                self.dev
+                    .read_blocks(block_id, &mut dst[done..done + n])
+                    .map_err(FsError::Device)?;
+                n
+            } else {
+                let n = (block_size - offset).min(remaining);
+                self.dev
+                    .read_blocks(block_id, &mut self.block)
+                    .map_err(FsError::Device)?;
+                dst[done..done + n].copy_from_slice(&self.block[offset..offset + n]);
+                n
+            };
+
+            done += n;
+            pos += n as u64;
+        }
+
+        Ok(())
+    }
+}
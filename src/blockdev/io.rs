@@ -0,0 +1,138 @@
+// ===== embedded-io Byte Stream =====
+
+use alloc::{vec, vec::Vec};
+
+use embedded_io::{ErrorKind, ErrorType, Read, Seek, SeekFrom, Write};
+
+use crate::err::SdError;
+
+use super::BlockDevice;
+
+impl embedded_io::Error for SdError {
+    fn kind(&self) -> ErrorKind {
+        match self.cause() {
+            SdError::Timeout | SdError::DataTimeout => ErrorKind::TimedOut,
+            SdError::InvalidArgument => ErrorKind::InvalidInput,
+            SdError::WriteProtected | SdError::CardLocked => ErrorKind::PermissionDenied,
+            SdError::NotSupported => ErrorKind::Unsupported,
+            SdError::MemoryError => ErrorKind::OutOfMemory,
+            SdError::NoCard => ErrorKind::NotConnected,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// Seekable byte stream over a block device. Unaligned writes read, modify
+/// and write back the blocks they touch.
+pub struct BlockStream<D: BlockDevice> {
+    dev: D,
+    pos: u64,
+    block: Vec<u8>,
+}
+
+impl<D: BlockDevice> BlockStream<D> {
+    pub fn new(dev: D) -> Self {
+        let block = vec![0; dev.block_size()];
+        Self { dev, pos: 0, block }
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Size of the device in bytes
+    pub fn len(&self) -> u64 {
+        self.dev.num_blocks() * self.dev.block_size() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Block containing the position, the offset into it and the bytes left on the device
+    fn locate(&self) -> (u64, usize, u64) {
+        let block_size = self.dev.block_size() as u64;
+        (
+            self.pos / block_size,
+            (self.pos % block_size) as usize,
+            self.len().saturating_sub(self.pos),
+        )
+    }
+}
+
+impl<D: BlockDevice> ErrorType for BlockStream<D> {
+    type Error = SdError;
+}
+
+impl<D: BlockDevice> Read for BlockStream<D> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, SdError> {
+        let (block_id, offset, left) = self.locate();
+        let block_size = self.block.len();
+        let len = (buf.len() as u64).min(left) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let n = if offset == 0 && len >= block_size {
+            // Whole blocks go straight into the caller's buffer
+            let n = len - len % block_size;
+            self.dev.read_blocks(block_id, &mut buf[..n])?;
+            n
+        } else {
+            let n = len.min(block_size - offset);
+            self.dev.read_blocks(block_id, &mut self.block)?;
+            buf[..n].copy_from_slice(&self.block[offset..offset + n]);
+            n
+        };
+
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<D: BlockDevice> Write for BlockStream<D> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, SdError> {
+        let (block_id, offset, left) = self.locate();
+        let block_size = self.block.len();
+        let len = (buf.len() as u64).min(left) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let n = if offset == 0 && len >= block_size {
+            let n = len - len % block_size;
+            self.dev.write_blocks(block_id, &buf[..n])?;
+            n
+        } else {
+            let n = len.min(block_size - offset);
+            self.dev.read_blocks(block_id, &mut self.block)?;
+            self.block[offset..offset + n].copy_from_slice(&buf[..n]);
+            self.dev.write_blocks(block_id, &self.block)?;
+            n
+        };
+
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), SdError> {
+        self.dev.flush()
+    }
+}
+
+impl<D: BlockDevice> Seek for BlockStream<D> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, SdError> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+
+        self.pos = pos.ok_or(SdError::InvalidArgument)?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,243 @@
+// ===== Block Device Abstraction =====
+
+mod cache;
+#[cfg(feature = "fs")]
+mod fs;
+#[cfg(feature = "embedded-io")]
+mod io;
+#[cfg(feature = "embedded-sdmmc")]
+mod sdmmc_compat;
+
+pub mod partition;
+
+pub use cache::{BlockCache, CacheConfig};
+#[cfg(feature = "fs")]
+pub use fs::{BootFs, FsError, FsType};
+#[cfg(feature = "embedded-io")]
+pub use io::BlockStream;
+#[cfg(feature = "embedded-sdmmc")]
+pub use sdmmc_compat::SdmmcCompat;
+
+#[cfg(feature = "dma")]
+use dma_api::{DVec, Direction};
+
+use crate::{
+    BLOCK_SIZE,
+    emmc::{EMmcHost, constant::MMC_TRIM_ARG},
+    err::SdError,
+};
+
+// Largest transfer handed to a single CMD18/CMD25
+const MAX_BLOCKS_PER_CMD: usize = 1024;
+
+/// Block storage as used by partition tables, filesystems and caches.
+/// Buffers hold whole blocks and `block_id` counts in `block_size()` units.
+pub trait BlockDevice {
+    fn block_size(&self) -> usize;
+
+    fn num_blocks(&self) -> u64;
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), SdError>;
+
+    fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> Result<(), SdError>;
+
+    /// Write that is persistent once it returns (forced unit access)
+    fn write_blocks_fua(&mut self, block_id: u64, buf: &[u8]) -> Result<(), SdError> {
+        self.write_blocks(block_id, buf)?;
+        self.flush()
+    }
+
+    /// Make completed writes persistent
+    fn flush(&mut self) -> Result<(), SdError> {
+        Ok(())
+    }
+
+    /// Tell the device that `blocks` blocks from `block_id` no longer hold data
+    fn discard(&mut self, _block_id: u64, _blocks: u64) -> Result<(), SdError> {
+        Err(SdError::NotSupported)
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for &mut D {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (**self).num_blocks()
+    }
+
+    fn read_only(&self) -> bool {
+        (**self).read_only()
+    }
+
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), SdError> {
+        (**self).read_blocks(block_id, buf)
+    }
+
+    fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> Result<(), SdError> {
+        (**self).write_blocks(block_id, buf)
+    }
+
+    fn write_blocks_fua(&mut self, block_id: u64, buf: &[u8]) -> Result<(), SdError> {
+        (**self).write_blocks_fua(block_id, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), SdError> {
+        (**self).flush()
+    }
+
+    fn discard(&mut self, block_id: u64, blocks: u64) -> Result<(), SdError> {
+        (**self).discard(block_id, blocks)
+    }
+}
+
+/// Check that `len` bytes at `block_id` are whole blocks inside the device.
+/// Returns the number of blocks.
+pub fn check_range<D: BlockDevice + ?Sized>(
+    dev: &D,
+    block_id: u64,
+    len: usize,
+) -> Result<u64, SdError> {
+    let block_size = dev.block_size();
+    if block_size == 0 || !len.is_multiple_of(block_size) {
+        return Err(SdError::InvalidArgument);
+    }
+
+    let blocks = (len / block_size) as u64;
+    let end = block_id
+        .checked_add(blocks)
+        .ok_or(SdError::InvalidArgument)?;
+    if end > dev.num_blocks() {
+        return Err(SdError::InvalidArgument);
+    }
+
+    Ok(blocks)
+}
+
+// User data area of the card
+impl BlockDevice for EMmcHost {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.get_block_num()
+    }
+
+    fn read_only(&self) -> bool {
+        self.is_write_protected()
+    }
+
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), SdError> {
+        self.transfer(block_id, Transfer::Read(buf))
+    }
+
+    fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> Result<(), SdError> {
+        self.transfer(block_id, Transfer::Write(buf, false))
+    }
+
+    // Reliable writes go around the volatile cache of the card. Without
+    // reliable write support the data is written and the cache flushed.
+    fn write_blocks_fua(&mut self, block_id: u64, buf: &[u8]) -> Result<(), SdError> {
+        if self.rel_wr_sec_c().ok_or(SdError::NoCard)? == 0 {
+            self.transfer(block_id, Transfer::Write(buf, false))?;
+            return self.flush_cache();
+        }
+
+        self.transfer(block_id, Transfer::Write(buf, true))
+    }
+
+    fn flush(&mut self) -> Result<(), SdError> {
+        self.flush_cache()
+    }
+
+    fn discard(&mut self, block_id: u64, blocks: u64) -> Result<(), SdError> {
+        let len = usize::try_from(blocks).map_err(|_| SdError::InvalidArgument)?;
+        check_range(self, block_id, len.saturating_mul(BLOCK_SIZE))?;
+
+        let blocks = u32::try_from(blocks).map_err(|_| SdError::InvalidArgument)?;
+        self.erase_blocks(card_block(block_id)?, blocks, MMC_TRIM_ARG)
+    }
+}
+
+// Data of a block device transfer, writes flagged reliable or not
+enum Transfer<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8], bool),
+}
+
+impl EMmcHost {
+    // Split `buf` into commands of at most MAX_BLOCKS_PER_CMD blocks, each
+    // retried through `run_with_recovery`. Reliable writes are cut further to
+    // the sizes the card accepts.
+    fn transfer(&mut self, block_id: u64, mut buf: Transfer<'_>) -> Result<(), SdError> {
+        let len = match &buf {
+            Transfer::Read(buf) => buf.len(),
+            Transfer::Write(buf, _) => buf.len(),
+        };
+        check_range(self, block_id, len)?;
+
+        let mut offset = 0;
+        while offset < len {
+            let id = card_block(block_id + (offset / BLOCK_SIZE) as u64)?;
+            let mut blocks = ((len - offset) / BLOCK_SIZE).min(MAX_BLOCKS_PER_CMD) as u16;
+            if let Transfer::Write(_, true) = buf {
+                blocks = self.reliable_write_blocks(id, blocks)?;
+            }
+
+            let range = offset..offset + blocks as usize * BLOCK_SIZE;
+            offset = range.end;
+
+            match &mut buf {
+                Transfer::Read(buf) => {
+                    let chunk = &mut buf[range];
+                    cfg_if::cfg_if! {
+                        if #[cfg(feature = "dma")] {
+                            let mut data: DVec<u8> = DVec::zeros(chunk.len(), 0x1000, Direction::FromDevice)
+                                .ok_or(SdError::MemoryError)?;
+                            self.run_with_recovery(|host| EMmcHost::read_blocks(host, id, blocks, &mut data))?;
+                            chunk.copy_from_slice(&data.to_vec());
+                        } else if #[cfg(feature = "pio")] {
+                            self.run_with_recovery(|host| EMmcHost::read_blocks(host, id, blocks, chunk))?;
+                        }
+                    }
+                }
+                Transfer::Write(buf, reliable) => {
+                    let reliable = *reliable;
+                    let chunk = &buf[range];
+                    cfg_if::cfg_if! {
+                        if #[cfg(feature = "dma")] {
+                            let mut data: DVec<u8> = DVec::zeros(chunk.len(), 0x1000, Direction::ToDevice)
+                                .ok_or(SdError::MemoryError)?;
+                            for (i, b) in chunk.iter().enumerate() {
+                                data.set(i, *b);
+                            }
+                            let data = &data;
+                        } else if #[cfg(feature = "pio")] {
+                            let data = chunk;
+                        }
+                    }
+                    self.run_with_recovery(|host| {
+                        if reliable {
+                            host.write_blocks_reliable(id, blocks, data)
+                        } else {
+                            EMmcHost::write_blocks(host, id, blocks, data)
+                        }
+                    })?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Block numbers on the bus are 32 bits wide
+fn card_block(block_id: u64) -> Result<u32, SdError> {
+    u32::try_from(block_id).map_err(|_| SdError::InvalidArgument)
+}
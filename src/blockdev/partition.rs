@@ -0,0 +1,451 @@
+// ===== GPT / MBR Partition Tables =====
+
+use alloc::{string::String, vec, vec::Vec};
+use core::fmt;
+
+use log::{debug, warn};
+
+use crate::err::SdError;
+
+use super::{BlockDevice, check_range};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+const MBR_TYPE_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+// Sanity limit for the entry array, the spec minimum is 16 KiB
+const GPT_ENTRIES_MAX_BYTES: usize = 1 << 20;
+
+/// GUID as stored on disk, the first three fields little-endian
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const EFI_SYSTEM: Guid = Guid::from_fields(
+        0xC12A7328,
+        0xF81F,
+        0x11D2,
+        [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B],
+    );
+    pub const BASIC_DATA: Guid = Guid::from_fields(
+        0xEBD0A0A2,
+        0xB9E5,
+        0x4433,
+        [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7],
+    );
+    pub const LINUX_FS: Guid = Guid::from_fields(
+        0x0FC63DAF,
+        0x8483,
+        0x4772,
+        [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4],
+    );
+
+    pub const fn from_fields(d1: u32, d2: u16, d3: u16, d4: [u8; 8]) -> Self {
+        let a = d1.to_le_bytes();
+        let b = d2.to_le_bytes();
+        let c = d3.to_le_bytes();
+        Guid([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d4[0], d4[1], d4[2], d4[3], d4[4],
+            d4[5], d4[6], d4[7],
+        ])
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|b| *b == 0)
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]]),
+            g[8],
+            g[9]
+        )?;
+        for b in &g[10..] {
+            write!(f, "{:02X}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    Gpt {
+        type_guid: Guid,
+        unique_guid: Guid,
+        attributes: u64,
+    },
+    Mbr {
+        os_type: u8,
+        bootable: bool,
+    },
+}
+
+/// Partition in blocks of the underlying device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// Position in the table, starting at 1 as in `mmcblk0p1`
+    pub number: usize,
+    pub start: u64,
+    pub blocks: u64,
+    /// GPT partition name, empty for MBR
+    pub name: String,
+    pub kind: PartitionKind,
+}
+
+impl Partition {
+    pub fn end(&self) -> u64 {
+        self.start + self.blocks
+    }
+}
+
+/// Read the partition table of `dev`. A protective MBR selects GPT, which is
+/// taken from the primary header or, if that is damaged, from the backup.
+/// Intact primary and backup copies that disagree are rejected.
+pub fn read_partitions<D: BlockDevice + ?Sized>(dev: &mut D) -> Result<Vec<Partition>, SdError> {
+    let block_size = dev.block_size();
+    if block_size < 512 {
+        return Err(SdError::NotSupported);
+    }
+
+    let mut mbr = vec![0u8; block_size];
+    dev.read_blocks(0, &mut mbr)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        debug!("No MBR signature");
+        return Err(SdError::BadMessage);
+    }
+
+    let protective =
+        (0..4).any(|i| mbr[MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE + 4] == MBR_TYPE_GPT_PROTECTIVE);
+    if protective {
+        read_gpt(dev)
+    } else {
+        parse_mbr(dev, &mbr)
+    }
+}
+
+/// Find a GPT partition by name
+pub fn find_partition<'a>(parts: &'a [Partition], name: &str) -> Option<&'a Partition> {
+    parts.iter().find(|p| p.name == name)
+}
+
+fn parse_mbr<D: BlockDevice + ?Sized>(dev: &D, mbr: &[u8]) -> Result<Vec<Partition>, SdError> {
+    let mut parts = Vec::new();
+
+    for i in 0..4 {
+        let entry = &mbr[MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        let os_type = entry[4];
+        let start = le_u32(&entry[8..]) as u64;
+        let blocks = le_u32(&entry[12..]) as u64;
+
+        if os_type == 0 || blocks == 0 {
+            continue;
+        }
+        if MBR_TYPE_EXTENDED.contains(&os_type) {
+            // Logical partitions inside the extended one are not followed
+            debug!("MBR partition {} is extended, skipped", i + 1);
+            continue;
+        }
+        if start + blocks > dev.num_blocks() {
+            warn!("MBR partition {} beyond end of device", i + 1);
+            continue;
+        }
+
+        parts.push(Partition {
+            number: i + 1,
+            start,
+            blocks,
+            name: String::new(),
+            kind: PartitionKind::Mbr {
+                os_type,
+                bootable: entry[0] & 0x80 != 0,
+            },
+        });
+    }
+
+    debug!("MBR: {} partitions", parts.len());
+    Ok(parts)
+}
+
+// Validated GPT header fields
+struct GptHeader {
+    my_lba: u64,
+    alternate_lba: u64,
+    first_usable: u64,
+    last_usable: u64,
+    entries_lba: u64,
+    num_entries: usize,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+fn read_gpt<D: BlockDevice + ?Sized>(dev: &mut D) -> Result<Vec<Partition>, SdError> {
+    let last_lba = dev.num_blocks().checked_sub(1).ok_or(SdError::BadMessage)?;
+
+    let primary = read_gpt_at(dev, 1);
+    let backup_lba = match &primary {
+        Ok((header, _)) => header.alternate_lba,
+        Err(_) => last_lba,
+    };
+    let backup = read_gpt_at(dev, backup_lba);
+
+    let (header, entries) = match (primary, backup) {
+        (Ok(primary), Ok(backup)) => {
+            // Two intact copies that disagree leave no way to tell the current one
+            if !gpt_copies_match(&primary.0, &backup.0) {
+                warn!("GPT: primary and backup headers disagree");
+                return Err(SdError::BadMessage);
+            }
+            primary
+        }
+        (Ok(primary), Err(e)) => {
+            warn!("GPT: backup header at LBA {} invalid: {:?}", backup_lba, e);
+            primary
+        }
+        (Err(e), Ok(backup)) => {
+            if backup.0.alternate_lba != 1 {
+                warn!("GPT: backup header does not point back to LBA 1");
+                return Err(e);
+            }
+            warn!("GPT: primary header invalid ({:?}), using backup", e);
+            backup
+        }
+        (Err(e), Err(_)) => return Err(e),
+    };
+
+    let mut parts = Vec::new();
+    for (i, entry) in entries.chunks_exact(header.entry_size).enumerate() {
+        let type_guid = guid_at(entry, 0);
+        if type_guid.is_zero() {
+            continue;
+        }
+
+        let first = le_u64(&entry[32..]);
+        let last = le_u64(&entry[40..]);
+        if first > last || first < header.first_usable || last > header.last_usable {
+            warn!("GPT entry {} out of bounds: {}..={}", i + 1, first, last);
+            continue;
+        }
+
+        let name = char::decode_utf16(
+            entry[56..128]
+                .as_chunks::<2>()
+                .0
+                .iter()
+                .map(|c| u16::from_le_bytes(*c))
+                .take_while(|c| *c != 0),
+        )
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect();
+
+        parts.push(Partition {
+            number: i + 1,
+            start: first,
+            blocks: last - first + 1,
+            name,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                unique_guid: guid_at(entry, 16),
+                attributes: le_u64(&entry[48..]),
+            },
+        });
+    }
+
+    debug!("GPT: {} partitions", parts.len());
+    Ok(parts)
+}
+
+// The backup header mirrors the primary one, with the two LBAs swapped
+fn gpt_copies_match(primary: &GptHeader, backup: &GptHeader) -> bool {
+    backup.my_lba == primary.alternate_lba
+        && backup.alternate_lba == primary.my_lba
+        && backup.first_usable == primary.first_usable
+        && backup.last_usable == primary.last_usable
+        && backup.num_entries == primary.num_entries
+        && backup.entry_size == primary.entry_size
+        && backup.entries_crc == primary.entries_crc
+}
+
+// Read and check the header at `lba` and its entry array. A header whose
+// entry array fails its CRC is rejected as a whole.
+fn read_gpt_at<D: BlockDevice + ?Sized>(
+    dev: &mut D,
+    lba: u64,
+) -> Result<(GptHeader, Vec<u8>), SdError> {
+    let block_size = dev.block_size();
+    let num_blocks = dev.num_blocks();
+
+    let mut raw = vec![0u8; block_size];
+    check_range(dev, lba, raw.len()).map_err(|_| SdError::BadMessage)?;
+    dev.read_blocks(lba, &mut raw)?;
+
+    if &raw[0..8] != GPT_SIGNATURE {
+        return Err(SdError::BadMessage);
+    }
+
+    let header_size = le_u32(&raw[12..]) as usize;
+    if !(GPT_HEADER_MIN_SIZE..=block_size).contains(&header_size) {
+        return Err(SdError::BadMessage);
+    }
+
+    let header_crc = le_u32(&raw[16..]);
+    raw[16..20].fill(0);
+    if crc32(&raw[..header_size]) != header_crc {
+        debug!("GPT header CRC mismatch at LBA {}", lba);
+        return Err(SdError::BadMessage);
+    }
+
+    let header = GptHeader {
+        my_lba: le_u64(&raw[24..]),
+        alternate_lba: le_u64(&raw[32..]),
+        first_usable: le_u64(&raw[40..]),
+        last_usable: le_u64(&raw[48..]),
+        entries_lba: le_u64(&raw[72..]),
+        num_entries: le_u32(&raw[80..]) as usize,
+        entry_size: le_u32(&raw[84..]) as usize,
+        entries_crc: le_u32(&raw[88..]),
+    };
+
+    let entries_len = header.num_entries.saturating_mul(header.entry_size);
+    if header.my_lba != lba
+        || header.first_usable > header.last_usable
+        || header.last_usable >= num_blocks
+        || header.entry_size < GPT_ENTRY_MIN_SIZE
+        || !header.entry_size.is_power_of_two()
+        || entries_len > GPT_ENTRIES_MAX_BYTES
+    {
+        return Err(SdError::BadMessage);
+    }
+
+    let mut entries = vec![0u8; entries_len.div_ceil(block_size) * block_size];
+    check_range(dev, header.entries_lba, entries.len()).map_err(|_| SdError::BadMessage)?;
+    dev.read_blocks(header.entries_lba, &mut entries)?;
+    entries.truncate(entries_len);
+
+    if crc32(&entries) != header.entries_crc {
+        debug!("GPT entries CRC mismatch for header at LBA {}", lba);
+        return Err(SdError::BadMessage);
+    }
+
+    Ok((header, entries))
+}
+
+/// A partition of `dev` as a block device of its own. Accesses are checked
+/// against the partition bounds.
+pub struct PartitionDevice<D: BlockDevice> {
+    dev: D,
+    start: u64,
+    blocks: u64,
+}
+
+impl<D: BlockDevice> PartitionDevice<D> {
+    pub fn new(dev: D, part: &Partition) -> Result<Self, SdError> {
+        if part.end() > dev.num_blocks() {
+            return Err(SdError::InvalidArgument);
+        }
+
+        Ok(Self {
+            dev,
+            start: part.start,
+            blocks: part.blocks,
+        })
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for PartitionDevice<D> {
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_only(&self) -> bool {
+        self.dev.read_only()
+    }
+
+    fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), SdError> {
+        check_range(self, block_id, buf.len())?;
+        self.dev.read_blocks(self.start + block_id, buf)
+    }
+
+    fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> Result<(), SdError> {
+        check_range(self, block_id, buf.len())?;
+        self.dev.write_blocks(self.start + block_id, buf)
+    }
+
+    fn write_blocks_fua(&mut self, block_id: u64, buf: &[u8]) -> Result<(), SdError> {
+        check_range(self, block_id, buf.len())?;
+        self.dev.write_blocks_fua(self.start + block_id, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), SdError> {
+        self.dev.flush()
+    }
+
+    fn discard(&mut self, block_id: u64, blocks: u64) -> Result<(), SdError> {
+        let end = block_id
+            .checked_add(blocks)
+            .ok_or(SdError::InvalidArgument)?;
+        if end > self.blocks {
+            return Err(SdError::InvalidArgument);
+        }
+        self.dev.discard(self.start + block_id, blocks)
+    }
+}
+
+// CRC-32 (IEEE 802.3) as used by GPT
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn le_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn le_u64(b: &[u8]) -> u64 {
+    u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])
+}
+
+fn guid_at(b: &[u8], offset: usize) -> Guid {
+    let mut guid = [0u8; 16];
+    guid.copy_from_slice(&b[offset..offset + 16]);
+    Guid(guid)
+}
@@ -0,0 +1,61 @@
+// ===== embedded-sdmmc Block Device =====
+
+use core::cell::RefCell;
+
+use embedded_sdmmc::{Block, BlockCount, BlockIdx};
+
+use crate::err::SdError;
+
+use super::BlockDevice;
+
+/// `embedded_sdmmc::BlockDevice` on top of a device with 512-byte blocks.
+/// That trait works on `&self`, so the device is kept in a `RefCell`.
+pub struct SdmmcCompat<D: BlockDevice> {
+    dev: RefCell<D>,
+}
+
+impl<D: BlockDevice> SdmmcCompat<D> {
+    pub fn new(dev: D) -> Result<Self, SdError> {
+        if dev.block_size() != Block::LEN {
+            return Err(SdError::NotSupported);
+        }
+
+        Ok(Self {
+            dev: RefCell::new(dev),
+        })
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dev.into_inner()
+    }
+}
+
+impl<D: BlockDevice> embedded_sdmmc::BlockDevice for SdmmcCompat<D> {
+    type Error = SdError;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), SdError> {
+        let mut dev = self.dev.borrow_mut();
+        for (i, block) in blocks.iter_mut().enumerate() {
+            dev.read_blocks(start_block_idx.0 as u64 + i as u64, &mut block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), SdError> {
+        let mut dev = self.dev.borrow_mut();
+        for (i, block) in blocks.iter().enumerate() {
+            dev.write_blocks(start_block_idx.0 as u64 + i as u64, &block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, SdError> {
+        let blocks = self.dev.borrow().num_blocks();
+        Ok(BlockCount(u32::try_from(blocks).unwrap_or(u32::MAX)))
+    }
+}
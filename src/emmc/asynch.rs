@@ -0,0 +1,358 @@
+// ===== Async Block I/O =====
+
+#[cfg(feature = "dma")]
+use core::fmt;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(feature = "dma")]
+use dma_api::DVec;
+use log::trace;
+#[cfg(feature = "dma")]
+use log::{debug, warn};
+
+use crate::err::SdError;
+
+use super::{
+    EMmcHost,
+    block::DataBuffer,
+    cmd::EMmcCommand,
+    constant::*,
+    irq::{IrqHandle, IrqState},
+};
+
+/// Host driven by an async executor. Requests borrow the host mutably, so
+/// there is at most one outstanding request and no lock is needed.
+///
+/// Completions are signalled by `IrqHandle::handle_irq`, to be called from
+/// the interrupt handler of the controller. There is no timeout, wrap the
+/// futures in the timer of the executor if needed. A request whose future is
+/// dropped early is left to finish on the bus before the next one is issued.
+///
+/// With the `dma` feature the block requests take their buffer by value and
+/// hand it back on success. If such a request is dropped before it is done,
+/// or fails, the buffer stays with the host until the next request has
+/// stopped the controller, so the DMA engine never writes to freed memory.
+#[derive(Debug)]
+pub struct AsyncEMmcHost {
+    host: EMmcHost,
+}
+
+impl EMmcHost {
+    /// Switch to interrupt driven completion and hand the host over to async use
+    pub fn into_async(mut self) -> Result<AsyncEMmcHost, SdError> {
+        self.enable_irq_mode()?;
+        Ok(AsyncEMmcHost { host: self })
+    }
+}
+
+impl AsyncEMmcHost {
+    pub fn host(&self) -> &EMmcHost {
+        &self.host
+    }
+
+    /// Give the host back for synchronous use, still in interrupt mode
+    pub fn into_inner(self) -> EMmcHost {
+        #[cfg(feature = "dma")]
+        self.host.dma_settle();
+        self.host
+    }
+
+    pub fn irq_handle(&self) -> IrqHandle {
+        self.host.irq_handle()
+    }
+
+    /// Async counterpart of `EMmcHost::send_command`
+    #[cfg(feature = "pio")]
+    pub async fn send_command(
+        &mut self,
+        cmd: &EMmcCommand,
+        data_buffer: Option<DataBuffer<'_>>,
+    ) -> Result<(), SdError> {
+        self.host.send_command_async(cmd, data_buffer).await
+    }
+
+    /// Async counterpart of `EMmcHost::send_command` for commands without
+    /// data. Data goes through `read_blocks` and `write_blocks`, which keep
+    /// the buffer alive until the controller is done with it.
+    #[cfg(feature = "dma")]
+    pub async fn send_command(&mut self, cmd: &EMmcCommand) -> Result<(), SdError> {
+        self.host.dma_settle();
+        self.host.send_command_async(cmd, None).await
+    }
+
+    /// Async counterpart of `EMmcHost::read_blocks`
+    #[cfg(feature = "pio")]
+    pub async fn read_blocks(
+        &mut self,
+        block_id: u32,
+        blocks: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), SdError> {
+        if buffer.len() != blocks as usize * 512 {
+            return Err(SdError::IoError);
+        }
+
+        let cmd = self.host.rw_blocks_cmd(block_id, blocks, true)?;
+        self.host
+            .send_command_async(&cmd, Some(DataBuffer::Read(buffer)))
+            .await?;
+
+        self.host.stop_transmission_async(blocks).await
+    }
+
+    /// Async counterpart of `EMmcHost::read_blocks`, returning the filled buffer
+    #[cfg(feature = "dma")]
+    pub async fn read_blocks(
+        &mut self,
+        block_id: u32,
+        blocks: u16,
+        buffer: DVec<u8>,
+    ) -> Result<DVec<u8>, SdError> {
+        if buffer.len() != blocks as usize * 512 {
+            return Err(SdError::IoError);
+        }
+
+        self.host.dma_settle();
+        let cmd = self.host.rw_blocks_cmd(block_id, blocks, true)?;
+        let mut request = DmaRequest::new(&self.host, buffer);
+        self.host
+            .send_command_async(&cmd, Some(DataBuffer::Read(request.buffer()?)))
+            .await?;
+
+        self.host.stop_transmission_async(blocks).await?;
+        request.finish()
+    }
+
+    /// Async counterpart of `EMmcHost::write_blocks`
+    #[cfg(feature = "pio")]
+    pub async fn write_blocks(
+        &mut self,
+        block_id: u32,
+        blocks: u16,
+        buffer: &[u8],
+    ) -> Result<(), SdError> {
+        if buffer.len() != blocks as usize * 512 {
+            return Err(SdError::IoError);
+        }
+
+        let cmd = self.host.rw_blocks_cmd(block_id, blocks, false)?;
+        self.host
+            .send_command_async(&cmd, Some(DataBuffer::Write(buffer)))
+            .await?;
+
+        self.host.stop_transmission_async(blocks).await
+    }
+
+    /// Async counterpart of `EMmcHost::write_blocks`, returning the buffer
+    #[cfg(feature = "dma")]
+    pub async fn write_blocks(
+        &mut self,
+        block_id: u32,
+        blocks: u16,
+        buffer: DVec<u8>,
+    ) -> Result<DVec<u8>, SdError> {
+        if buffer.len() != blocks as usize * 512 {
+            return Err(SdError::IoError);
+        }
+
+        self.host.dma_settle();
+        let cmd = self.host.rw_blocks_cmd(block_id, blocks, false)?;
+        let mut request = DmaRequest::new(&self.host, buffer);
+        self.host
+            .send_command_async(&cmd, Some(DataBuffer::Write(request.buffer()?)))
+            .await?;
+
+        self.host.stop_transmission_async(blocks).await?;
+        request.finish()
+    }
+}
+
+impl EMmcHost {
+    async fn send_command_async(
+        &self,
+        cmd: &EMmcCommand,
+        data_buffer: Option<DataBuffer<'_>>,
+    ) -> Result<(), SdError> {
+        let result = self.send_command_async_inner(cmd, data_buffer).await;
+        result
+            .and_then(|_| self.check_card_status(cmd))
+            .map_err(|err| self.command_error(cmd, err))
+    }
+
+    async fn send_command_async_inner(
+        &self,
+        cmd: &EMmcCommand,
+        data_buffer: Option<DataBuffer<'_>>,
+    ) -> Result<(), SdError> {
+        let int_mask = self.cmd_start(cmd, &data_buffer)?;
+        let stat = IrqEvent::new(self, int_mask as u32).await?;
+        self.cmd_complete(cmd, int_mask, stat as u16, Some((stat >> 16) as u16))?;
+
+        if cmd.data_present {
+            trace!("Async data transfer: opcode={:#x}", cmd.opcode);
+            match data_buffer {
+                Some(buffer) => self.transfer_data_async(buffer).await?,
+                None => return Err(SdError::InvalidArgument),
+            }
+        }
+
+        self.cmd_finish()
+    }
+
+    // CMD12 after an open ended multi block transfer
+    async fn stop_transmission_async(&self, blocks: u16) -> Result<(), SdError> {
+        if blocks <= 1 {
+            return Ok(());
+        }
+
+        let stop_cmd = EMmcCommand::new(MMC_STOP_TRANSMISSION, 0, MMC_RSP_R1B);
+        self.send_command_async(&stop_cmd, None).await
+    }
+
+    /// Stop the transfer of an async DMA request that did not complete and
+    /// free its buffer. Runs before every async request, when the host leaves
+    /// async use and when it is dropped.
+    #[cfg(feature = "dma")]
+    pub(crate) fn dma_settle(&self) {
+        let Some(parked) = self.dma_parked.lock().take() else {
+            return;
+        };
+
+        debug!("Aborting unfinished DMA request");
+        if let Err(e) = self.reset(EMMC_RESET_CMD | EMMC_RESET_DATA) {
+            warn!("Reset after unfinished DMA request failed: {:?}", e);
+        }
+
+        // Brings the card back to transfer state, fails harmlessly if the
+        // transfer had already ended
+        let stop_cmd = EMmcCommand::new(MMC_STOP_TRANSMISSION, 0, MMC_RSP_R1B);
+        if let Err(e) = self.send_command(&stop_cmd, None) {
+            debug!("CMD12 after unfinished DMA request: {:?}", e);
+        }
+
+        drop(parked);
+    }
+}
+
+/// Buffer of an unfinished DMA request, only kept alive until it is dropped
+#[cfg(feature = "dma")]
+pub(crate) struct ParkedBuffer {
+    _buffer: DVec<u8>,
+}
+
+// The buffer is never accessed again, only freed, which any thread may do
+#[cfg(feature = "dma")]
+unsafe impl Send for ParkedBuffer {}
+
+#[cfg(feature = "dma")]
+impl fmt::Debug for ParkedBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ParkedBuffer")
+    }
+}
+
+// Owns the buffer of an async DMA request. Dropped before `finish()`, i.e.
+// when the future is dropped or the request failed, it parks the buffer in
+// the host instead of freeing it under a possibly running transfer.
+#[cfg(feature = "dma")]
+struct DmaRequest<'a> {
+    host: &'a EMmcHost,
+    buffer: Option<DVec<u8>>,
+}
+
+#[cfg(feature = "dma")]
+impl<'a> DmaRequest<'a> {
+    fn new(host: &'a EMmcHost, buffer: DVec<u8>) -> Self {
+        Self {
+            host,
+            buffer: Some(buffer),
+        }
+    }
+
+    fn buffer(&mut self) -> Result<&mut DVec<u8>, SdError> {
+        self.buffer.as_mut().ok_or(SdError::InvalidArgument)
+    }
+
+    fn finish(mut self) -> Result<DVec<u8>, SdError> {
+        self.buffer.take().ok_or(SdError::InvalidArgument)
+    }
+}
+
+#[cfg(feature = "dma")]
+impl Drop for DmaRequest<'_> {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            *self.host.dma_parked.lock() = Some(ParkedBuffer { _buffer: buffer });
+        }
+    }
+}
+
+impl EMmcHost {
+    #[cfg(feature = "dma")]
+    async fn transfer_data_async(&self, _buffer: DataBuffer<'_>) -> Result<(), SdError> {
+        let stat = IrqEvent::new(self, EMMC_INT_DATA_END).await?;
+        self.dma_xfer_result(stat as u16, (stat >> 16) as u16)
+    }
+
+    #[cfg(feature = "pio")]
+    async fn transfer_data_async(&self, buffer: DataBuffer<'_>) -> Result<(), SdError> {
+        match buffer {
+            DataBuffer::Read(buf) => {
+                let stat = IrqEvent::new(self, EMMC_INT_DATA_AVAIL).await?;
+                self.data_irq_result(stat)?;
+                self.pio_read_buf(buf);
+            }
+            DataBuffer::Write(buf) => {
+                let stat = IrqEvent::new(self, EMMC_INT_SPACE_AVAIL).await?;
+                self.data_irq_result(stat)?;
+                self.pio_write_buf(buf);
+            }
+        }
+
+        let stat = IrqEvent::new(self, EMMC_INT_DATA_END).await?;
+        self.data_irq_result(stat)
+    }
+}
+
+// Resolves once all of `mask` or an error has been recorded by the interrupt
+// handler, with the same result as `EMmcHost::irq_wait`
+struct IrqEvent<'a> {
+    host: &'a EMmcHost,
+    mask: u32,
+}
+
+impl<'a> IrqEvent<'a> {
+    fn new(host: &'a EMmcHost, mask: u32) -> Self {
+        Self { host, mask }
+    }
+
+    fn state(&self) -> &IrqState {
+        &self.host.irq
+    }
+}
+
+impl Future for IrqEvent<'_> {
+    type Output = Result<u32, SdError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(status) = self.state().take(self.mask) {
+            return Poll::Ready(Ok(status));
+        }
+
+        // Look again after registering, the interrupt may have come in between
+        self.state().register(cx.waker());
+        if let Some(status) = self.state().take(self.mask) {
+            return Poll::Ready(Ok(status));
+        }
+
+        if let Err(e) = self.host.check_card_removed() {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Pending
+    }
+}
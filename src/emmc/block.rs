@@ -4,17 +4,16 @@ use aux::MMC_VERSION_UNKNOWN;
 use core::sync::atomic::{AtomicBool, Ordering};
 
 #[cfg(feature = "dma")]
-use {
-    dma_api::DVec,
-    crate::delay_us,
-    log::{debug, info},
-};
+use {dma_api::DVec, log::info};
 
-use log::trace;
+use log::{debug, trace};
 
 use crate::err::SdError;
 
-use super::{CardType, EMmcHost, aux, cmd::EMmcCommand, constant::*};
+use super::{
+    CardType, EMmcHost, aux, cmd::EMmcCommand, constant::*, drive::PowerClasses,
+    timeout::CardTimeouts,
+};
 
 #[cfg(feature = "pio")]
 pub enum DataBuffer<'a> {
@@ -50,6 +49,9 @@ pub struct EMmcCard {
     pub part_support: u8,
     pub part_attr: u8,
     pub wr_rel_set: u8,
+    pub wr_rel_param: u8,
+    // Reliable write size in blocks, 0 if reliable write is not supported
+    pub rel_wr_sec_c: u8,
     pub part_config: u8,
     pub dsr_imp: u32,
     pub card_caps: u32,
@@ -65,6 +67,16 @@ pub struct EMmcCard {
     pub enh_user_size: u64,
     pub enh_user_start: u64,
     pub raw_driver_strength: u8,
+    // Driver type selected in HS_TIMING
+    pub driver_type: u8,
+    pub power_classes: PowerClasses,
+    pub power_class: u8,
+    pub locked: bool,
+    pub timeouts: CardTimeouts,
+    // Volatile cache switched on in CACHE_CTRL
+    pub cache_ctrl: bool,
+    // TRIM and discard allowed, SEC_GB_CL_EN in SEC_FEATURE_SUPPORT
+    pub can_trim: bool,
 
     // 扩展CSD相关字段
     pub ext_csd_rev: u8,
@@ -97,6 +109,8 @@ impl EMmcCard {
             part_support: 0,
             part_attr: 0,
             wr_rel_set: 0,
+            wr_rel_param: 0,
+            rel_wr_sec_c: 0,
             part_config: 0,
             read_bl_len: 0,
             write_bl_len: 0,
@@ -110,6 +124,13 @@ impl EMmcCard {
             enh_user_size: 0,
             enh_user_start: 0,
             raw_driver_strength: 0,
+            driver_type: 0,
+            power_classes: PowerClasses::default(),
+            power_class: 0,
+            locked: false,
+            timeouts: CardTimeouts::default(),
+            cache_ctrl: false,
+            can_trim: false,
 
             ext_csd_rev: 0,
             ext_csd_sectors: 0,
@@ -178,52 +199,72 @@ impl EMmcHost {
         self.card = Some(card);
     }
 
-    /// Read one or more data blocks from the card
-    #[cfg(feature = "dma")]
-    pub fn read_blocks(
-        &self,
+    // Build the read or write command for `blocks` blocks at `block_id`,
+    // shared by the sync and async block I/O. Re-tunes first when the
+    // controller or the re-tuning timer asks for it.
+    pub(crate) fn rw_blocks_cmd(
+        &mut self,
         block_id: u32,
         blocks: u16,
-        buffer: &mut DVec<u8>,
-    ) -> Result<(), SdError> {
-        // Check if buffer size matches the expected size based on number of blocks
-        let expected_size = blocks as usize * 512;
-        if buffer.len() != expected_size {
-            return Err(SdError::IoError);
+        read: bool,
+    ) -> Result<EMmcCommand, SdError> {
+        if let Err(err) = self.retune_if_needed() {
+            debug!("Transfer without re-tuning: {}", err);
         }
 
-        // Check if card is initialized and extract card information
-        let card = match &self.card {
-            Some(card) => card,
-            None => return Err(SdError::NoCard),
-        };
+        let card = self.card.as_ref().ok_or(SdError::NoCard)?;
+
+        // Class 2/4 commands are rejected while the card is locked
+        if card.locked {
+            return Err(SdError::CardLocked);
+        }
+
+        if !read && self.is_write_protected() {
+            return Err(SdError::WriteProtected);
+        }
 
-        // Adjust block address based on card type
         // High capacity cards use block addressing, standard capacity cards use byte addressing
         let card_addr = if card.state & MMC_STATE_HIGHCAPACITY != 0 {
-            block_id // High capacity card: use block address directly
+            block_id
         } else {
-            block_id * 512 // Standard capacity card: convert to byte address
+            block_id * 512
         };
 
         trace!(
-            "Reading {} blocks starting at address: {:#x}",
-            blocks, card_addr
+            "{} {} blocks starting at address: {:#x}",
+            if read { "Reading" } else { "Writing" },
+            blocks,
+            card_addr
         );
 
-        // Select appropriate command based on number of blocks
-        if blocks == 1 {
-            // Single block read operation
-            let cmd = EMmcCommand::new(MMC_READ_SINGLE_BLOCK, card_addr, MMC_RSP_R1)
-                .with_data(512, 1, true); // Configure for reading 512 bytes with DMA
-            self.send_command(&cmd, Some(DataBuffer::Read(buffer)))?;
-        } else {
-            // Multiple block read operation
-            let cmd = EMmcCommand::new(MMC_READ_MULTIPLE_BLOCK, card_addr, MMC_RSP_R1)
-                .with_data(512, blocks, true); // Configure for reading multiple blocks with DMA
+        let opcode = match (read, blocks) {
+            (true, 1) => MMC_READ_SINGLE_BLOCK,
+            (true, _) => MMC_READ_MULTIPLE_BLOCK,
+            (false, 1) => MMC_WRITE_BLOCK,
+            (false, _) => MMC_WRITE_MULTIPLE_BLOCK,
+        };
+
+        Ok(EMmcCommand::new(opcode, card_addr, MMC_RSP_R1).with_data(512, blocks, read))
+    }
+
+    /// Read one or more data blocks from the card
+    #[cfg(feature = "dma")]
+    pub fn read_blocks(
+        &mut self,
+        block_id: u32,
+        blocks: u16,
+        buffer: &mut DVec<u8>,
+    ) -> Result<(), SdError> {
+        // Check if buffer size matches the expected size based on number of blocks
+        let expected_size = blocks as usize * 512;
+        if buffer.len() != expected_size {
+            return Err(SdError::IoError);
+        }
 
-            self.send_command(&cmd, Some(DataBuffer::Read(buffer)))?;
+        let cmd = self.rw_blocks_cmd(block_id, blocks, true)?;
+        self.send_command(&cmd, Some(DataBuffer::Read(buffer)))?;
 
+        if blocks > 1 {
             // Must send stop transmission command after multiple block read
             let stop_cmd = EMmcCommand::new(MMC_STOP_TRANSMISSION, 0, MMC_RSP_R1B);
             self.send_command(&stop_cmd, None)?;
@@ -235,72 +276,185 @@ impl EMmcHost {
     /// Write multiple blocks to the card
     #[cfg(feature = "dma")]
     pub fn write_blocks(
-        &self,
+        &mut self,
         block_id: u32,
         blocks: u16,
         buffer: &DVec<u8>,
     ) -> Result<(), SdError> {
-        // Verify that buffer size matches the requested number of blocks
+        // Check if buffer size matches the expected size based on number of blocks
         let expected_size = blocks as usize * 512;
         if buffer.len() != expected_size {
             return Err(SdError::IoError);
         }
 
-        // Extract card information and check if card exists
-        let card = match &self.card {
-            Some(card) => card,
-            None => return Err(SdError::NoCard),
-        };
-
         // Check if card is properly initialized
-        if !card.initialized.load(Ordering::SeqCst) {
+        if let Some(card) = &self.card
+            && !card.initialized.load(Ordering::SeqCst)
+        {
             return Err(SdError::UnsupportedCard);
         }
 
-        // Check if card is write protected
+        let cmd = self.rw_blocks_cmd(block_id, blocks, false)?;
+        self.send_command(&cmd, Some(DataBuffer::Write(buffer)))?;
+
+        if blocks > 1 {
+            // Must send stop transmission command after multiple block write
+            let stop_cmd = EMmcCommand::new(MMC_STOP_TRANSMISSION, 0, MMC_RSP_R1B);
+            self.send_command(&stop_cmd, None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Erase `blocks` blocks starting at `block_id` (CMD35/CMD36/CMD38).
+    /// `arg` selects erase, trim or discard, e.g. `MMC_TRIM_ARG`.
+    pub fn erase_blocks(&self, block_id: u32, blocks: u32, arg: u32) -> Result<(), SdError> {
+        if blocks == 0 {
+            return Err(SdError::InvalidArgument);
+        }
+        let last = block_id
+            .checked_add(blocks - 1)
+            .ok_or(SdError::InvalidArgument)?;
+
+        let card = self.card.as_ref().ok_or(SdError::NoCard)?;
+        if card.locked {
+            return Err(SdError::CardLocked);
+        }
         if self.is_write_protected() {
-            return Err(SdError::IoError);
+            return Err(SdError::WriteProtected);
+        }
+        if arg & MMC_TRIM_ARG != 0 && !card.can_trim {
+            return Err(SdError::NotSupported);
         }
 
-        // Determine the correct address based on card capacity type
-        let card_addr = if card.state & MMC_STATE_HIGHCAPACITY != 0 {
-            block_id // High capacity card: use block address directly
+        let (start, end) = if card.state & MMC_STATE_HIGHCAPACITY != 0 {
+            (block_id, last)
         } else {
-            block_id * 512 // Standard capacity card: convert to byte address
+            (
+                block_id.checked_mul(512).ok_or(SdError::InvalidArgument)?,
+                last.checked_mul(512).ok_or(SdError::InvalidArgument)?,
+            )
         };
 
+        let cmd = EMmcCommand::new(MMC_EARSE_GROUP_START, start, MMC_RSP_R1);
+        self.send_command(&cmd, None)?;
+        let cmd = EMmcCommand::new(MMC_EARSE_GROUP_END, end, MMC_RSP_R1);
+        self.send_command(&cmd, None)?;
+
+        // The card takes its erase or trim time once per erase group touched
+        let group = card.erase_grp_size.max(1);
+        let groups = (last / group - block_id / group) as u64 + 1;
+        let timeout_us = card.timeouts.erase_us(arg, groups, card.clock);
+
+        let cmd = EMmcCommand::new(MMC_ERASE, arg, MMC_RSP_R1B).with_busy_timeout(timeout_us);
+        self.send_command(&cmd, None)?;
+        self.mmc_poll_for_busy(true, timeout_us)?;
+
         trace!(
-            "Writing {} blocks starting at address: {:#x}",
-            blocks, card_addr
+            "Erased blocks {:#x}..={:#x}, arg {:#x}",
+            block_id, last, arg
         );
+        Ok(())
+    }
+
+    /// Reliable write of `blocks` blocks (CMD23 with the reliable write flag,
+    /// then CMD25). The data bypasses the volatile cache, and an interrupted
+    /// write leaves the old contents of each sector in place. The size must
+    /// be one accepted by `reliable_write_blocks()`.
+    #[cfg(feature = "pio")]
+    pub fn write_blocks_reliable(
+        &mut self,
+        block_id: u32,
+        blocks: u16,
+        buffer: &[u8],
+    ) -> Result<(), SdError> {
+        if buffer.len() != blocks as usize * 512 {
+            return Err(SdError::IoError);
+        }
+
+        self.reliable_write(block_id, blocks, DataBuffer::Write(buffer))
+    }
+
+    /// Reliable write of `blocks` blocks (CMD23 with the reliable write flag,
+    /// then CMD25). The data bypasses the volatile cache, and an interrupted
+    /// write leaves the old contents of each sector in place. The size must
+    /// be one accepted by `reliable_write_blocks()`.
+    #[cfg(feature = "dma")]
+    pub fn write_blocks_reliable(
+        &mut self,
+        block_id: u32,
+        blocks: u16,
+        buffer: &DVec<u8>,
+    ) -> Result<(), SdError> {
+        if buffer.len() != blocks as usize * 512 {
+            return Err(SdError::IoError);
+        }
 
-        // Select appropriate command based on number of blocks
-        if blocks == 1 {
-            // Single block write operation
-            let cmd =
-                EMmcCommand::new(MMC_WRITE_BLOCK, card_addr, MMC_RSP_R1).with_data(512, 1, false); // Configure for writing 512 bytes with DMA (false = write)
-            self.send_command(&cmd, Some(DataBuffer::Write(buffer)))?;
+        self.reliable_write(block_id, blocks, DataBuffer::Write(buffer))
+    }
+
+    /// Largest reliable write the card accepts at `block_id`, up to `blocks`.
+    /// Without EN_REL_WR in WR_REL_PARAM the card only supports the legacy
+    /// scheme of single blocks or REL_WR_SEC_C blocks aligned to that count.
+    pub fn reliable_write_blocks(&self, block_id: u32, blocks: u16) -> Result<u16, SdError> {
+        let card = self.card.as_ref().ok_or(SdError::NoCard)?;
+        let rel_sectors = card.rel_wr_sec_c as u16;
+        if rel_sectors == 0 {
+            return Err(SdError::NotSupported);
+        }
+
+        if card.wr_rel_param as u32 & EXT_CSD_WR_REL_PARAM_EN != 0 || blocks <= 1 {
+            Ok(blocks)
+        } else if !block_id.is_multiple_of(rel_sectors as u32) || blocks < rel_sectors {
+            Ok(1)
         } else {
-            // Multiple block write operation
-            let cmd = EMmcCommand::new(MMC_WRITE_MULTIPLE_BLOCK, card_addr, MMC_RSP_R1)
-                .with_data(512, blocks, false); // Configure for writing multiple blocks
+            Ok(rel_sectors)
+        }
+    }
 
-            self.send_command(&cmd, Some(DataBuffer::Write(buffer)))?;
+    fn reliable_write(
+        &mut self,
+        block_id: u32,
+        blocks: u16,
+        buffer: DataBuffer<'_>,
+    ) -> Result<(), SdError> {
+        if blocks == 0 || self.reliable_write_blocks(block_id, blocks)? != blocks {
+            return Err(SdError::InvalidArgument);
+        }
 
-            // Must send stop transmission command after multiple block write
-            let stop_cmd = EMmcCommand::new(MMC_STOP_TRANSMISSION, 0, MMC_RSP_R1B);
-            self.send_command(&stop_cmd, None)?;
+        let mut cmd = self.rw_blocks_cmd(block_id, blocks, false)?;
+        // CMD24 ignores CMD23, and the predefined count makes CMD12 unnecessary
+        cmd.opcode = MMC_WRITE_MULTIPLE_BLOCK;
+
+        let set_count = EMmcCommand::new(
+            MMC_SET_BLOCK_COUNT,
+            MMC_CMD23_ARG_REL_WR | blocks as u32,
+            MMC_RSP_R1,
+        );
+        self.send_command(&set_count, None)?;
+        self.send_command(&cmd, Some(buffer))
+    }
+
+    /// Write the volatile cache of the card back to flash (FLUSH_CACHE).
+    /// Nothing to do if the cache is off.
+    pub fn flush_cache(&mut self) -> Result<(), SdError> {
+        if !self.cache_ctrl().ok_or(SdError::NoCard)? {
+            return Ok(());
         }
 
-        Ok(())
+        self.mmc_switch(EXT_CSD_CMD_SET_NORMAL, EXT_CSD_FLUSH_CACHE, 1, true)
     }
 
     /// Transfer data using DMA mode
-    /// This function polls for transfer completion or errors
+    /// This function polls for transfer completion or errors for up to `timeout_us`
     #[cfg(feature = "dma")]
-    pub fn transfer_data_by_dma(&self) -> Result<(), SdError> {
-        let mut timeout = 100;
+    pub fn transfer_data_by_dma(&self, timeout_us: u64) -> Result<(), SdError> {
+        if self.irq_mode_enabled() {
+            let stat = self.irq_wait(EMMC_INT_DATA_END, timeout_us)?;
+            return self.dma_xfer_result(stat as u16, (stat >> 16) as u16);
+        }
 
+        let mut deadline = self.deadline(timeout_us);
         loop {
             // Read the interrupt status register
             let stat = self.read_reg16(EMMC_NORMAL_INT_STAT);
@@ -309,25 +463,7 @@ impl EMmcHost {
             // Check for any errors during transfer
             if stat & EMMC_INT_ERROR as u16 != 0 {
                 let err_status = self.read_reg16(EMMC_ERROR_INT_STAT);
-                trace!(
-                    "Data transfer error: status={:#b}, err_status={:#b}",
-                    stat, err_status
-                );
-
-                // Reset the data circuit to recover from error
-                self.reset_data()?;
-
-                // Determine specific error type based on error status bits
-                let err = if err_status & 0x10 != 0 {
-                    SdError::DataTimeout
-                } else if err_status & 0x20 != 0 {
-                    SdError::DataCrc
-                } else if err_status & 0x40 != 0 {
-                    SdError::DataEndBit
-                } else {
-                    SdError::DataError
-                };
-                return Err(err);
+                return self.dma_xfer_result(stat, err_status);
             }
 
             // Check if data transfer is complete
@@ -338,18 +474,36 @@ impl EMmcHost {
             }
 
             // Handle timeout to prevent infinite loop
-            if timeout > 0 {
-                timeout -= 1;
-                delay_us(1000); // Wait 1ms before checking again
-            } else {
+            if deadline.expired() {
+                self.check_card_removed()?;
                 info!("Data transfer timeout");
                 return Err(SdError::DataTimeout);
             }
+            self.wait_step(&mut deadline, 1000)?; // Wait 1ms before checking again
         }
 
         Ok(())
     }
 
+    #[cfg(feature = "dma")]
+    pub(crate) fn dma_xfer_result(&self, stat: u16, err_status: u16) -> Result<(), SdError> {
+        if stat & EMMC_INT_ERROR as u16 == 0 {
+            return Ok(());
+        }
+
+        trace!(
+            "Data transfer error: status={:#b}, err_status={:#b}",
+            stat, err_status
+        );
+
+        let err = self.int_error(stat, err_status, true);
+
+        // Reset the data circuit to recover from error
+        self.reset_data()?;
+
+        Err(err)
+    }
+
     /// Read blocks from SD card using PIO (Programmed I/O) mode
     /// Parameters:
     /// - block_id: Starting block address to read from
@@ -357,7 +511,7 @@ impl EMmcHost {
     /// - buffer: Buffer to store the read data
     #[cfg(feature = "pio")]
     pub fn read_blocks(
-        &self,
+        &mut self,
         block_id: u32,
         blocks: u16,
         buffer: &mut [u8],
@@ -366,37 +520,11 @@ impl EMmcHost {
             "pio read_blocks: block_id = {}, blocks = {}",
             block_id, blocks
         );
-        // Check if card is initialized
-        let card = match &self.card {
-            Some(card) => card,
-            None => return Err(SdError::NoCard),
-        };
-
-        // Adjust block address based on card type
-        // High capacity cards use block addressing, standard capacity cards use byte addressing
-        let card_addr = if card.state & MMC_STATE_HIGHCAPACITY != 0 {
-            block_id // High capacity card: use block address directly
-        } else {
-            block_id * 512 // Standard capacity card: convert to byte address
-        };
-
-        trace!(
-            "Reading {} blocks starting at address: {:#x}",
-            blocks, card_addr
-        );
 
-        if blocks == 1 {
-            // Single block read operation
-            let cmd = EMmcCommand::new(MMC_READ_SINGLE_BLOCK, card_addr, MMC_RSP_R1)
-                .with_data(512, 1, true);
-            self.send_command(&cmd, Some(DataBuffer::Read(buffer)))?;
-        } else {
-            // Multiple block read operation
-            let cmd = EMmcCommand::new(MMC_READ_MULTIPLE_BLOCK, card_addr, MMC_RSP_R1)
-                .with_data(512, blocks, true);
-
-            self.send_command(&cmd, Some(DataBuffer::Read(buffer)))?;
+        let cmd = self.rw_blocks_cmd(block_id, blocks, true)?;
+        self.send_command(&cmd, Some(DataBuffer::Read(buffer)))?;
 
+        if blocks > 1 {
             // Must send stop transmission command after multiple block read
             let stop_cmd = EMmcCommand::new(MMC_STOP_TRANSMISSION, 0, MMC_RSP_R1B);
             self.send_command(&stop_cmd, None)?;
@@ -411,49 +539,21 @@ impl EMmcHost {
     /// - blocks: Number of blocks to write
     /// - buffer: Buffer containing data to write
     #[cfg(feature = "pio")]
-    pub fn write_blocks(&self, block_id: u32, blocks: u16, buffer: &[u8]) -> Result<(), SdError> {
-        use log::trace;
-
+    pub fn write_blocks(
+        &mut self,
+        block_id: u32,
+        blocks: u16,
+        buffer: &[u8],
+    ) -> Result<(), SdError> {
         trace!(
             "pio write_blocks: block_id = {}, blocks = {}",
             block_id, blocks
         );
-        // Check if card is initialized
-        let card = match &self.card {
-            Some(card) => card,
-            None => return Err(SdError::NoCard),
-        };
-
-        // Check if card is write protected
-        if self.is_write_protected() {
-            return Err(SdError::IoError);
-        }
-
-        // Determine the correct address based on card capacity type
-        let card_addr = if card.state & MMC_STATE_HIGHCAPACITY != 0 {
-            block_id // High capacity card: use block address directly
-        } else {
-            block_id * 512 // Standard capacity card: convert to byte address
-        };
-
-        trace!(
-            "Writing {} blocks starting at address: {:#x}",
-            blocks, card_addr
-        );
-
-        // Select appropriate command based on number of blocks
-        if blocks == 1 {
-            // Single block write operation
-            let cmd =
-                EMmcCommand::new(MMC_WRITE_BLOCK, card_addr, MMC_RSP_R1).with_data(512, 1, false);
-            self.send_command(&cmd, Some(DataBuffer::Write(buffer)))?;
-        } else {
-            // Multiple block write operation
-            let cmd = EMmcCommand::new(MMC_WRITE_MULTIPLE_BLOCK, card_addr, MMC_RSP_R1)
-                .with_data(512, blocks, false);
 
-            self.send_command(&cmd, Some(DataBuffer::Write(buffer)))?;
+        let cmd = self.rw_blocks_cmd(block_id, blocks, false)?;
+        self.send_command(&cmd, Some(DataBuffer::Write(buffer)))?;
 
+        if blocks > 1 {
             // Must send stop transmission command after multiple block write
             let stop_cmd = EMmcCommand::new(MMC_STOP_TRANSMISSION, 0, MMC_RSP_R1B);
             self.send_command(&stop_cmd, None)?;
@@ -533,9 +633,37 @@ impl EMmcHost {
     /// Write data to SD card buffer register
     /// This is a lower-level function used by data transfer operations
     pub fn write_buffer(&self, buffer: &[u8]) -> Result<(), SdError> {
+        let blocks = buffer.len().div_ceil(MMC_MAX_BLOCK_LEN as usize) as u64;
+
         // Wait until space is available in the controller buffer
-        self.wait_for_interrupt(EMMC_INT_SPACE_AVAIL, 100000)?;
+        self.wait_for_interrupt(EMMC_INT_SPACE_AVAIL, self.data_timeout_us(true, 1))?;
+
+        self.pio_write_buf(buffer);
+
+        // Wait for data transfer to complete
+        self.wait_for_interrupt(EMMC_INT_DATA_END, self.data_timeout_us(true, blocks))?;
+
+        Ok(())
+    }
+
+    /// Read data from SD card buffer register
+    /// This is a lower-level function used by data transfer operations
+    pub fn read_buffer(&self, buffer: &mut [u8]) -> Result<(), SdError> {
+        let blocks = buffer.len().div_ceil(MMC_MAX_BLOCK_LEN as usize) as u64;
+
+        // Wait until data is available in the controller buffer
+        self.wait_for_interrupt(EMMC_INT_DATA_AVAIL, self.data_timeout_us(false, 1))?;
+
+        self.pio_read_buf(buffer);
+
+        // Wait for data transfer to complete
+        self.wait_for_interrupt(EMMC_INT_DATA_END, self.data_timeout_us(false, blocks))?;
+
+        Ok(())
+    }
 
+    // Move `buffer` into the controller buffer, once SPACE_AVAIL is signalled
+    pub(crate) fn pio_write_buf(&self, buffer: &[u8]) {
         let len = buffer.len();
         // Write data in 4-byte chunks
         for i in (0..len).step_by(4) {
@@ -557,19 +685,10 @@ impl EMmcHost {
             // Write the 32-bit word to the buffer data register
             self.write_reg(EMMC_BUF_DATA, val);
         }
-
-        // Wait for data transfer to complete
-        self.wait_for_interrupt(EMMC_INT_DATA_END, 1000000)?;
-
-        Ok(())
     }
 
-    /// Read data from SD card buffer register
-    /// This is a lower-level function used by data transfer operations
-    pub fn read_buffer(&self, buffer: &mut [u8]) -> Result<(), SdError> {
-        // Wait until data is available in the controller buffer
-        self.wait_for_interrupt(EMMC_INT_DATA_AVAIL, 100000)?;
-
+    // Move the controller buffer into `buffer`, once DATA_AVAIL is signalled
+    pub(crate) fn pio_read_buf(&self, buffer: &mut [u8]) {
         // Read data into buffer in 4-byte chunks
         let len = buffer.len();
         for i in (0..len).step_by(4) {
@@ -591,21 +710,21 @@ impl EMmcHost {
                 buffer[i + 3] = ((val >> 24) & 0xFF) as u8;
             }
         }
-
-        // Wait for data transfer to complete
-        self.wait_for_interrupt(EMMC_INT_DATA_END, 100000)?;
-
-        Ok(())
     }
 
     /// Wait for a specific interrupt flag to be set
     /// Helper function used by data transfer operations
     /// Parameters:
     /// - flag: The interrupt flag to wait for
-    /// - timeout_count: Maximum number of iterations to wait
-    fn wait_for_interrupt(&self, flag: u32, timeout_count: u32) -> Result<(), SdError> {
-        let mut timeout = timeout_count;
-        while timeout > 0 {
+    /// - timeout_us: Maximum time to wait
+    fn wait_for_interrupt(&self, flag: u32, timeout_us: u64) -> Result<(), SdError> {
+        if self.irq_mode_enabled() {
+            let int_status = self.irq_wait(flag, timeout_us)?;
+            return self.data_irq_result(int_status);
+        }
+
+        let mut deadline = self.deadline(timeout_us);
+        loop {
             // Read the current interrupt status
             let int_status = self.read_reg(EMMC_NORMAL_INT_STAT);
 
@@ -618,6 +737,8 @@ impl EMmcHost {
 
             // Check for any error flags
             if int_status & EMMC_INT_ERROR_MASK != 0 {
+                let err = self.int_error(int_status as u16, (int_status >> 16) as u16, true);
+
                 // Clear error flags
                 self.write_reg16(
                     EMMC_NORMAL_INT_STAT,
@@ -625,17 +746,26 @@ impl EMmcHost {
                 );
                 // Reset the data circuit
                 self.reset_data()?;
-                return Err(SdError::DataError);
+                return Err(err);
             }
 
-            timeout -= 1;
-        }
+            // If we reached the timeout limit, return timeout error
+            if deadline.expired() {
+                self.check_card_removed()?;
+                return Err(SdError::DataTimeout);
+            }
 
-        // If we reached the timeout limit, return timeout error
-        if timeout == 0 {
-            return Err(SdError::DataTimeout);
+            self.wait_step(&mut deadline, 1)?;
         }
+    }
 
+    // Outcome of a PIO data phase step from the recorded interrupt status
+    pub(crate) fn data_irq_result(&self, int_status: u32) -> Result<(), SdError> {
+        if int_status & EMMC_INT_ERROR_MASK != 0 {
+            let err = self.int_error(int_status as u16, (int_status >> 16) as u16, true);
+            self.reset_data()?;
+            return Err(err);
+        }
         Ok(())
     }
 }
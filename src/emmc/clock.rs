@@ -1,4 +1,7 @@
-use spin::Once;
+use alloc::boxed::Box;
+use core::fmt;
+
+use super::EMmcHost;
 
 #[derive(Debug, Clone, Copy)]
 pub enum ClkError {
@@ -9,33 +12,114 @@ pub enum ClkError {
     NotInitialized,
 }
 
-pub trait Clk {
-    fn emmc_get_clk(&self) -> Result<u64, ClkError>;
-    fn emmc_set_clk(&self, rate: u64) -> Result<u64, ClkError>;
-}
+/// Clock controller serving the clocks of a host, e.g. the SoC CRU.
+/// `id` is the clock specifier as used in the device tree.
+pub trait Clk: Send + Sync {
+    fn get_rate(&self, id: u32) -> Result<u64, ClkError>;
 
-static INIT: Once = Once::new();
-static mut GLOBAL_CLK_INSTANCE: Option<&'static dyn Clk> = None;
+    /// Request `rate` Hz and return the rate actually achieved
+    fn set_rate(&self, id: u32, rate: u64) -> Result<u64, ClkError>;
 
-pub fn init_global_clk(clk: &'static dyn Clk) {
-    INIT.call_once(|| unsafe {
-        GLOBAL_CLK_INSTANCE = Some(clk);
-    });
+    fn enable(&self, _id: u32) -> Result<(), ClkError> {
+        Ok(())
+    }
+
+    fn disable(&self, _id: u32) -> Result<(), ClkError> {
+        Ok(())
+    }
 }
 
-pub fn global_clk() -> Result<&'static dyn Clk, ClkError> {
-    unsafe {
-        match GLOBAL_CLK_INSTANCE {
-            Some(instance) => Ok(instance),
-            None => Err(ClkError::NotInitialized),
+// Clock provider owned by a host together with the ID of its card clock
+pub struct HostClk {
+    provider: Option<Box<dyn Clk>>,
+    id: Option<u32>,
+}
+
+impl HostClk {
+    pub const fn new() -> Self {
+        Self {
+            provider: None,
+            id: None,
         }
     }
+
+    pub fn set_provider(&mut self, provider: Box<dyn Clk>) {
+        self.provider = Some(provider);
+    }
+
+    pub fn set_id(&mut self, id: u32) {
+        self.id = Some(id);
+    }
+
+    pub fn id(&self) -> Option<u32> {
+        self.id
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.provider.is_some()
+    }
+
+    // Provider and card clock ID, a provider without an ID has no clock to serve
+    fn provider(&self) -> Result<(&dyn Clk, u32), ClkError> {
+        let provider = self.provider.as_deref().ok_or(ClkError::NotInitialized)?;
+        let id = self.id.ok_or(ClkError::InvalidPeripheralId)?;
+        Ok((provider, id))
+    }
+
+    pub fn get_rate(&self) -> Result<u64, ClkError> {
+        let (provider, id) = self.provider()?;
+        provider.get_rate(id)
+    }
+
+    pub fn set_rate(&self, rate: u64) -> Result<u64, ClkError> {
+        let (provider, id) = self.provider()?;
+        provider.set_rate(id, rate)
+    }
+
+    pub fn enable(&self) -> Result<(), ClkError> {
+        let (provider, id) = self.provider()?;
+        provider.enable(id)
+    }
+
+    pub fn disable(&self) -> Result<(), ClkError> {
+        let (provider, id) = self.provider()?;
+        provider.disable(id)
+    }
 }
 
-pub fn emmc_set_clk(rate: u64) -> Result<u64, ClkError> {
-    global_clk()?.emmc_set_clk(rate)
+impl Default for HostClk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-pub fn emmc_get_clk() -> Result<u64, ClkError> {
-    global_clk()?.emmc_get_clk()
+impl fmt::Debug for HostClk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "HostClk {{ provider: {}, id: {:?} }}",
+            self.provider.is_some(),
+            self.id
+        )
+    }
+}
+
+impl EMmcHost {
+    /// Hand the clock controller of this host to the driver. The card clock
+    /// has to be selected with `with_clk_id()` or by the device tree, clock
+    /// requests fail with `InvalidPeripheralId` otherwise.
+    pub fn with_clk(mut self, clk: Box<dyn Clk>) -> Self {
+        self.clk.set_provider(clk);
+        self
+    }
+
+    /// Select the card clock of the provider, e.g. `CCLK_EMMC`
+    pub fn with_clk_id(mut self, id: u32) -> Self {
+        self.clk.set_id(id);
+        self
+    }
+
+    pub fn clk(&self) -> &HostClk {
+        &self.clk
+    }
 }
@@ -1,16 +1,28 @@
+use alloc::boxed::Box;
 #[cfg(feature = "dma")]
-use dma_api::DVec;
+use dma_api::{DVec, Direction};
 use log::{debug, info, trace};
 
-use crate::{delay_us, emmc::CardType, err::SdError};
+use crate::{
+    emmc::CardType,
+    err::{CommandError, ErrorOrigin, RegSnapshot, SdError},
+};
 
-use super::{EMmcHost, block::DataBuffer, constant::*};
+use super::status::{CardState, CardStatus, StatusFlags};
+
+use super::{EMmcHost, block::DataBuffer, constant::*, irq::IRQ_CMD_DATA_MASK};
 
 #[allow(dead_code)]
 const EMMC_DEFAULT_BOUNDARY_ARG: u16 = 7;
 
-const CMD_DEFAULT_TIMEOUT: u32 = 100;
-const CMD_MAX_TIMEOUT: u32 = 500;
+// Response timeouts, CMD0 and CMD1 are given longer
+const CMD_DEFAULT_TIMEOUT_US: u64 = 10_000;
+const CMD_MAX_TIMEOUT_US: u64 = 50_000;
+// Wait for CMD/DAT inhibit before issuing a command
+const CMD_INHIBIT_TIMEOUT_US: u64 = 500_000;
+const RESET_TIMEOUT_US: u64 = 100_000;
+// Time the card may take to finish power-up and leave CMD1 busy
+const OP_COND_TIMEOUT_US: u64 = 1_000_000;
 
 #[derive(Debug)]
 pub struct EMmcCommand {
@@ -21,6 +33,8 @@ pub struct EMmcCommand {
     pub data_dir_read: bool,
     pub block_size: u16,
     pub block_count: u16,
+    /// Busy time of an R1b command, 0 for the CMD6 default of the card
+    pub busy_timeout_us: u64,
 }
 
 impl EMmcCommand {
@@ -33,6 +47,7 @@ impl EMmcCommand {
             data_dir_read: true,
             block_size: 0,
             block_count: 0,
+            busy_timeout_us: 0,
         }
     }
 
@@ -43,6 +58,20 @@ impl EMmcCommand {
         self.block_count = block_count;
         self
     }
+
+    pub fn with_busy_timeout(mut self, timeout_us: u64) -> Self {
+        self.busy_timeout_us = timeout_us;
+        self
+    }
+
+    // Whether the response carries the R1 card status. R5/R6/R7 share the
+    // flags of R1, so the SDIO and SD_SEND_IF_COND opcodes are told apart.
+    pub(crate) fn has_r1_response(&self) -> bool {
+        self.resp_type & !MMC_RSP_BUSY == MMC_RSP_R1
+            && self.opcode != SD_IO_RW_DIRECT
+            && self.opcode != SD_IO_RW_EXTENDED
+            && (self.opcode != MMC_SEND_EXT_CSD || self.data_present)
+    }
 }
 
 pub struct SdResponse {
@@ -90,11 +119,121 @@ impl SdResponse {
 impl EMmcHost {
     // Send command
     pub fn send_command(
+        &self,
+        cmd: &EMmcCommand,
+        data_buffer: Option<DataBuffer>,
+    ) -> Result<(), SdError> {
+        self.send_command_inner(cmd, data_buffer)
+            .and_then(|_| self.check_card_status(cmd))
+            .map_err(|err| self.command_error(cmd, err))
+    }
+
+    // Fail R1/R1b commands whose response reports an error
+    pub(crate) fn check_card_status(&self, cmd: &EMmcCommand) -> Result<(), SdError> {
+        if !cmd.has_r1_response() {
+            return Ok(());
+        }
+
+        let status = CardStatus::new(self.get_response().as_r1());
+        let mut errors = status.flags() & StatusFlags::CHECKED;
+        // Multi-block reads ending at the last block report OUT_OF_RANGE on CMD12
+        if cmd.opcode == MMC_STOP_TRANSMISSION {
+            errors.remove(StatusFlags::OUT_OF_RANGE);
+        }
+
+        if !errors.is_empty() {
+            info!("CMD{} card status error: {}", cmd.opcode, status);
+            return Err(SdError::CardStatus(status));
+        }
+
+        Ok(())
+    }
+
+    fn send_command_inner(
         &self,
         cmd: &EMmcCommand,
         mut data_buffer: Option<DataBuffer>,
     ) -> Result<(), SdError> {
-        let mut cmd_timeout = CMD_DEFAULT_TIMEOUT;
+        let int_mask = self.cmd_start(cmd, &data_buffer)?;
+
+        // Special command handling
+        let timeout_us = if int_mask & EMMC_INT_DATA_END as u16 != 0 {
+            self.cmd_data_timeout_us(cmd)
+        } else if cmd.opcode == MMC_GO_IDLE_STATE || cmd.opcode == MMC_SEND_OP_COND {
+            CMD_MAX_TIMEOUT_US
+        } else {
+            CMD_DEFAULT_TIMEOUT_US
+        };
+
+        // Wait for command completion
+        let mut status: u16;
+        let mut err_status: Option<u16> = None;
+        if self.irq_mode_enabled() {
+            let stat = self.irq_wait(int_mask as u32, timeout_us)?;
+            status = stat as u16;
+            err_status = Some((stat >> 16) as u16);
+        } else {
+            let mut deadline = self.deadline(timeout_us);
+            loop {
+                status = self.read_reg16(EMMC_NORMAL_INT_STAT);
+                trace!("Response Status: {:#b}", status);
+
+                // Check for errors
+                if status & EMMC_INT_ERROR as u16 != 0 {
+                    break;
+                }
+
+                // Check for response completion
+                if (status & int_mask) == int_mask {
+                    break;
+                }
+
+                // Check for timeout
+                if deadline.expired() {
+                    self.check_card_removed()?;
+                    info!("Timeout for status update!");
+                    return Err(SdError::Timeout);
+                }
+
+                self.wait_step(&mut deadline, 100)?;
+            }
+        }
+
+        self.cmd_complete(cmd, int_mask, status, err_status)?;
+
+        // Process data transfer part
+        if cmd.data_present {
+            trace!("Data transfer: cmd.data_present={}", cmd.data_present);
+            if let Some(buffer) = &mut data_buffer {
+                #[cfg(feature = "dma")]
+                self.transfer_data_by_dma(self.cmd_data_timeout_us(cmd))?;
+
+                #[cfg(feature = "pio")]
+                match buffer {
+                    DataBuffer::Read(buf) => self.read_buffer(buf)?,
+                    DataBuffer::Write(buf) => self.write_buffer(buf)?,
+                }
+            } else {
+                return Err(SdError::InvalidArgument);
+            }
+        }
+
+        self.cmd_finish()
+    }
+
+    // Program the transfer and issue `cmd`, shared by the sync and async paths.
+    // Returns the interrupts that signal completion of the command phase.
+    pub(crate) fn cmd_start(
+        &self,
+        cmd: &EMmcCommand,
+        data_buffer: &Option<DataBuffer>,
+    ) -> Result<u16, SdError> {
+        self.check_card_removed()?;
+
+        // Legacy commands have to wait for the queue engine to be halted
+        if self.cqe.as_ref().is_some_and(|cqe| cqe.running()) {
+            return Err(SdError::QueueActive);
+        }
 
         // Check if command or data line is busy
         let mut mask = EMMC_CMD_INHIBIT;
@@ -107,30 +246,19 @@ impl EMmcHost {
             mask &= !EMMC_DATA_INHIBIT;
         }
 
-        // Wait using dynamically adjusted timeout
-        let mut time: u32 = 0;
-        while (self.read_reg(EMMC_PRESENT_STATE) & mask) != 0 {
-            if time >= cmd_timeout {
-                info!("MMC: busy timeout");
-
-                // If timeout can be increased, double the timeout and continue
-                if 2 * cmd_timeout <= CMD_MAX_TIMEOUT {
-                    cmd_timeout += cmd_timeout;
-                    info!("timeout increasing to: {} ms.", cmd_timeout);
-                    self.write_reg16(EMMC_NORMAL_INT_STAT, 0xFFFF);
-                } else {
-                    info!("timeout.");
-                    // Do not return an error, attempt to continue sending the command
-                    break;
-                }
-            }
-            time += 1;
-            delay_us(1000);
+        // Wait for the lines to be released
+        let inhibit = self.poll_until(CMD_INHIBIT_TIMEOUT_US, 1000, || {
+            self.read_reg(EMMC_PRESENT_STATE) & mask == 0
+        });
+        if inhibit.is_err() {
+            // Do not return an error, attempt to continue sending the command
+            info!("MMC: busy timeout");
         }
 
-        // Clear all interrupt statuses
-        self.write_reg16(EMMC_NORMAL_INT_STAT, 0xFFFF);
-        self.write_reg16(EMMC_ERROR_INT_STAT, 0xFFFF);
+        // Clear the command, data and error statuses. Card detect and card
+        // interrupt status stay for the hot-plug and SDIO handlers.
+        self.write_reg(EMMC_NORMAL_INT_STAT, IRQ_CMD_DATA_MASK);
+        self.irq_clear();
 
         let mut int_mask = EMMC_INT_RESPONSE as u16;
 
@@ -141,7 +269,9 @@ impl EMmcHost {
 
         // Set data transfer-related registers
         if cmd.data_present {
-            self.write_reg8(EMMC_TIMEOUT_CONTROL, 0xe);
+            // The counter runs per block
+            let timeout_us = self.data_timeout_us(!cmd.data_dir_read, 1);
+            self.write_reg8(EMMC_TIMEOUT_CONTROL, self.timeout_control(timeout_us));
 
             let mut mode = EMMC_TRNS_BLK_CNT_EN;
 
@@ -159,7 +289,7 @@ impl EMmcHost {
                 self.write_reg16(EMMC_XFER_MODE, mode);
 
                 match data_buffer {
-                    Some(DataBuffer::Read(ref read_buf)) if cmd.data_dir_read => {
+                    Some(DataBuffer::Read(read_buf)) if cmd.data_dir_read => {
                         let ptr = read_buf.bus_addr() as usize;
 
                         debug!("Read buffer address: {:#x}", ptr);
@@ -178,9 +308,7 @@ impl EMmcHost {
                 // Set block size and count
                 self.write_reg16(
                     EMMC_BLOCK_SIZE,
-                    (((EMMC_DEFAULT_BOUNDARY_ARG & 0x7) << 12) | (cmd.block_size & 0xFFF))
-                        .try_into()
-                        .unwrap(),
+                    ((EMMC_DEFAULT_BOUNDARY_ARG & 0x7) << 12) | (cmd.block_size & 0xFFF),
                 );
                 self.write_reg16(EMMC_BLOCK_COUNT, cmd.block_count);
                 self.write_reg16(EMMC_XFER_MODE, mode);
@@ -188,10 +316,7 @@ impl EMmcHost {
 
             #[cfg(feature = "pio")]
             {
-                self.write_reg16(
-                    EMMC_BLOCK_SIZE,
-                    (cmd.block_size & 0xFFF).try_into().unwrap(),
-                );
+                self.write_reg16(EMMC_BLOCK_SIZE, cmd.block_size & 0xFFF);
                 self.write_reg16(EMMC_BLOCK_COUNT, cmd.block_count);
 
                 self.write_reg16(EMMC_XFER_MODE, mode);
@@ -203,7 +328,8 @@ impl EMmcHost {
             }
         } else if cmd.resp_type & MMC_RSP_BUSY != 0 {
             // For commands with BUSY but no data, still set timeout control
-            self.write_reg8(EMMC_TIMEOUT_CONTROL, 0xe);
+            let timeout_us = self.cmd_busy_timeout_us(cmd);
+            self.write_reg8(EMMC_TIMEOUT_CONTROL, self.timeout_control(timeout_us));
         }
 
         // Set parameters
@@ -246,42 +372,21 @@ impl EMmcHost {
             cmd.opcode, cmd.arg, cmd.resp_type, command
         );
 
-        // Special command handling
-        let mut timeout_val = if cmd.opcode == MMC_GO_IDLE_STATE || cmd.opcode == MMC_SEND_OP_COND {
-            CMD_MAX_TIMEOUT
-        } else {
-            CMD_DEFAULT_TIMEOUT
-        };
-
         // Send the command
         self.write_reg16(EMMC_COMMAND, command);
 
-        // Wait for command completion
-        let mut status: u16;
-        loop {
-            status = self.read_reg16(EMMC_NORMAL_INT_STAT);
-            trace!("Response Status: {:#b}", status);
-
-            // Check for errors
-            if status & EMMC_INT_ERROR as u16 != 0 {
-                break;
-            }
-
-            // Check for response completion
-            if (status & int_mask) == int_mask {
-                break;
-            }
-
-            // Check for timeout
-            if timeout_val == 0 {
-                info!("Timeout for status update!");
-                return Err(SdError::Timeout);
-            }
-
-            timeout_val -= 1;
-            delay_us(100);
-        }
+        Ok(int_mask)
+    }
 
+    // Check the status the command phase ended with. `err_status` is the error
+    // status recorded together with `status`, read from the controller if `None`.
+    pub(crate) fn cmd_complete(
+        &self,
+        cmd: &EMmcCommand,
+        int_mask: u16,
+        status: u16,
+        err_status: Option<u16>,
+    ) -> Result<(), SdError> {
         // Process command completion
         if (status & (EMMC_INT_ERROR as u16 | int_mask)) == int_mask {
             // Command successfully completed
@@ -298,62 +403,132 @@ impl EMmcHost {
                 self.read_reg16(EMMC_ERROR_INT_STAT)
             );
 
-            let err_status = self.read_reg16(EMMC_ERROR_INT_STAT);
+            let err_status = err_status.unwrap_or_else(|| self.read_reg16(EMMC_ERROR_INT_STAT));
             info!(
                 "Command error: status={:#b}, err_status={:#b}",
                 status, err_status
             );
 
+            // Decode before the reset clears the state
+            let err = self.int_error(status, err_status, false);
+
             // Reset command and data lines
             self.reset_cmd()?;
             if cmd.data_present {
                 self.reset_data()?;
             }
 
-            // Map specific error types
-            let err = if err_status & 0x1 != 0 {
-                SdError::Timeout
-            } else if err_status & 0x2 != 0 {
-                SdError::Crc
-            } else if err_status & 0x4 != 0 {
-                SdError::EndBit
-            } else if err_status & 0x8 != 0 {
-                SdError::Index
-            } else if err_status & 0x10 != 0 {
-                SdError::DataTimeout
-            } else if err_status & 0x20 != 0 {
-                SdError::DataCrc
-            } else if err_status & 0x40 != 0 {
-                SdError::DataEndBit
-            } else if err_status & 0x80 != 0 {
-                SdError::CurrentLimit
-            } else {
-                SdError::CommandError
-            };
+            return Err(self.command_error(cmd, err));
+        }
+
+        Ok(())
+    }
+
+    // Decode the interrupt status of a failed command or data phase, shared by
+    // the command path and the DMA and PIO data paths. Call it before the
+    // lines are reset so the snapshot still shows the failure.
+    pub(crate) fn int_error(&self, status: u16, err_status: u16, data: bool) -> SdError {
+        let bits = err_status as u32;
+        let cause = if bits & EMMC_INT_ERR_CMD_TIMEOUT != 0 {
+            SdError::Timeout
+        } else if bits & EMMC_INT_ERR_CMD_CRC != 0 {
+            SdError::Crc
+        } else if bits & EMMC_INT_ERR_CMD_END_BIT != 0 {
+            SdError::EndBit
+        } else if bits & EMMC_INT_ERR_CMD_INDEX != 0 {
+            SdError::Index
+        } else if bits & EMMC_INT_ERR_DATA_TIMEOUT != 0 {
+            SdError::DataTimeout
+        } else if bits & EMMC_INT_ERR_DATA_CRC != 0 {
+            SdError::DataCrc
+        } else if bits & EMMC_INT_ERR_DATA_END_BIT != 0 {
+            SdError::DataEndBit
+        } else if bits & EMMC_INT_ERR_CURRENT_LIMIT != 0 {
+            SdError::CurrentLimit
+        } else if bits & EMMC_INT_ERR_AUTO_CMD != 0 {
+            SdError::Acmd12Error
+        } else if bits & EMMC_INT_ERR_ADMA != 0 {
+            SdError::AdmaError
+        } else if data {
+            SdError::DataError
+        } else {
+            SdError::CommandError
+        };
+
+        SdError::Command(Box::new(CommandError {
+            cause,
+            command: None,
+            response: self.get_response().raw,
+            regs: self.reg_snapshot(status, err_status),
+            card_status: None,
+        }))
+    }
 
-            return Err(err);
+    fn reg_snapshot(&self, status: u16, err_status: u16) -> RegSnapshot {
+        RegSnapshot {
+            normal_int_stat: status,
+            error_int_stat: err_status,
+            auto_cmd_stat: self.read_reg16(EMMC_AUTO_CMD_STAT),
+            adma_err_stat: self.read_reg8(EMMC_ADMA_ERR_STAT),
+            present_state: self.read_reg(EMMC_PRESENT_STATE),
         }
+    }
 
-        // Process data transfer part
-        if cmd.data_present {
-            trace!("Data transfer: cmd.data_present={}", cmd.data_present);
-            if let Some(buffer) = &mut data_buffer {
-                #[cfg(feature = "dma")]
-                self.transfer_data_by_dma()?;
+    // Attach `cmd` to an error from its command or data phase. Bus and card
+    // status errors detected without a decoded status get a snapshot of the
+    // current state.
+    pub(crate) fn command_error(&self, cmd: &EMmcCommand, err: SdError) -> SdError {
+        let mut err = match err {
+            SdError::Command(err) => err,
+            err if err.origin() == ErrorOrigin::Bus || matches!(err, SdError::CardStatus(_)) => {
+                Box::new(CommandError {
+                    cause: err,
+                    command: None,
+                    response: self.get_response().raw,
+                    regs: self.reg_snapshot(
+                        self.read_reg16(EMMC_NORMAL_INT_STAT),
+                        self.read_reg16(EMMC_ERROR_INT_STAT),
+                    ),
+                    card_status: None,
+                })
+            }
+            err => return err,
+        };
 
-                #[cfg(feature = "pio")]
-                match buffer {
-                    DataBuffer::Read(buf) => self.read_buffer(buf)?,
-                    DataBuffer::Write(buf) => self.write_buffer(buf)?,
-                }
-            } else {
-                return Err(SdError::InvalidArgument);
+        if err.command.is_none() {
+            err.command = Some((cmd.opcode, cmd.arg));
+
+            // Without a response the register still holds the previous one
+            let responded = !matches!(
+                err.cause,
+                SdError::Timeout | SdError::Crc | SdError::EndBit | SdError::Index
+            );
+            if responded && cmd.has_r1_response() {
+                err.card_status = Some(CardStatus::new(err.response[0]));
             }
         }
 
-        // Clear all interrupt statuses
-        self.write_reg16(EMMC_NORMAL_INT_STAT, 0xFFFF);
-        self.write_reg16(EMMC_ERROR_INT_STAT, 0xFFFF);
+        SdError::Command(err)
+    }
+
+    // Time allowed for the whole data phase of `cmd`
+    pub(crate) fn cmd_data_timeout_us(&self, cmd: &EMmcCommand) -> u64 {
+        self.data_timeout_us(!cmd.data_dir_read, cmd.block_count as u64)
+    }
+
+    // Time an R1b command may keep DAT0 busy
+    pub(crate) fn cmd_busy_timeout_us(&self, cmd: &EMmcCommand) -> u64 {
+        if cmd.busy_timeout_us != 0 {
+            cmd.busy_timeout_us
+        } else {
+            self.card_timeouts().cmd6_us
+        }
+    }
+
+    // Leave the controller ready for the next command
+    pub(crate) fn cmd_finish(&self) -> Result<(), SdError> {
+        // Clear the command, data and error statuses, see `cmd_start`
+        self.write_reg(EMMC_NORMAL_INT_STAT, IRQ_CMD_DATA_MASK);
 
         self.reset(EMMC_RESET_CMD)?;
         self.reset(EMMC_RESET_DATA)?;
@@ -366,16 +541,9 @@ impl EMmcHost {
         self.write_reg8(EMMC_SOFTWARE_RESET, EMMC_RESET_CMD);
 
         // Wait for reset to complete
-        let mut timeout = 100;
-        while (self.read_reg8(EMMC_SOFTWARE_RESET) & EMMC_RESET_CMD) != 0 {
-            if timeout == 0 {
-                return Err(SdError::Timeout);
-            }
-            timeout -= 1;
-            delay_us(1000);
-        }
-
-        Ok(())
+        self.poll_until(RESET_TIMEOUT_US, 1000, || {
+            self.read_reg8(EMMC_SOFTWARE_RESET) & EMMC_RESET_CMD == 0
+        })
     }
 
     // Reset data line
@@ -383,16 +551,9 @@ impl EMmcHost {
         self.write_reg8(EMMC_SOFTWARE_RESET, EMMC_RESET_DATA);
 
         // Wait for reset to complete
-        let mut timeout = 100;
-        while (self.read_reg8(EMMC_SOFTWARE_RESET) & EMMC_RESET_DATA) != 0 {
-            if timeout == 0 {
-                return Err(SdError::Timeout);
-            }
-            timeout -= 1;
-            delay_us(1000);
-        }
-
-        Ok(())
+        self.poll_until(RESET_TIMEOUT_US, 1000, || {
+            self.read_reg8(EMMC_SOFTWARE_RESET) & EMMC_RESET_DATA == 0
+        })
     }
 
     // Get response from the last command
@@ -411,19 +572,35 @@ impl EMmcHost {
         let cmd = EMmcCommand::new(MMC_GO_IDLE_STATE, 0, MMC_RSP_NONE);
         self.send_command(&cmd, None)?;
 
-        delay_us(10000);
+        self.delay_us(10000)?;
 
         info!("eMMC reset complete");
         Ok(())
     }
 
+    // CMD8 (SEND_IF_COND) for 2.7-3.6 V, answered with the check pattern by
+    // SD cards of version 2.00 and later
+    pub(crate) fn sd_send_if_cond(&self) -> Result<(), SdError> {
+        const CHECK_PATTERN: u32 = 0xAA;
+
+        let cmd = EMmcCommand::new(SD_SEND_IF_COND, 1 << 8 | CHECK_PATTERN, MMC_RSP_R7);
+        self.send_command(&cmd, None)?;
+
+        let resp = self.get_response().as_r1();
+        debug!("CMD8 response {:#x}", resp);
+        if resp & 0xFF != CHECK_PATTERN {
+            return Err(SdError::UnsupportedCard);
+        }
+        Ok(())
+    }
+
     // Send CMD1 to set OCR and check if card is ready
-    pub fn mmc_send_op_cond(&mut self, ocr: u32, mut retry: u32) -> Result<u32, SdError> {
+    pub fn mmc_send_op_cond(&mut self, ocr: u32) -> Result<u32, SdError> {
         // First command to get capabilities
 
         let mut cmd = EMmcCommand::new(MMC_SEND_OP_COND, ocr, MMC_RSP_R3);
         self.send_command(&cmd, None)?;
-        delay_us(10000);
+        self.delay_us(10000)?;
 
         // Get response and store it
         let mut card_ocr = self.get_response().as_r3();
@@ -444,7 +621,8 @@ impl EMmcHost {
 
         // Now retry with the proper argument until ready or timeout
         let mut ready = false;
-        while retry > 0 && !ready {
+        let mut deadline = self.deadline(OP_COND_TIMEOUT_US);
+        while !ready {
             cmd = EMmcCommand::new(MMC_SEND_OP_COND, cmd_arg, MMC_RSP_R3);
             self.send_command(&cmd, None)?;
             let resp = self.get_response().as_r3();
@@ -455,7 +633,7 @@ impl EMmcHost {
 
             // Update card OCR
             {
-                let card = self.card.as_mut().unwrap();
+                let card = self.card.as_mut().ok_or(SdError::NoCard)?;
                 card.ocr = resp;
 
                 // Check if card is ready (OCR_BUSY flag set)
@@ -469,9 +647,11 @@ impl EMmcHost {
             }
 
             if !ready {
-                retry -= 1;
+                if deadline.expired() {
+                    break;
+                }
                 // Delay between retries
-                delay_us(1000);
+                self.wait_step(&mut deadline, 1000)?;
             }
         }
 
@@ -481,7 +661,7 @@ impl EMmcHost {
             return Err(SdError::UnsupportedCard);
         }
 
-        delay_us(1000);
+        self.delay_us(1000)?;
 
         debug!(
             "Clock control before CMD2: 0x{:x}, stable: {}",
@@ -499,7 +679,7 @@ impl EMmcHost {
         let response = self.get_response();
 
         // Now borrow card as mutable to update it
-        let card = self.card.as_mut().unwrap();
+        let card = self.card.as_mut().ok_or(SdError::NoCard)?;
         card.cid = response.as_r2();
 
         Ok(card.cid)
@@ -508,7 +688,7 @@ impl EMmcHost {
     // Send CMD3 to set RCA for eMMC
     pub fn mmc_set_relative_addr(&self) -> Result<(), SdError> {
         // Get the RCA value before borrowing the card
-        let rca = self.card.as_ref().unwrap().rca;
+        let rca = self.card.as_ref().ok_or(SdError::NoCard)?.rca;
 
         let cmd = EMmcCommand::new(MMC_SET_RELATIVE_ADDR, rca << 16, MMC_RSP_R1);
         self.send_command(&cmd, None)?;
@@ -521,14 +701,14 @@ impl EMmcHost {
     // Send CMD9 to get CSD
     pub fn mmc_send_csd(&mut self) -> Result<[u32; 4], SdError> {
         // Get the RCA value before borrowing the card
-        let rca = self.card.as_ref().unwrap().rca;
+        let rca = self.card.as_ref().ok_or(SdError::NoCard)?.rca;
 
         let cmd = EMmcCommand::new(MMC_SEND_CSD, rca << 16, MMC_RSP_R2);
         self.send_command(&cmd, None)?;
         let response = self.get_response();
 
         // Now borrow card as mutable to update it
-        let card = self.card.as_mut().unwrap();
+        let card = self.card.as_mut().ok_or(SdError::NoCard)?;
         card.csd = response.as_r2();
 
         Ok(card.csd)
@@ -566,26 +746,45 @@ impl EMmcHost {
         Ok(())
     }
 
-    pub fn mmc_poll_for_busy(&self, send_status: bool) -> Result<(), SdError> {
+    // Read EXT_CSD into a plain byte array regardless of the transfer mode
+    pub fn mmc_read_ext_csd(&mut self) -> Result<[u8; 512], SdError> {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "dma")] {
+                let mut ext_csd: DVec<u8> = DVec::zeros(MMC_MAX_BLOCK_LEN as usize, 0x1000, Direction::FromDevice)
+                    .ok_or(SdError::MemoryError)?;
+                self.mmc_send_ext_csd(&mut ext_csd)?;
+
+                let mut buf = [0u8; 512];
+                buf.copy_from_slice(&ext_csd.to_vec());
+                Ok(buf)
+            } else if #[cfg(feature = "pio")] {
+                let mut ext_csd: [u8; 512] = [0; 512];
+                self.mmc_send_ext_csd(&mut ext_csd)?;
+                Ok(ext_csd)
+            }
+        }
+    }
+
+    /// Wait up to `timeout_us` for the card to leave the busy state of an R1b
+    /// command, polling CMD13 or DAT0
+    pub fn mmc_poll_for_busy(&self, send_status: bool, timeout_us: u64) -> Result<(), SdError> {
         let mut busy = true;
-        let mut timeout = 1000;
+        let mut deadline = self.deadline(timeout_us);
 
         // 轮询等待卡忙状态结束
         while busy {
             if send_status {
                 let cmd = EMmcCommand::new(
                     MMC_SEND_STATUS,
-                    self.card.as_ref().unwrap().rca << 16,
+                    self.card.as_ref().ok_or(SdError::NoCard)?.rca << 16,
                     MMC_RSP_R1,
                 );
+                // SWITCH_ERROR and other status errors fail the command itself
                 self.send_command(&cmd, None)?;
-                let response = self.get_response().as_r1();
-                trace!("cmd_d {:#x}", response);
+                let status = CardStatus::new(self.get_response().as_r1());
+                trace!("cmd_d {:?}", status);
 
-                if response & MMC_STATUS_SWITCH_ERROR != 0 {
-                    return Err(SdError::BadMessage);
-                }
-                busy = (response & MMC_STATUS_CURR_STATE) == MMC_STATE_PRG;
+                busy = status.state() == CardState::Prg;
                 if !busy {
                     break;
                 }
@@ -593,12 +792,11 @@ impl EMmcHost {
                 busy = self.mmc_card_busy();
             }
 
-            if timeout == 0 && busy {
+            if deadline.expired() && busy {
                 return Err(SdError::Timeout);
             }
 
-            timeout -= 1;
-            delay_us(1000);
+            self.wait_step(&mut deadline, 1000)?;
         }
 
         Ok(())
@@ -1,4 +1,4 @@
-use super::constant::RK_RXCLK_NO_INVERTER;
+use super::constant::{RK_DLL_CMD_OUT, RK_RXCLK_NO_INVERTER};
 
 #[derive(Debug, Clone, Copy)]
 pub struct EMmcChipConfig {
@@ -7,10 +7,23 @@ pub struct EMmcChipConfig {
     pub hs400_tx_tap: u8,
     pub hs400_cmd_tap: u8,
     pub hs400_strbin_tap: u8,
-    pub _ddr50_strbin_delay_num: u8,
+    pub ddr50_strbin_delay_num: u8,
 }
 
 impl EMmcChipConfig {
+    // The RK3588 bring-up ran with the RK3568 profile, keep its RX clock
+    // setup and add the HS400 command output tap of the RK3588 DLL
+    pub fn rk3588_config() -> Self {
+        Self {
+            flags: RK_DLL_CMD_OUT | RK_RXCLK_NO_INVERTER,
+            hs200_tx_tap: 16,
+            hs400_tx_tap: 9,
+            hs400_cmd_tap: 8,
+            hs400_strbin_tap: 3,
+            ddr50_strbin_delay_num: 16,
+        }
+    }
+
     pub fn rk3568_config() -> Self {
         Self {
             flags: RK_RXCLK_NO_INVERTER,
@@ -18,7 +31,66 @@ impl EMmcChipConfig {
             hs400_tx_tap: 8,
             hs400_cmd_tap: 8,
             hs400_strbin_tap: 3,
-            _ddr50_strbin_delay_num: 16,
+            ddr50_strbin_delay_num: 16,
         }
     }
+
+    // RK3566 shares the eMMC controller of RK3568
+    pub fn rk3566_config() -> Self {
+        Self::rk3568_config()
+    }
+
+    // RK3399 uses an Arasan SDHCI with its PHY in the GRF, there is no DWCMSHC
+    // DLL to program. Pair it with `StandardSdhci`.
+    pub fn rk3399_config() -> Self {
+        Self {
+            flags: 0,
+            hs200_tx_tap: 0,
+            hs400_tx_tap: 0,
+            hs400_cmd_tap: 0,
+            hs400_strbin_tap: 0,
+            ddr50_strbin_delay_num: 0,
+        }
+    }
+
+    /// Built-in profile matching a device-tree compatible string
+    pub fn from_compatible(compatible: &str) -> Option<Self> {
+        match compatible {
+            "rockchip,rk3588-dwcmshc" => Some(Self::rk3588_config()),
+            "rockchip,rk3568-dwcmshc" => Some(Self::rk3568_config()),
+            "rockchip,rk3566-dwcmshc" => Some(Self::rk3566_config()),
+            "rockchip,rk3399-sdhci-5.1" => Some(Self::rk3399_config()),
+            _ => None,
+        }
+    }
+
+    /// Override tap values with board specific device-tree properties.
+    /// `read_u32` returns the value of a u32 property of the controller node.
+    pub fn apply_dt_overrides<F>(&mut self, read_u32: F)
+    where
+        F: Fn(&str) -> Option<u32>,
+    {
+        let taps = [
+            ("rockchip,txclk-tapnum", &mut self.hs200_tx_tap),
+            ("rockchip,hs400-txclk-tapnum", &mut self.hs400_tx_tap),
+            ("rockchip,hs400-cmdout-tapnum", &mut self.hs400_cmd_tap),
+            ("rockchip,hs400-strbin-tapnum", &mut self.hs400_strbin_tap),
+            (
+                "rockchip,ddr50-strbin-delay-num",
+                &mut self.ddr50_strbin_delay_num,
+            ),
+        ];
+
+        for (name, tap) in taps {
+            if let Some(value) = read_u32(name) {
+                *tap = value as u8;
+            }
+        }
+    }
+}
+
+impl Default for EMmcChipConfig {
+    fn default() -> Self {
+        Self::rk3568_config()
+    }
 }
@@ -41,6 +41,7 @@ pub const EMMC_PRESET_SDR25: u32 = 0x0068; // Preset Value for SDR25
 pub const EMMC_PRESET_SDR50: u32 = 0x006A; // Preset Value for SDR50
 pub const EMMC_PRESET_SDR104: u32 = 0x006C; // Preset Value for SDR104
 pub const EMMC_PRESET_DDR50: u32 = 0x006E; // Preset Value for DDR50
+pub const EMMC_PRESET_HS400: u32 = 0x0074; // Preset Value for HS400
 pub const EMMC_ADMA_ID: u32 = 0x0078; // ADMA3 Integrated Descriptor Address Register
 pub const EMMC_SLOT_INTR_STATUS: u32 = 0x00FC; // Slot Interrupt Status Register
 pub const EMMC_HOST_CNTRL_VER: u32 = 0x00FE; // Host Controller Version
@@ -54,6 +55,7 @@ pub const EMMC_CQISE: u32 = 0x0194; // Command Queuing Interrupt Status Enable R
 pub const EMMC_CQISGE: u32 = 0x0198; // Command Queuing Interrupt Signal Enable Register
 pub const EMMC_CQIC: u32 = 0x019C; // Command Queuing Interrupt Coalescing Register
 pub const EMMC_CQTDLBA: u32 = 0x01A0; // Command Queuing Task Descriptor List Base Address Register
+pub const EMMC_CQTDLBAU: u32 = 0x01A4; // Command Queuing Task Descriptor List Base Address Upper 32 Bits Register
 pub const EMMC_CQTDBR: u32 = 0x01A8; // Command Queuing Doorbell Register
 pub const EMMC_CQTDBN: u32 = 0x01AC; // Command Queuing Task Clear Notification Register
 pub const EMMC_CQDOS: u32 = 0x01B0; // Command Queuing Device Queue Status Register
@@ -67,6 +69,46 @@ pub const EMMC_CQTERRI: u32 = 0x01D4; // Command Queuing Task Error Information
 pub const EMMC_CQCRI: u32 = 0x01D8; // Command Queuing Command Response Index Register
 pub const EMMC_CQCRA: u32 = 0x01DC; // Command Queuing Command Response Argument Register
 
+// CQHCI configuration and control flags
+pub const CQHCI_ENABLE: u32 = 1 << 0;
+pub const CQHCI_HALT: u32 = 1 << 0;
+pub const CQHCI_CLEAR_ALL_TASKS: u32 = 1 << 8;
+
+// CQHCI interrupt status flags
+pub const CQHCI_IS_HAC: u32 = 1 << 0; // Halt complete
+pub const CQHCI_IS_TCC: u32 = 1 << 1; // Task complete
+pub const CQHCI_IS_RED: u32 = 1 << 2; // Response error detected
+pub const CQHCI_IS_TCL: u32 = 1 << 3; // Task cleared
+pub const CQHCI_IS_GCE: u32 = 1 << 4; // General crypto error
+pub const CQHCI_IS_ICCE: u32 = 1 << 5; // Invalid crypto configuration error
+pub const CQHCI_IS_MASK: u32 = CQHCI_IS_HAC | CQHCI_IS_TCC | CQHCI_IS_RED | CQHCI_IS_TCL;
+pub const CQHCI_IS_ERROR: u32 = CQHCI_IS_RED | CQHCI_IS_GCE | CQHCI_IS_ICCE;
+
+// CQHCI task error information
+pub const CQHCI_TERRI_RESP_TASK_SHIFT: u32 = 8;
+pub const CQHCI_TERRI_RESP_VALID: u32 = 1 << 15;
+pub const CQHCI_TERRI_DATA_TASK_SHIFT: u32 = 24;
+pub const CQHCI_TERRI_DATA_VALID: u32 = 1 << 31;
+pub const CQHCI_TERRI_TASK_MASK: u32 = 0x1F;
+
+// CQHCI task and transfer descriptor fields
+pub const CQHCI_DESC_VALID: u64 = 1 << 0;
+pub const CQHCI_DESC_END: u64 = 1 << 1;
+pub const CQHCI_DESC_INT: u64 = 1 << 2;
+pub const CQHCI_DESC_ACT_TRAN: u64 = 0x4 << 3;
+pub const CQHCI_DESC_ACT_TASK: u64 = 0x5 << 3;
+pub const CQHCI_DESC_DATA_DIR: u64 = 1 << 12; // Read from the card
+pub const CQHCI_DESC_PRIORITY: u64 = 1 << 13;
+pub const CQHCI_DESC_REL_WRITE: u64 = 1 << 15;
+pub const CQHCI_DESC_BLK_COUNT_SHIFT: u64 = 16;
+pub const CQHCI_DESC_BLK_ADDR_SHIFT: u64 = 32;
+pub const CQHCI_DESC_LEN_SHIFT: u64 = 16;
+pub const CQHCI_DESC_ADDR_SHIFT: u64 = 32;
+
+// CMD48 task management op codes
+pub const CQHCI_TM_DISCARD_QUEUE: u32 = 1;
+pub const CQHCI_TM_DISCARD_TASK: u32 = 2;
+
 pub const EMMC_VER_ID: u32 = 0x0500; // Host Version ID Register
 pub const EMMC_VER_TYPE: u32 = 0x0504; // Host Version Type Register
 pub const EMMC_HOST_CTRL3: u32 = 0x0508; // Host Control 3 Register
@@ -110,6 +152,7 @@ pub const EMMC_TRNS_MULTI: u16 = 0x20;
 // EMMC present state flags
 pub const EMMC_CMD_INHIBIT: u32 = 0x00000001;
 pub const EMMC_DATA_INHIBIT: u32 = 0x00000002;
+pub const EMMC_RETUNE_REQUEST: u32 = 0x00000008;
 pub const EMMC_CARD_INSERTED: u32 = 0x00010000;
 pub const EMMC_CARD_STABLE: u32 = 0x00020000;
 pub const EMMC_WRITE_PROTECT: u32 = 0x00080000;
@@ -146,6 +189,8 @@ pub const EMMC_INT_DATA_AVAIL: u32 = 0x00000020;
 pub const EMMC_INT_CARD_INSERT: u32 = 0x00000040;
 pub const EMMC_INT_CARD_REMOVE: u32 = 0x00000080;
 pub const EMMC_INT_CARD_INT: u32 = 0x00000100;
+pub const EMMC_INT_RETUNE: u32 = 0x00001000;
+pub const EMMC_INT_CQE: u32 = 0x00004000;
 pub const EMMC_INT_ERROR: u32 = 0x00008000;
 pub const EMMC_INT_TIMEOUT: u32 = 0x00010000;
 pub const EMMC_INT_CRC: u32 = 0x00020000;
@@ -235,6 +280,14 @@ pub const MMC_EARSE_GROUP_START: u8 = 35;
 pub const MMC_EARSE_GROUP_END: u8 = 36;
 pub const MMC_ERASE: u8 = 38;
 
+// CMD38 arguments
+pub const MMC_ERASE_ARG: u32 = 0x00000000;
+pub const MMC_TRIM_ARG: u32 = 0x00000001;
+pub const MMC_DISCARD_ARG: u32 = 0x00000003;
+
+// CMD23 argument flags
+pub const MMC_CMD23_ARG_REL_WR: u32 = 1 << 31;
+
 // Table 55 — I/O mode commands (class 9)
 pub const MMC_FAST_IO: u8 = 39;
 pub const MMC_GO_IRQ_STATE: u8 = 40;
@@ -257,6 +310,34 @@ pub const MMC_EXECUTE_READ_TASK: u8 = 46;
 pub const MMC_EXECUTE_WRITE_TASK: u8 = 47;
 pub const MMC_CMDQ_TASK_MGMT: u8 = 48;
 
+// SD commands
+pub const SD_SEND_IF_COND: u8 = 8;
+
+// SDIO commands
+pub const SD_IO_SEND_OP_COND: u8 = 5;
+pub const SD_IO_RW_DIRECT: u8 = 52;
+pub const SD_IO_RW_EXTENDED: u8 = 53;
+
+// SDIO CCCR registers
+pub const SDIO_CCCR_IOEX: u32 = 0x02; // I/O Enable
+pub const SDIO_CCCR_IORX: u32 = 0x03; // I/O Ready
+pub const SDIO_CCCR_IENX: u32 = 0x04; // Function/Master Interrupt Enable
+pub const SDIO_CCCR_INTX: u32 = 0x05; // Function Interrupt Pending
+pub const SDIO_CCCR_ABORT: u32 = 0x06; // I/O Abort
+
+pub const SDIO_CCCR_IEN_MASTER: u8 = 1 << 0;
+pub const SDIO_CCCR_ABORT_RES: u8 = 1 << 3; // I/O card reset
+pub const SDIO_MAX_FUNCS: usize = 7;
+
+// SDIO R5 response flags
+pub const R5_COM_CRC_ERROR: u32 = 1 << 15;
+pub const R5_ILLEGAL_COMMAND: u32 = 1 << 14;
+pub const R5_ERROR: u32 = 1 << 11;
+pub const R5_FUNCTION_NUMBER: u32 = 1 << 9;
+pub const R5_OUT_OF_RANGE: u32 = 1 << 8;
+pub const R5_ERROR_MASK: u32 =
+    R5_COM_CRC_ERROR | R5_ILLEGAL_COMMAND | R5_ERROR | R5_FUNCTION_NUMBER | R5_OUT_OF_RANGE;
+
 // Response types
 pub const MMC_RSP_PRESENT: u32 = 1 << 0;
 pub const MMC_RSP_136: u32 = 1 << 1; // 136-bit response
@@ -308,6 +389,7 @@ pub const DWCMSHC_EMMC_DLL_INC_VALUE: u32 = 2;
 pub const DWCMSHC_EMMC_DLL_INC: u32 = 8;
 pub const DWCMSHC_EMMC_DLL_BYPASS: u32 = 1 << 24;
 pub const DWCMSHC_EMMC_DLL_DLYENA: u32 = 1 << 27;
+pub const DLL_TAPNUM_MASK: u32 = 0x1f;
 pub const DLL_TXCLK_TAPNUM_DEFAULT: u32 = 0x10;
 pub const DLL_TXCLK_TAPNUM_90_DEGREES: u32 = 0x9;
 pub const DLL_STRBIN_TAPNUM_DEFAULT: u32 = 0x4;
@@ -320,6 +402,7 @@ pub const DWCMSHC_EMMC_DLL_LOCKED: u32 = 1 << 8;
 pub const DWCMSHC_EMMC_DLL_TIMEOUT: u32 = 1 << 9;
 pub const DLL_TAP_VALUE_SEL: u32 = 1 << 25;
 pub const DLL_TAP_VALUE_OFFSET: u32 = 8;
+pub const DLL_RXCLK_TAPNUM_FROM_SW: u32 = 1 << 24;
 pub const DLL_RXCLK_NO_INVERTER: u32 = 1 << 29;
 pub const DLL_RXCLK_ORI_GATE: u32 = 1 << 31;
 pub const DLL_CMDOUT_TAPNUM_90_DEGREES: u32 = 0x8;
@@ -365,10 +448,16 @@ pub const MMC_CTRL_DRV_TYPE_B: u16 = 0x0000;
 pub const MMC_CTRL_DRV_TYPE_A: u16 = 0x0010;
 pub const MMC_CTRL_DRV_TYPE_C: u16 = 0x0020;
 pub const MMC_CTRL_DRV_TYPE_D: u16 = 0x0030;
+pub const MMC_CTRL_DRV_TYPE_SHIFT: u16 = 4;
 pub const MMC_CTRL_EXEC_TUNING: u16 = 0x0040;
 pub const MMC_CTRL_TUNED_CLK: u16 = 0x0080;
 pub const MMC_CTRL_PRESET_VAL_ENABLE: u16 = 0x8000;
 
+pub const EMMC_PRESET_DRV_MASK: u16 = 0xC000;
+pub const EMMC_PRESET_DRV_SHIFT: u16 = 14;
+pub const EMMC_PRESET_CLKGEN_SEL: u16 = 1 << 10;
+pub const EMMC_PRESET_SDCLK_FREQ_MASK: u16 = 0x03FF;
+
 pub const MMC_HIGH_26_MAX_DTR: u32 = 26000000;
 pub const MMC_HIGH_52_MAX_DTR: u32 = 52000000;
 pub const MMC_HIGH_DDR_MAX_DTR: u32 = 52000000;
@@ -382,6 +471,9 @@ pub const EMMC_INT_ERR_CMD_INDEX: u32 = 0x0008;
 pub const EMMC_INT_ERR_DATA_TIMEOUT: u32 = 0x0010;
 pub const EMMC_INT_ERR_DATA_CRC: u32 = 0x0020;
 pub const EMMC_INT_ERR_DATA_END_BIT: u32 = 0x0040;
+pub const EMMC_INT_ERR_CURRENT_LIMIT: u32 = 0x0080;
+pub const EMMC_INT_ERR_AUTO_CMD: u32 = 0x0100;
+pub const EMMC_INT_ERR_ADMA: u32 = 0x0200;
 
 pub const EMMC_SPEC_VER_MASK: u16 = 0x00FF;
 pub const EMMC_SPEC_VER_SHIFT: u32 = 0;
@@ -391,6 +483,11 @@ pub const EMMC_SPEC_300: u16 = 2;
 
 pub const EMMC_CLOCK_MUL_MASK: u32 = 0x00FF0000;
 pub const EMMC_CLOCK_MUL_SHIFT: u32 = 16;
+pub const EMMC_DRIVER_TYPE_A: u32 = 1 << 4;
+pub const EMMC_DRIVER_TYPE_C: u32 = 1 << 5;
+pub const EMMC_DRIVER_TYPE_D: u32 = 1 << 6;
+pub const EMMC_RETUNING_TIMER_COUNT_MASK: u32 = 0x00000F00;
+pub const EMMC_RETUNING_TIMER_COUNT_SHIFT: u32 = 8;
 
 pub const MMC_VDD_165_195_SHIFT: u32 = 7;
 pub const MMC_VDD_165_195: u32 = 0x00000080; /* VDD voltage 1.65 - 1.95 */
@@ -421,6 +518,15 @@ pub const MMC_STATUS_SWITCH_ERROR: u32 = 1 << 7;
 pub const MMC_STATUS_RDY_FOR_DATA: u32 = 1 << 8;
 pub const MMC_STATUS_CURR_STATE: u32 = 0xf << 9;
 pub const MMC_STATUS_ERROR: u32 = 1 << 19;
+pub const MMC_STATUS_LOCK_UNLOCK_FAILED: u32 = 1 << 24;
+pub const MMC_STATUS_CARD_IS_LOCKED: u32 = 1 << 25;
+
+// CMD42 LOCK_UNLOCK data structure flags
+pub const MMC_LOCK_SET_PWD: u8 = 1 << 0;
+pub const MMC_LOCK_CLR_PWD: u8 = 1 << 1;
+pub const MMC_LOCK_LOCK_UNLOCK: u8 = 1 << 2;
+pub const MMC_LOCK_ERASE: u8 = 1 << 3;
+pub const MMC_LOCK_MAX_PWD_LEN: usize = 16;
 
 pub const MMC_STATE_PRG: u32 = 7 << 9;
 
@@ -453,6 +559,11 @@ pub const EXT_CSD_TIMING_HS200: u8 = 2; /* HS200 */
 pub const EXT_CSD_TIMING_HS400: u8 = 3; /* HS400 */
 pub const EXT_CSD_DRV_STR_SHIFT: u8 = 4; /* Driver Strength shift */
 
+pub const EXT_CSD_PWR_CL_8BIT_MASK: u8 = 0xF0; /* 8 bit PWR CLS */
+pub const EXT_CSD_PWR_CL_4BIT_MASK: u8 = 0x0F; /* 4 bit PWR CLS */
+pub const EXT_CSD_PWR_CL_8BIT_SHIFT: u8 = 4;
+pub const EXT_CSD_PWR_CL_4BIT_SHIFT: u8 = 0;
+
 pub const EXT_CSD_BUS_WIDTH_1: u8 = 0; /* Card is in 1 bit mode */
 pub const EXT_CSD_BUS_WIDTH_4: u8 = 1; /* Card is in 4 bit mode */
 pub const EXT_CSD_BUS_WIDTH_8: u8 = 2; /* Card is in 8 bit mode */
@@ -486,6 +597,9 @@ pub const SDHCI_QUIRK_USE_WIDE8: u32 = 1 << 8;
 /*
  * EXT_CSD fields
  */
+pub const EXT_CSD_CMDQ_MODE_EN: u32 = 15; /* R/W */
+pub const EXT_CSD_FLUSH_CACHE: u32 = 32; /* W */
+pub const EXT_CSD_CACHE_CTRL: u32 = 33; /* R/W */
 pub const EXT_CSD_ENH_START_ADDR: u32 = 136; /* R/W */
 pub const EXT_CSD_ENH_SIZE_MULT: u32 = 140; /* R/W */
 pub const EXT_CSD_GP_SIZE_MULT: u32 = 143; /* R/W */
@@ -498,29 +612,68 @@ pub const EXT_CSD_BKOPS_EN: u32 = 163; /* R/W & R/W/E */
 pub const EXT_CSD_WR_REL_PARAM: u32 = 166; /* R */
 pub const EXT_CSD_WR_REL_SET: u32 = 167; /* R/W */
 pub const EXT_CSD_RPMB_MULT: u32 = 168; /* RO */
+pub const EXT_CSD_USER_WP: u32 = 171; /* R/W */
+pub const EXT_CSD_BOOT_WP: u32 = 173; /* R/W */
+pub const EXT_CSD_BOOT_WP_STATUS: u32 = 174; /* RO */
 pub const EXT_CSD_ERASE_GROUP_DEF: u32 = 175; /* R/W */
 pub const EXT_CSD_BOOT_BUS_WIDTH: u32 = 177;
 pub const EXT_CSD_PART_CONF: u32 = 179; /* R/W */
 pub const EXT_CSD_BUS_WIDTH: u32 = 183; /* R/W */
 pub const EXT_CSD_STROBE_SUPPORT: u32 = 184; /* RO */
 pub const EXT_CSD_HS_TIMING: u32 = 185; /* R/W */
+pub const EXT_CSD_POWER_CLASS: u32 = 187; /* R/W */
 pub const EXT_CSD_REV: u32 = 192; /* RO */
 pub const EXT_CSD_CARD_TYPE: u32 = 196; /* RO */
 pub const EXT_CSD_DRIVER_STRENGTH: u32 = 197; /* RO */
+pub const EXT_CSD_PART_SWITCH_TIME: u32 = 199; /* RO */
+pub const EXT_CSD_PWR_CL_52_195: u32 = 200; /* RO */
+pub const EXT_CSD_PWR_CL_26_195: u32 = 201; /* RO */
+pub const EXT_CSD_PWR_CL_52_360: u32 = 202; /* RO */
+pub const EXT_CSD_PWR_CL_26_360: u32 = 203; /* RO */
 pub const EXT_CSD_SEC_CNT: u32 = 212; /* RO, 4 bytes */
+pub const EXT_CSD_SLEEP_NOTIFICATION_TIME: u32 = 216; /* RO */
 pub const EXT_CSD_HC_WP_GRP_SIZE: u32 = 221; /* RO */
+pub const EXT_CSD_REL_WR_SEC_C: u32 = 222; /* RO */
+pub const EXT_CSD_ERASE_TIMEOUT_MULT: u32 = 223; /* RO */
 pub const EXT_CSD_HC_ERASE_GRP_SIZE: u32 = 224; /* RO */
 pub const EXT_CSD_BOOT_MULT: u32 = 226; /* RO */
 pub const EXT_CSD_SEC_FEATURE_SUPPORT: u32 = 231; /* RO */
+pub const EXT_CSD_TRIM_MULT: u32 = 232; /* RO */
+pub const EXT_CSD_PWR_CL_200_195: u32 = 236; /* RO */
+pub const EXT_CSD_PWR_CL_200_360: u32 = 237; /* RO */
+pub const EXT_CSD_PWR_CL_DDR_52_195: u32 = 238; /* RO */
+pub const EXT_CSD_PWR_CL_DDR_52_360: u32 = 239; /* RO */
+pub const EXT_CSD_GENERIC_CMD6_TIME: u32 = 248; /* RO */
+pub const EXT_CSD_PWR_CL_DDR_200_360: u32 = 253; /* RO */
+pub const EXT_CSD_CMDQ_DEPTH: u32 = 307; /* RO */
+pub const EXT_CSD_CMDQ_SUPPORT: u32 = 308; /* RO */
 pub const EXT_CSD_BKOPS_SUPPORT: u32 = 502; /* RO */
 
 pub const EXT_CSD_PARTITION_SETTING_COMPLETED: u32 = 1 << 0;
 
+pub const EXT_CSD_US_PWR_WP_EN: u8 = 1 << 0;
+pub const EXT_CSD_US_PERM_WP_EN: u8 = 1 << 2;
+pub const EXT_CSD_US_PWR_WP_DIS: u8 = 1 << 3;
+pub const EXT_CSD_US_PERM_WP_DIS: u8 = 1 << 4;
+pub const EXT_CSD_CD_PERM_WP_DIS: u8 = 1 << 6;
+pub const EXT_CSD_PERM_PSWD_DIS: u8 = 1 << 7;
+
+pub const EXT_CSD_BOOT_WP_B_PWR_WP_EN: u8 = 1 << 0;
+pub const EXT_CSD_BOOT_WP_B_PWR_WP_SEC_SEL: u8 = 1 << 1;
+pub const EXT_CSD_BOOT_WP_B_PERM_WP_EN: u8 = 1 << 2;
+pub const EXT_CSD_BOOT_WP_B_PERM_WP_SEC_SEL: u8 = 1 << 3;
+pub const EXT_CSD_BOOT_WP_B_PERM_WP_DIS: u8 = 1 << 4;
+pub const EXT_CSD_BOOT_WP_B_PWR_WP_DIS: u8 = 1 << 6;
+pub const EXT_CSD_BOOT_WP_B_SEC_WP_SEL: u8 = 1 << 7;
+
 pub const EXT_CSD_SEC_ER_EN: u32 = 1 << 0;
 pub const EXT_CSD_SEC_BD_BLK_EN: u32 = 1 << 2;
 pub const EXT_CSD_SEC_GB_CL_EN: u32 = 1 << 4;
 pub const EXT_CSD_SEC_SANITIZE: u32 = 1 << 6;
 
+// WR_REL_PARAM
+pub const EXT_CSD_WR_REL_PARAM_EN: u32 = 1 << 2;
+
 pub const MMC_MODE_HS: u32 = 1 << 0;
 pub const MMC_MODE_HS_52MHZ: u32 = 1 << 1;
 pub const MMC_MODE_4BIT: u32 = 1 << 2;
@@ -0,0 +1,538 @@
+// ===== Command Queue Engine (CQHCI) =====
+
+use log::{debug, info, warn};
+
+use crate::err::SdError;
+
+use super::{EMmcHost, cmd::EMmcCommand, constant::*, platform::DmaRegion};
+
+// Hardware limit of the task descriptor list
+const CQHCI_MAX_SLOTS: usize = 32;
+
+// 64-bit task descriptor followed by one 32-bit ADMA2 transfer descriptor
+const CQHCI_SLOT_SIZE: usize = 16;
+
+// A single transfer descriptor moves at most 64 KiB
+pub const CQHCI_MAX_TASK_BLOCKS: u16 = 128;
+
+/// Read or write queued with `EMmcHost::cqe_submit`. `data` must be DMA
+/// memory below 4 GiB holding at least `blocks` blocks, and stays in use by
+/// the controller until the task is reported back.
+#[derive(Debug, Clone, Copy)]
+pub struct CqeTask {
+    pub read: bool,
+    pub block_id: u32,
+    pub blocks: u16,
+    pub data: DmaRegion,
+    pub reliable_write: bool,
+    pub priority: bool,
+}
+
+impl CqeTask {
+    pub fn read(block_id: u32, blocks: u16, data: DmaRegion) -> Self {
+        Self {
+            read: true,
+            block_id,
+            blocks,
+            data,
+            reliable_write: false,
+            priority: false,
+        }
+    }
+
+    pub fn write(block_id: u32, blocks: u16, data: DmaRegion) -> Self {
+        Self {
+            read: false,
+            ..Self::read(block_id, blocks, data)
+        }
+    }
+}
+
+/// Tags finished since the last call, bit N standing for tag N
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CqeEvents {
+    pub completed: u32,
+    pub failed: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CqeSlot {
+    read: bool,
+    data: DmaRegion,
+    len: usize,
+}
+
+// Engine state while the card is in command queue mode
+#[derive(Debug)]
+pub(crate) struct CqeState {
+    tdl: DmaRegion,
+    depth: usize,
+    halted: bool,
+    slots: [Option<CqeSlot>; CQHCI_MAX_SLOTS],
+    // Finished in hardware, not yet handed to the caller
+    events: CqeEvents,
+}
+
+impl CqeState {
+    pub(crate) fn running(&self) -> bool {
+        !self.halted
+    }
+}
+
+impl EMmcHost {
+    /// Switch the card to command queue mode (CMDQ_MODE_EN) and start the
+    /// engine. Needs a `Platform` for the task descriptor list.
+    pub fn cqe_enable(&mut self) -> Result<(), SdError> {
+        if self.cqe.is_some() {
+            return Ok(());
+        }
+
+        let card = self.card.as_ref().ok_or(SdError::NoCard)?;
+        // Task descriptors carry block addresses only
+        if card.state & MMC_STATE_HIGHCAPACITY == 0 {
+            return Err(SdError::UnsupportedCard);
+        }
+        let rca = card.rca;
+
+        if self.read_reg(EMMC_COVER) == 0 {
+            return Err(SdError::NotSupported);
+        }
+        let platform = self.platform.ok_or(SdError::NotSupported)?;
+
+        let ext_csd = self.mmc_read_ext_csd()?;
+        if ext_csd[EXT_CSD_CMDQ_SUPPORT as usize] & 0x1 == 0 {
+            return Err(SdError::UnsupportedCard);
+        }
+        let depth =
+            ((ext_csd[EXT_CSD_CMDQ_DEPTH as usize] & 0x1F) as usize + 1).min(CQHCI_MAX_SLOTS);
+
+        let tdl_size = CQHCI_MAX_SLOTS * CQHCI_SLOT_SIZE;
+        let tdl = platform
+            .dma_alloc(tdl_size, 1024)
+            .ok_or(SdError::MemoryError)?;
+        if tdl.bus_addr + tdl_size as u64 > u32::MAX as u64 {
+            platform.dma_free(tdl);
+            return Err(SdError::MemoryError);
+        }
+        unsafe { core::ptr::write_bytes(tdl.cpu_addr.as_ptr(), 0, tdl_size) };
+        platform.dma_clean(tdl.cpu_addr, tdl_size);
+
+        if let Err(e) = self.mmc_switch(EXT_CSD_CMD_SET_NORMAL, EXT_CSD_CMDQ_MODE_EN, 1, true) {
+            platform.dma_free(tdl);
+            return Err(e);
+        }
+
+        // The engine moves data through ADMA2 transfer descriptors
+        let ctrl = self.read_reg8(EMMC_HOST_CTRL1);
+        self.write_reg8(
+            EMMC_HOST_CTRL1,
+            (ctrl & !EMMC_CTRL_DMA_MASK) | EMMC_CTRL_ADMA32,
+        );
+        self.write_reg16(EMMC_BLOCK_SIZE, 512);
+        let timeout_us = self.data_timeout_us(true, 1);
+        self.write_reg8(EMMC_TIMEOUT_CONTROL, self.timeout_control(timeout_us));
+
+        let int_en = self.read_reg16(EMMC_NORMAL_INT_STAT_EN);
+        self.write_reg16(EMMC_NORMAL_INT_STAT_EN, int_en | EMMC_INT_CQE as u16);
+
+        self.write_reg(EMMC_CQCFG, 0);
+        self.write_reg(EMMC_CQTDLBA, tdl.bus_addr as u32);
+        self.write_reg(EMMC_CQTDLBAU, 0);
+        // RCA used by the engine for its CMD13 status polling
+        self.write_reg(EMMC_QSSC2, rca);
+        self.write_reg(EMMC_CQIS, EMMC_INT_ALL_MASK);
+        self.write_reg(EMMC_CQISE, CQHCI_IS_MASK | CQHCI_IS_ERROR);
+        self.write_reg(EMMC_CQCFG, CQHCI_ENABLE);
+        self.write_reg(EMMC_CQCTRL, 0);
+
+        self.cqe = Some(CqeState {
+            tdl,
+            depth,
+            halted: false,
+            slots: [None; CQHCI_MAX_SLOTS],
+            events: CqeEvents::default(),
+        });
+
+        info!("Command queue enabled, depth {}", depth);
+        Ok(())
+    }
+
+    /// Stop the engine and take the card out of command queue mode.
+    /// All tasks must have been completed.
+    pub fn cqe_disable(&mut self) -> Result<(), SdError> {
+        let Some(cqe) = self.cqe.as_ref() else {
+            return Ok(());
+        };
+        if cqe.slots.iter().any(Option::is_some) {
+            return Err(SdError::QueueActive);
+        }
+
+        self.cqe_teardown();
+        let ret = self.mmc_switch(EXT_CSD_CMD_SET_NORMAL, EXT_CSD_CMDQ_MODE_EN, 0, true);
+
+        info!("Command queue disabled");
+        ret
+    }
+
+    /// Drop the engine without talking to the card, e.g. before the card is
+    /// re-initialized, which takes it out of command queue mode anyway.
+    /// Queued tasks are cleared and never reported.
+    pub(crate) fn cqe_abort(&mut self) {
+        let Some(cqe) = self.cqe.as_ref() else {
+            return;
+        };
+
+        let busy = cqe_busy(cqe);
+        if busy != 0 {
+            if self.cqe_halt().is_ok() {
+                let ctrl = self.read_reg(EMMC_CQCTRL);
+                self.write_reg(EMMC_CQCTRL, ctrl | CQHCI_CLEAR_ALL_TASKS);
+                let _ = self.cqe_wait_cleared(EMMC_CQCTRL, CQHCI_CLEAR_ALL_TASKS);
+            }
+            warn!("CQE dropped tasks {:#x}", busy);
+        }
+
+        self.cqe_teardown();
+    }
+
+    // Stop the engine, give the SDHCI its DMA mode back and free the task
+    // descriptor list
+    fn cqe_teardown(&mut self) {
+        let Some(cqe) = self.cqe.take() else {
+            return;
+        };
+
+        self.write_reg(EMMC_CQCFG, 0);
+
+        let ctrl = self.read_reg8(EMMC_HOST_CTRL1);
+        self.write_reg8(
+            EMMC_HOST_CTRL1,
+            (ctrl & !EMMC_CTRL_DMA_MASK) | EMMC_CTRL_SDMA,
+        );
+
+        if let Some(platform) = self.platform {
+            platform.dma_free(cqe.tdl);
+        }
+    }
+
+    pub fn cqe_enabled(&self) -> bool {
+        self.cqe.is_some()
+    }
+
+    /// Number of tasks the card accepts at once
+    pub fn cqe_depth(&self) -> Option<usize> {
+        self.cqe.as_ref().map(|cqe| cqe.depth)
+    }
+
+    /// Queue `task` and ring its doorbell. Returns the task tag.
+    ///
+    /// # Safety
+    ///
+    /// The controller reads or writes `task.data` behind the back of the
+    /// borrow checker. The region must stay allocated, and must not be
+    /// accessed by the CPU, until the returned tag has been reported as
+    /// completed or failed by `cqe_complete()` or `cqe_wait()`, or the queue
+    /// has been dropped by `recovery_reinit()`. Dropping the host with the
+    /// task still queued leaves the region in use by the controller.
+    pub unsafe fn cqe_submit(&mut self, task: &CqeTask) -> Result<u8, SdError> {
+        let len = task.blocks as usize * 512;
+        if task.blocks == 0 || task.blocks > CQHCI_MAX_TASK_BLOCKS || task.data.size < len {
+            return Err(SdError::InvalidArgument);
+        }
+        if task.data.bus_addr + len as u64 > u32::MAX as u64 {
+            return Err(SdError::InvalidArgument);
+        }
+
+        let card = self.card.as_ref().ok_or(SdError::NoCard)?;
+        if card.locked {
+            return Err(SdError::CardLocked);
+        }
+        if !task.read && self.is_write_protected() {
+            return Err(SdError::WriteProtected);
+        }
+        if task.reliable_write
+            && self.reliable_write_blocks(task.block_id, task.blocks)? != task.blocks
+        {
+            return Err(SdError::InvalidArgument);
+        }
+
+        self.cqe_retune_if_idle()?;
+
+        let platform = self.platform.ok_or(SdError::NotSupported)?;
+        let cqe = self.cqe.as_mut().ok_or(SdError::NotSupported)?;
+
+        let tag = cqe.slots[..cqe.depth]
+            .iter()
+            .position(Option::is_none)
+            .ok_or(SdError::QueueFull)?;
+
+        if !task.read {
+            platform.dma_clean(task.data.cpu_addr, len);
+        }
+
+        let mut task_desc = CQHCI_DESC_VALID
+            | CQHCI_DESC_END
+            | CQHCI_DESC_INT
+            | CQHCI_DESC_ACT_TASK
+            | (task.blocks as u64) << CQHCI_DESC_BLK_COUNT_SHIFT
+            | (task.block_id as u64) << CQHCI_DESC_BLK_ADDR_SHIFT;
+        if task.read {
+            task_desc |= CQHCI_DESC_DATA_DIR;
+        }
+        if task.priority {
+            task_desc |= CQHCI_DESC_PRIORITY;
+        }
+        if task.reliable_write {
+            task_desc |= CQHCI_DESC_REL_WRITE;
+        }
+
+        // A length of 0 stands for 64 KiB
+        let tran_desc = CQHCI_DESC_VALID
+            | CQHCI_DESC_END
+            | CQHCI_DESC_ACT_TRAN
+            | ((len & 0xFFFF) as u64) << CQHCI_DESC_LEN_SHIFT
+            | task.data.bus_addr << CQHCI_DESC_ADDR_SHIFT;
+
+        unsafe {
+            let slot = cqe.tdl.cpu_addr.as_ptr().add(tag * CQHCI_SLOT_SIZE) as *mut u64;
+            core::ptr::write_volatile(slot, task_desc.to_le());
+            core::ptr::write_volatile(slot.add(1), tran_desc.to_le());
+        }
+        platform.dma_clean(
+            unsafe { cqe.tdl.cpu_addr.add(tag * CQHCI_SLOT_SIZE) },
+            CQHCI_SLOT_SIZE,
+        );
+
+        cqe.slots[tag] = Some(CqeSlot {
+            read: task.read,
+            data: task.data,
+            len,
+        });
+
+        self.write_reg(EMMC_CQTDBR, 1 << tag);
+
+        debug!(
+            "CQE task {}: {} {} blocks at {:#x}",
+            tag,
+            if task.read { "read" } else { "write" },
+            task.blocks,
+            task.block_id
+        );
+        Ok(tag as u8)
+    }
+
+    /// Collect finished tasks from CQIS, recovering from task errors on the way.
+    /// Call from the controller interrupt handler or poll it.
+    pub fn cqe_complete(&mut self) -> Result<CqeEvents, SdError> {
+        self.cqe_poll()?;
+
+        let cqe = self.cqe.as_mut().ok_or(SdError::NotSupported)?;
+        let events = core::mem::take(&mut cqe.events);
+        cqe_release(cqe, events.completed | events.failed);
+
+        Ok(events)
+    }
+
+    /// Wait for task `tag` to finish
+    pub fn cqe_wait(&mut self, tag: u8, timeout_us: u64) -> Result<(), SdError> {
+        let bit = 1u32 << tag;
+        let mut deadline = self.deadline(timeout_us);
+
+        loop {
+            self.cqe_poll()?;
+
+            let cqe = self.cqe.as_mut().ok_or(SdError::NotSupported)?;
+            if (cqe.events.completed | cqe.events.failed) & bit != 0 {
+                let failed = cqe.events.failed & bit != 0;
+                cqe.events.completed &= !bit;
+                cqe.events.failed &= !bit;
+                cqe_release(cqe, bit);
+
+                return if failed {
+                    Err(SdError::TransferError)
+                } else {
+                    Ok(())
+                };
+            }
+
+            if deadline.expired() {
+                self.check_card_removed()?;
+                return Err(SdError::Timeout);
+            }
+
+            self.wait_step(&mut deadline, 10)?;
+        }
+    }
+
+    /// Halt the engine so that legacy commands can be sent
+    pub fn cqe_halt(&mut self) -> Result<(), SdError> {
+        let cqe = self.cqe.as_ref().ok_or(SdError::NotSupported)?;
+        if cqe.halted {
+            return Ok(());
+        }
+
+        let ctrl = self.read_reg(EMMC_CQCTRL);
+        self.write_reg(EMMC_CQCTRL, ctrl | CQHCI_HALT);
+
+        self.poll_until(100_000, 1000, || {
+            self.read_reg(EMMC_CQCTRL) & CQHCI_HALT != 0
+        })
+        .inspect_err(|_| warn!("CQE halt timeout"))?;
+        self.write_reg(EMMC_CQIS, CQHCI_IS_HAC);
+
+        if let Some(cqe) = self.cqe.as_mut() {
+            cqe.halted = true;
+        }
+        Ok(())
+    }
+
+    pub fn cqe_resume(&mut self) -> Result<(), SdError> {
+        let cqe = self.cqe.as_mut().ok_or(SdError::NotSupported)?;
+        if !cqe.halted {
+            return Ok(());
+        }
+        cqe.halted = false;
+
+        let ctrl = self.read_reg(EMMC_CQCTRL);
+        self.write_reg(EMMC_CQCTRL, ctrl & !CQHCI_HALT);
+        Ok(())
+    }
+
+    // Re-tune between tasks when it is due. The tuning commands need the
+    // engine halted, so with tasks in flight a later submission does it.
+    fn cqe_retune_if_idle(&mut self) -> Result<(), SdError> {
+        let cqe = self.cqe.as_ref().ok_or(SdError::NotSupported)?;
+        if cqe_busy(cqe) != 0 || !self.retune_needed() {
+            return Ok(());
+        }
+
+        let halted = cqe.halted;
+        self.cqe_halt()?;
+        if let Err(err) = self.retune() {
+            debug!("Queueing without re-tuning: {}", err);
+        }
+        if !halted {
+            self.cqe_resume()?;
+        }
+        Ok(())
+    }
+
+    // Move finished tasks from the hardware into the pending events
+    fn cqe_poll(&mut self) -> Result<(), SdError> {
+        if self.cqe.is_none() {
+            return Err(SdError::NotSupported);
+        }
+
+        let status = self.read_reg(EMMC_CQIS);
+        self.write_reg(EMMC_CQIS, status);
+
+        let mut completed = 0;
+        if status & CQHCI_IS_TCC != 0 {
+            completed = self.read_reg(EMMC_CQTDBN);
+            self.write_reg(EMMC_CQTDBN, completed);
+        }
+
+        let mut failed = 0;
+        let err_status = self.read_reg16(EMMC_ERROR_INT_STAT);
+        if status & CQHCI_IS_ERROR != 0 || err_status != 0 {
+            warn!(
+                "CQE error: CQIS {:#x}, error status {:#x}",
+                status, err_status
+            );
+            failed = self.cqe_recover()?;
+        }
+
+        let platform = self.platform;
+        let cqe = self.cqe.as_mut().ok_or(SdError::NotSupported)?;
+        for (tag, slot) in cqe.slots[..cqe.depth].iter().enumerate() {
+            if completed & !failed & (1 << tag) == 0 {
+                continue;
+            }
+            if let (Some(slot), Some(platform)) = (slot, platform)
+                && slot.read
+            {
+                platform.dma_invalidate(slot.data.cpu_addr, slot.len);
+            }
+        }
+
+        cqe.events.completed |= completed & !failed;
+        cqe.events.failed |= failed;
+        Ok(())
+    }
+
+    // Discard the tasks named by CQTERRI, or the whole queue if the error
+    // cannot be tied to a task. Returns the tags that were dropped.
+    fn cqe_recover(&mut self) -> Result<u32, SdError> {
+        let terri = self.read_reg(EMMC_CQTERRI);
+
+        let mut failed = 0;
+        if terri & CQHCI_TERRI_RESP_VALID != 0 {
+            failed |= 1 << ((terri >> CQHCI_TERRI_RESP_TASK_SHIFT) & CQHCI_TERRI_TASK_MASK);
+        }
+        if terri & CQHCI_TERRI_DATA_VALID != 0 {
+            failed |= 1 << ((terri >> CQHCI_TERRI_DATA_TASK_SHIFT) & CQHCI_TERRI_TASK_MASK);
+        }
+
+        self.cqe_halt()?;
+
+        // Clear the error on the SDHCI side before CMD48 goes out
+        self.write_reg16(EMMC_ERROR_INT_STAT, 0xFFFF);
+        self.reset_cmd()?;
+        self.reset_data()?;
+
+        if failed == 0 {
+            let cqe = self.cqe.as_ref().ok_or(SdError::NotSupported)?;
+            failed = cqe_busy(cqe);
+
+            self.cqe_task_mgmt(CQHCI_TM_DISCARD_QUEUE, 0)?;
+
+            let ctrl = self.read_reg(EMMC_CQCTRL);
+            self.write_reg(EMMC_CQCTRL, ctrl | CQHCI_CLEAR_ALL_TASKS);
+            self.cqe_wait_cleared(EMMC_CQCTRL, CQHCI_CLEAR_ALL_TASKS)?;
+        } else {
+            for tag in 0..CQHCI_MAX_SLOTS as u32 {
+                if failed & (1 << tag) == 0 {
+                    continue;
+                }
+                self.cqe_task_mgmt(CQHCI_TM_DISCARD_TASK, tag)?;
+
+                self.write_reg(EMMC_COTCLR, 1 << tag);
+                self.cqe_wait_cleared(EMMC_COTCLR, 1 << tag)?;
+            }
+        }
+
+        warn!("CQE discarded tasks {:#x} (CQTERRI {:#x})", failed, terri);
+
+        self.cqe_resume()?;
+        Ok(failed)
+    }
+
+    // CMD48 CMDQ_TASK_MGMT, only while the engine is halted
+    fn cqe_task_mgmt(&self, op: u32, tag: u32) -> Result<(), SdError> {
+        let cmd = EMmcCommand::new(MMC_CMDQ_TASK_MGMT, (tag << 16) | op, MMC_RSP_R1B);
+        self.send_command(&cmd, None)?;
+        self.mmc_poll_for_busy(false, self.card_timeouts().cmd6_us)
+    }
+
+    fn cqe_wait_cleared(&self, reg: u32, mask: u32) -> Result<(), SdError> {
+        self.poll_until(100_000, 1000, || self.read_reg(reg) & mask == 0)
+    }
+}
+
+// Tags with a task queued or not yet reported
+fn cqe_busy(cqe: &CqeState) -> u32 {
+    cqe.slots
+        .iter()
+        .enumerate()
+        .filter(|(_, slot)| slot.is_some())
+        .fold(0, |mask, (tag, _)| mask | 1 << tag)
+}
+
+// Hand the slots of reported tasks back for reuse
+fn cqe_release(cqe: &mut CqeState, tags: u32) {
+    for (tag, slot) in cqe.slots.iter_mut().enumerate() {
+        if tags & (1 << tag) != 0 {
+            *slot = None;
+        }
+    }
+}
@@ -0,0 +1,180 @@
+// ===== Driver Strength and Power Class =====
+
+use log::{debug, info, warn};
+
+use crate::err::SdError;
+
+use super::{EMmcHost, aux::MMC_VERSION_4, constant::*};
+
+// eMMC driver types with an SDHCI equivalent, type 4 has none
+const HOST_DRIVER_TYPES: u8 = 4;
+
+/// POWER_CLASS bytes of the EXT_CSD, per clock range and supply voltage. The
+/// low nibble holds the class for a 4-bit bus, the high nibble for 8 bits.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PowerClasses {
+    pub cl_26_195: u8,
+    pub cl_52_195: u8,
+    pub cl_200_195: u8,
+    pub cl_ddr_52_195: u8,
+    pub cl_26_360: u8,
+    pub cl_52_360: u8,
+    pub cl_200_360: u8,
+    pub cl_ddr_52_360: u8,
+    pub cl_ddr_200_360: u8,
+}
+
+impl PowerClasses {
+    pub fn parse_ext_csd(&mut self, ext_csd: &[u8]) {
+        let byte = |index: u32| ext_csd[index as usize];
+
+        self.cl_26_195 = byte(EXT_CSD_PWR_CL_26_195);
+        self.cl_52_195 = byte(EXT_CSD_PWR_CL_52_195);
+        self.cl_200_195 = byte(EXT_CSD_PWR_CL_200_195);
+        self.cl_ddr_52_195 = byte(EXT_CSD_PWR_CL_DDR_52_195);
+        self.cl_26_360 = byte(EXT_CSD_PWR_CL_26_360);
+        self.cl_52_360 = byte(EXT_CSD_PWR_CL_52_360);
+        self.cl_200_360 = byte(EXT_CSD_PWR_CL_200_360);
+        self.cl_ddr_52_360 = byte(EXT_CSD_PWR_CL_DDR_52_360);
+        self.cl_ddr_200_360 = byte(EXT_CSD_PWR_CL_DDR_200_360);
+    }
+
+    /// Class needed at `clock` Hz with `bus_width` as written to EXT_CSD
+    /// BUS_WIDTH, and VCC at 1.8 V if `low_voltage`, else 3.3 V
+    pub fn select(&self, clock: u32, bus_width: u8, low_voltage: bool) -> u8 {
+        let bus_width = bus_width & !EXT_CSD_BUS_WIDTH_STROBE;
+        if bus_width == EXT_CSD_BUS_WIDTH_1 {
+            return 0;
+        }
+        let ddr = bus_width == EXT_CSD_DDR_BUS_WIDTH_4 || bus_width == EXT_CSD_DDR_BUS_WIDTH_8;
+
+        let raw = match (low_voltage, clock) {
+            (true, ..=MMC_HIGH_26_MAX_DTR) => self.cl_26_195,
+            (true, ..=MMC_HIGH_52_MAX_DTR) if ddr => self.cl_ddr_52_195,
+            (true, ..=MMC_HIGH_52_MAX_DTR) => self.cl_52_195,
+            (true, _) => self.cl_200_195,
+            (false, ..=MMC_HIGH_26_MAX_DTR) => self.cl_26_360,
+            (false, ..=MMC_HIGH_52_MAX_DTR) if ddr => self.cl_ddr_52_360,
+            (false, ..=MMC_HIGH_52_MAX_DTR) => self.cl_52_360,
+            (false, _) if ddr => self.cl_ddr_200_360,
+            (false, _) => self.cl_200_360,
+        };
+
+        if bus_width == EXT_CSD_BUS_WIDTH_8 || bus_width == EXT_CSD_DDR_BUS_WIDTH_8 {
+            (raw & EXT_CSD_PWR_CL_8BIT_MASK) >> EXT_CSD_PWR_CL_8BIT_SHIFT
+        } else {
+            (raw & EXT_CSD_PWR_CL_4BIT_MASK) >> EXT_CSD_PWR_CL_4BIT_SHIFT
+        }
+    }
+}
+
+impl EMmcHost {
+    /// Driver type the board wants for HS200 and HS400, as numbered by
+    /// EXT_CSD DRIVER_STRENGTH. Used if the card supports it, otherwise the
+    /// host preset or type 0 is chosen.
+    pub fn with_driver_type(mut self, driver_type: u8) -> Self {
+        self.fixed_driver_type = Some(driver_type);
+        self
+    }
+
+    // Driver types of the host as a DRIVER_STRENGTH style mask, type B (0)
+    // is always there
+    fn host_driver_types(&self) -> u8 {
+        let caps2 = self.read_reg(EMMC_CAPABILITIES2);
+        let mut types = 1 << 0;
+        if caps2 & EMMC_DRIVER_TYPE_A != 0 {
+            types |= 1 << 1;
+        }
+        if caps2 & EMMC_DRIVER_TYPE_C != 0 {
+            types |= 1 << 2;
+        }
+        if caps2 & EMMC_DRIVER_TYPE_D != 0 {
+            types |= 1 << 3;
+        }
+        types
+    }
+
+    // Driver type for `timing`: the board choice if the card has it, else the
+    // type of the host preset if both sides have it, else type 0. With preset
+    // values in use the host drives with the preset type, which then wins.
+    pub(crate) fn mmc_select_driver_type(&self, timing: u32) -> u8 {
+        let card_types = self.raw_driver_strength().unwrap_or(0) | 1 << 0;
+
+        let preset_type = match timing {
+            MMC_TIMING_MMC_HS200 | MMC_TIMING_MMC_HS400 | MMC_TIMING_MMC_HS400ES => self
+                .sdhci_preset_value(timing)
+                .map(|preset| ((preset & EMMC_PRESET_DRV_MASK) >> EMMC_PRESET_DRV_SHIFT) as u8)
+                .filter(|drv| card_types & self.host_driver_types() & (1 << drv) != 0),
+            _ => None,
+        };
+
+        if let Some(drv) = preset_type.filter(|_| self.preset_values_supported()) {
+            debug!("Driver type {} from the host preset", drv);
+            return drv;
+        }
+
+        if let Some(fixed) = self.fixed_driver_type {
+            if fixed < 8 && card_types & (1 << fixed) != 0 {
+                debug!("Driver type {} from the board", fixed);
+                return fixed;
+            }
+            warn!(
+                "Card does not support driver type {}, types {:#x}",
+                fixed, card_types
+            );
+        }
+
+        if let Some(drv) = preset_type {
+            debug!("Driver type {} from the host preset", drv);
+            return drv;
+        }
+
+        0
+    }
+
+    // Driver type field of Host Control 2 for the card's driver type
+    pub(crate) fn host_driver_type_ctrl(&self) -> u16 {
+        match self.card.as_ref().map_or(0, |card| card.driver_type) {
+            drv if drv < HOST_DRIVER_TYPES => (drv as u16) << MMC_CTRL_DRV_TYPE_SHIFT,
+            _ => MMC_CTRL_DRV_TYPE_B,
+        }
+    }
+
+    /// Switch the card to the power class of its current clock, bus width
+    /// and supply voltage
+    pub fn mmc_select_power_class(&mut self) -> Result<(), SdError> {
+        let card = self.card.as_ref().ok_or(SdError::NoCard)?;
+        if card.version < MMC_VERSION_4 {
+            return Ok(());
+        }
+
+        let ddr = matches!(
+            card.timing,
+            MMC_TIMING_MMC_DDR52 | MMC_TIMING_MMC_HS400 | MMC_TIMING_MMC_HS400ES
+        );
+        let bus_width = match (card.bus_width, ddr) {
+            (MMC_BUS_WIDTH_8BIT, true) => EXT_CSD_DDR_BUS_WIDTH_8,
+            (MMC_BUS_WIDTH_8BIT, false) => EXT_CSD_BUS_WIDTH_8,
+            (MMC_BUS_WIDTH_4BIT, true) => EXT_CSD_DDR_BUS_WIDTH_4,
+            (MMC_BUS_WIDTH_4BIT, false) => EXT_CSD_BUS_WIDTH_4,
+            _ => EXT_CSD_BUS_WIDTH_1,
+        };
+        // The card runs on the highest supply the host offers
+        let low_voltage = self.voltages & !MMC_VDD_165_195 == 0;
+
+        let class = card
+            .power_classes
+            .select(card.clock, bus_width, low_voltage);
+        if class == card.power_class {
+            return Ok(());
+        }
+
+        self.mmc_switch(EXT_CSD_CMD_SET_NORMAL, EXT_CSD_POWER_CLASS, class, true)?;
+        info!("Power class {}", class);
+
+        let card = self.card.as_mut().ok_or(SdError::NoCard)?;
+        card.power_class = class;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+// ===== Device Tree Probe =====
+
+use alloc::vec::Vec;
+
+use fdt_parser::Node;
+use log::{debug, info};
+
+use crate::err::SdError;
+
+use super::{
+    EMmcHost,
+    config::EMmcChipConfig,
+    constant::*,
+    host::{RockchipDwcmshc, SdhciHostOps, StandardSdhci},
+};
+
+/// Clock specifier from the `clocks` property, assuming `#clock-cells = <1>`
+/// as used by the Rockchip CRU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DtClock {
+    pub phandle: u32,
+    pub id: u32,
+}
+
+/// Generic MMC controller bindings read from a device-tree node
+#[derive(Debug, Clone, Default)]
+pub struct MmcDtConfig {
+    pub reg_base: u64,
+    pub reg_size: usize,
+    pub clocks: Vec<DtClock>,
+    pub bus_width: u8,
+    pub max_frequency: Option<u32>,
+    /// Host capabilities in `MMC_MODE_*` bits
+    pub caps: u32,
+    pub non_removable: bool,
+    /// Skip the SD and the SDIO card probe
+    pub no_sd: bool,
+    pub no_sdio: bool,
+    pub cd_gpios: bool,
+    pub wp_gpios: bool,
+    /// Phandles of the card supply and the I/O signalling supply
+    pub vmmc_supply: Option<u32>,
+    pub vqmmc_supply: Option<u32>,
+    /// Controller is a Rockchip DWCMSHC with its DLL and vendor registers
+    pub dwcmshc: bool,
+    pub chip_config: Option<EMmcChipConfig>,
+    /// `fixed-emmc-driver-type`
+    pub fixed_driver_type: Option<u8>,
+}
+
+impl MmcDtConfig {
+    pub fn parse(node: &Node<'_>) -> Result<Self, SdError> {
+        let reg = node
+            .reg()
+            .and_then(|mut regs| regs.next())
+            .ok_or(SdError::InvalidArgument)?;
+
+        let mut config = Self {
+            reg_base: reg.address,
+            reg_size: reg.size.unwrap_or(0),
+            bus_width: 1,
+            ..Default::default()
+        };
+
+        if let Some(prop) = node.find_property("clocks") {
+            config.clocks = be32_cells(prop.raw_value())
+                .collect::<Vec<_>>()
+                .as_chunks::<2>()
+                .0
+                .iter()
+                .map(|&[phandle, id]| DtClock { phandle, id })
+                .collect();
+        }
+
+        config.bus_width = match prop_u32(node, "bus-width") {
+            Some(8) => 8,
+            Some(4) => 4,
+            _ => 1,
+        };
+        config.caps |= match config.bus_width {
+            8 => MMC_MODE_8BIT | MMC_MODE_4BIT,
+            4 => MMC_MODE_4BIT,
+            _ => 0,
+        };
+
+        config.max_frequency = prop_u32(node, "max-frequency");
+
+        if prop_bool(node, "cap-mmc-highspeed") {
+            config.caps |= MMC_MODE_HS | MMC_MODE_HS_52MHZ;
+        }
+        // HS400 is entered through HS200 tuning
+        if prop_bool(node, "mmc-hs200-1_8v") {
+            config.caps |= MMC_MODE_HS200;
+        }
+        if prop_bool(node, "mmc-hs400-1_8v") {
+            config.caps |= MMC_MODE_HS400 | MMC_MODE_HS200;
+        }
+        if prop_bool(node, "mmc-hs400-enhanced-strobe") {
+            config.caps |= MMC_MODE_HS400ES;
+        }
+
+        config.non_removable = prop_bool(node, "non-removable");
+        config.no_sd = prop_bool(node, "no-sd");
+        config.no_sdio = prop_bool(node, "no-sdio");
+        config.cd_gpios = prop_bool(node, "cd-gpios");
+        config.wp_gpios = prop_bool(node, "wp-gpios");
+        config.vmmc_supply = prop_u32(node, "vmmc-supply");
+        config.vqmmc_supply = prop_u32(node, "vqmmc-supply");
+        config.fixed_driver_type = prop_u32(node, "fixed-emmc-driver-type").map(|t| t as u8);
+
+        if let Some(prop) = node.find_property("compatible") {
+            for compatible in prop
+                .raw_value()
+                .split(|b| *b == 0)
+                .filter_map(|s| core::str::from_utf8(s).ok())
+            {
+                // e.g. "rockchip,rk3588-dwcmshc" and "rockchip,dwcmshc-sdhci".
+                // Other vendors' DWCMSHC lack the Rockchip DLL and CRU setup.
+                config.dwcmshc |=
+                    compatible.starts_with("rockchip,") && compatible.contains("dwcmshc");
+                if config.chip_config.is_none() {
+                    config.chip_config = EMmcChipConfig::from_compatible(compatible);
+                }
+            }
+        }
+
+        if let Some(chip_config) = config.chip_config.as_mut() {
+            chip_config.apply_dt_overrides(|name| prop_u32(node, name));
+        }
+
+        debug!("{} DT config: {:?}", node.name(), config);
+
+        Ok(config)
+    }
+}
+
+impl EMmcHost {
+    /// Create a host from its device-tree node. `iomap` maps the register
+    /// window given by `reg` (physical address, size) and returns its virtual address.
+    pub fn from_fdt_node<F>(node: &Node<'_>, iomap: F) -> Result<Self, SdError>
+    where
+        F: FnOnce(u64, usize) -> usize,
+    {
+        let dt = MmcDtConfig::parse(node)?;
+
+        let ops: &'static dyn SdhciHostOps = if dt.dwcmshc {
+            &RockchipDwcmshc
+        } else {
+            &StandardSdhci
+        };
+
+        let mut host = Self::new_with_ops(iomap(dt.reg_base, dt.reg_size), ops);
+        host.apply_dt_config(&dt);
+
+        Ok(host)
+    }
+
+    /// Apply board level bindings to the host, replacing the built-in defaults
+    pub fn apply_dt_config(&mut self, dt: &MmcDtConfig) {
+        if let Some(chip_config) = dt.chip_config {
+            self.chip_config = chip_config;
+        }
+
+        // The first clock is the card clock, the rest are bus and AXI clocks
+        if let Some(clk) = dt.clocks.first() {
+            self.clk.set_id(clk.id);
+        }
+
+        self.board_caps = Some(dt.caps);
+        self.max_freq = dt.max_frequency.unwrap_or(0);
+        // Read through `Platform::gpio_get`, the controller pin is ignored
+        self.wp_gpio = dt.wp_gpios;
+        self.no_sd = dt.no_sd;
+        self.no_sdio = dt.no_sdio;
+        // Supplies without a regulator in the tree are fixed on the board
+        self.vmmc_supply = dt.vmmc_supply.is_some();
+        self.vqmmc_supply = dt.vqmmc_supply.is_some();
+        if dt.fixed_driver_type.is_some() {
+            self.fixed_driver_type = dt.fixed_driver_type;
+        }
+
+        // A card detect GPIO needs a `CardDetect` from the board before
+        // hot-plug can be enabled, otherwise the controller CD pin is used
+        if !dt.non_removable && !dt.cd_gpios {
+            self.enable_hotplug();
+        }
+
+        info!(
+            "DT caps: {:#x}, max frequency: {}, removable: {}",
+            dt.caps, self.max_freq, !dt.non_removable
+        );
+    }
+}
+
+fn be32_cells(raw: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    raw.as_chunks::<4>()
+        .0
+        .iter()
+        .map(|c| u32::from_be_bytes(*c))
+}
+
+fn prop_u32(node: &Node<'_>, name: &str) -> Option<u32> {
+    let prop = node.find_property(name)?;
+    be32_cells(prop.raw_value()).next()
+}
+
+fn prop_bool(node: &Node<'_>, name: &str) -> bool {
+    node.find_property(name).is_some()
+}
@@ -0,0 +1,192 @@
+// ===== Host Controller Operations =====
+
+use core::fmt;
+
+use log::{debug, info};
+
+use crate::err::SdError;
+
+use super::{EMmcHost, constant::*, tuning::TuningResult};
+
+pub use super::rockchip::{DllLine, RockchipDwcmshc, SweepProbe, TapSweep};
+
+/// Controller specific hooks on top of the standard SDHCI register interface.
+/// Every method defaults to the plain SDHCI behaviour, vendor drivers only
+/// override what their controller does differently.
+pub trait SdhciHostOps: fmt::Debug + Send + Sync {
+    /// Program the SD clock to `freq` Hz, 0 gates the clock
+    fn set_clock(&self, host: &mut EMmcHost, freq: u32) -> Result<(), SdError> {
+        host.sdhci_set_clock(freq)
+    }
+
+    /// Program the UHS mode select of Host Control 2 for the current timing
+    fn set_uhs_signaling(&self, host: &mut EMmcHost) -> Result<(), SdError> {
+        host.sdhci_set_uhs_signaling()
+    }
+
+    /// Run the tuning procedure with the given tuning command
+    fn execute_tuning(&self, host: &mut EMmcHost, opcode: u8) -> Result<TuningResult, SdError> {
+        host.sdhci_execute_tuning(opcode)
+    }
+
+    /// Number of sampling clock phases software tuning can choose from, 0 if
+    /// the driver has no control over the sampling point
+    fn tuning_taps(&self, _host: &EMmcHost) -> u8 {
+        0
+    }
+
+    /// Sample the data lines at phase `tap` of `tuning_taps()`
+    fn set_tuning_tap(&self, _host: &mut EMmcHost, _tap: u8) -> Result<(), SdError> {
+        Err(SdError::NotSupported)
+    }
+
+    /// Software reset of the parts of the controller selected by `mask`
+    fn reset(&self, host: &EMmcHost, mask: u8) -> Result<(), SdError> {
+        host.sdhci_reset(mask)
+    }
+
+    /// Enable or disable the data strobe used by HS400 enhanced strobe mode.
+    /// Not part of the SDHCI specification.
+    fn hs400_enhanced_strobe(&self, _host: &mut EMmcHost, _enable: bool) -> Result<(), SdError> {
+        Err(SdError::NotSupported)
+    }
+}
+
+/// Controller following the SD Host Controller specification without vendor
+/// extensions, e.g. QEMU's `sdhci-pci`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StandardSdhci;
+
+impl SdhciHostOps for StandardSdhci {}
+
+impl EMmcHost {
+    pub fn set_clock(&mut self, freq: u32) -> Result<(), SdError> {
+        let ops = self.ops;
+        ops.set_clock(self, freq)
+    }
+
+    pub fn set_uhs_signaling(&mut self) -> Result<(), SdError> {
+        let ops = self.ops;
+        ops.set_uhs_signaling(self)
+    }
+
+    pub fn execute_tuning(&mut self, opcode: u8) -> Result<TuningResult, SdError> {
+        let ops = self.ops;
+        ops.execute_tuning(self, opcode)
+    }
+
+    // Reset the controller
+    pub fn reset(&self, mask: u8) -> Result<(), SdError> {
+        self.ops.reset(self, mask)
+    }
+
+    pub fn hs400_enhanced_strobe(&mut self, enable: bool) -> Result<(), SdError> {
+        let ops = self.ops;
+        ops.hs400_enhanced_strobe(self, enable)
+    }
+
+    /// SDHCI clock setup using the rate of the clock provider, or the base
+    /// clock reported in the capabilities if the host has none
+    pub fn sdhci_set_clock(&mut self, freq: u32) -> Result<(), SdError> {
+        self.sdhci_clock_off()?;
+
+        if freq == 0 {
+            return Ok(());
+        }
+
+        let input_clk = if self.clk.is_present() {
+            self.clk.get_rate()? as u32
+        } else {
+            self.clock_base
+        };
+
+        self.sdhci_enable_clock(freq, input_clk)
+    }
+
+    /// Standard SDHCI software reset
+    pub fn sdhci_reset(&self, mask: u8) -> Result<(), SdError> {
+        // Request reset
+        self.write_reg8(EMMC_SOFTWARE_RESET, mask);
+
+        // Wait for reset to complete with timeout
+        self.poll_until(20_000, 1000, || {
+            self.read_reg8(EMMC_SOFTWARE_RESET) & mask == 0
+        })
+    }
+
+    // Wait for the bus to go idle and gate the SD clock
+    pub(crate) fn sdhci_clock_off(&mut self) -> Result<(), SdError> {
+        // wait for command and data inhibit to be cleared
+        self.poll_until(20_000, 1000, || {
+            self.read_reg(EMMC_PRESENT_STATE) & (EMMC_CMD_INHIBIT | EMMC_DATA_INHIBIT) == 0
+        })
+        .inspect_err(|_| debug!("Timeout waiting for cmd & data inhibit"))?;
+
+        // first disable the clock
+        self.write_reg16(EMMC_CLOCK_CONTROL, 0x0000);
+
+        Ok(())
+    }
+
+    // Derive the divider for `freq` from `input_clk` and start the SD clock
+    pub(crate) fn sdhci_enable_clock(&mut self, freq: u32, input_clk: u32) -> Result<(), SdError> {
+        if let Some(clk) = self.sdhci_preset_clock(freq, input_clk) {
+            self.write_reg16(EMMC_CLOCK_CONTROL, clk);
+            return self.enable_card_clock(clk);
+        }
+
+        let mut div = 0;
+        let mut clk = 0u16;
+        let sdhci_version = self.read_reg16(EMMC_HOST_CNTRL_VER);
+
+        if (sdhci_version & 0xFF) >= EMMC_SPEC_300 {
+            let caps2 = self.read_reg(EMMC_CAPABILITIES2);
+            let clk_mul = (caps2 & EMMC_CLOCK_MUL_MASK) >> EMMC_CLOCK_MUL_SHIFT;
+
+            info!("EMMC Clock Mul: {}", clk_mul);
+
+            // Check if the Host Controller supports Programmable Clock Mode.
+            if clk_mul != 0 {
+                for i in 1..=1024 {
+                    if (input_clk / i) <= freq {
+                        div = i;
+                        break;
+                    }
+                }
+                // Set Programmable Clock Mode in the Clock Control register.
+                clk = EMMC_PROG_CLOCK_MODE;
+                div -= 1;
+            } else {
+                // Version 3.00 divisors must be a multiple of 2.
+                if input_clk <= freq {
+                    div = 1;
+                } else {
+                    for i in (2..=2046).step_by(2) {
+                        if (input_clk / i) <= freq {
+                            div = i;
+                            break;
+                        }
+                    }
+                }
+                div >>= 1;
+            }
+        } else {
+            // Version 2.00 divisors must be a power of 2.
+            let mut i = 1;
+            while i < 256 && (input_clk / i) > freq {
+                i *= 2;
+            }
+            div = i >> 1;
+        }
+
+        info!("EMMC Clock Divisor: 0x{:x}", div);
+
+        clk |= ((div as u16) & 0xFF) << EMMC_DIVIDER_SHIFT;
+        clk |= (((div as u16) & 0x300) >> 8) << EMMC_DIVIDER_HI_SHIFT;
+
+        self.write_reg16(EMMC_CLOCK_CONTROL, clk);
+        self.enable_card_clock(clk)?;
+
+        Ok(())
+    }
+}
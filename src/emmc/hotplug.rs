@@ -0,0 +1,292 @@
+// ===== Card Detect and Hot-plug =====
+
+use alloc::boxed::Box;
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::{debug, info, warn};
+
+use crate::err::SdError;
+
+use super::{EMmcHost, constant::*, platform::GpioLine};
+
+// Time the card detect signal has to settle before a state change is reported
+const CD_DEBOUNCE_MS: u32 = 200;
+// Consecutive equal samples required from a GPIO card detect line
+const CD_GPIO_STABLE_SAMPLES: u32 = 10;
+
+/// Card detect source provided by the board, e.g. a GPIO line wired to the slot
+pub trait CardDetect: Send + Sync {
+    /// Return `true` if a card is physically present in the slot
+    fn card_present(&self) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotplugEvent {
+    Inserted,
+    Removed,
+}
+
+/// Callback invoked on a debounced card insertion or removal.
+/// On `Inserted` the embedding OS is expected to call `EMmcHost::rescan()`.
+pub type HotplugCallback = Box<dyn Fn(HotplugEvent) + Send + Sync>;
+
+pub struct Hotplug {
+    detect: Option<Box<dyn CardDetect>>,
+    callback: Option<HotplugCallback>,
+    // Slot is removable and hot-plug handling is armed
+    enabled: bool,
+    // Last debounced card presence
+    present: bool,
+    // Set as soon as a removal is seen so that in-flight I/O can bail out
+    removed: AtomicBool,
+}
+
+impl Hotplug {
+    pub const fn new() -> Self {
+        Self {
+            detect: None,
+            callback: None,
+            enabled: false,
+            present: false,
+            removed: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Default for Hotplug {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Hotplug {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Hotplug {{ enabled: {}, gpio_cd: {}, present: {}, removed: {} }}",
+            self.enabled,
+            self.detect.is_some(),
+            self.present,
+            self.removed.load(Ordering::Relaxed)
+        )
+    }
+}
+
+impl EMmcHost {
+    /// Use a board card detect line instead of the controller CD pin
+    pub fn set_card_detect(&mut self, detect: Box<dyn CardDetect>) {
+        self.hotplug.detect = Some(detect);
+    }
+
+    pub fn set_hotplug_callback(&mut self, callback: HotplugCallback) {
+        self.hotplug.callback = Some(callback);
+    }
+
+    /// Mark the slot as removable and arm insertion/removal detection
+    pub fn enable_hotplug(&mut self) {
+        self.hotplug.enabled = true;
+        self.hotplug.present = self.is_card_present();
+        self.hotplug
+            .removed
+            .store(!self.hotplug.present, Ordering::SeqCst);
+        self.hotplug_arm_irq();
+
+        info!("Hot-plug enabled, card present: {}", self.hotplug.present);
+    }
+
+    pub fn disable_hotplug(&mut self) {
+        self.hotplug.enabled = false;
+        self.hotplug.removed.store(false, Ordering::SeqCst);
+        self.hotplug_set_irq(0);
+    }
+
+    pub fn hotplug_enabled(&self) -> bool {
+        self.hotplug.enabled
+    }
+
+    // Check if card is present
+    pub fn is_card_present(&self) -> bool {
+        if let Some(detect) = &self.hotplug.detect {
+            return detect.card_present();
+        }
+
+        if let Some(present) = self.platform_gpio_get(GpioLine::CardDetect) {
+            return present;
+        }
+
+        let state = self.read_reg(EMMC_PRESENT_STATE);
+        // debug!("EMMC Present State: {:#b}", state);
+        (state & EMMC_CARD_INSERTED) != 0 && ((state & EMMC_CARD_STABLE) != 0)
+    }
+
+    // Fail fast if the card was pulled while a request is in flight
+    pub(crate) fn check_card_removed(&self) -> Result<(), SdError> {
+        if !self.hotplug.enabled {
+            return Ok(());
+        }
+
+        if self.hotplug.removed.load(Ordering::SeqCst) {
+            return Err(SdError::NoCard);
+        }
+
+        if self.hotplug.detect.is_none()
+            && self.read_reg(EMMC_PRESENT_STATE) & EMMC_CARD_INSERTED == 0
+        {
+            self.hotplug.removed.store(true, Ordering::SeqCst);
+            return Err(SdError::NoCard);
+        }
+
+        Ok(())
+    }
+
+    // Only the opposite event of the current state is unmasked, the insertion and
+    // removal status bits stay asserted as long as the slot is in that state
+    pub(crate) fn hotplug_arm_irq(&self) {
+        if !self.hotplug.enabled || self.hotplug.detect.is_some() {
+            return;
+        }
+
+        let mask = if self.hotplug.present {
+            EMMC_INT_CARD_REMOVE
+        } else {
+            EMMC_INT_CARD_INSERT
+        };
+        self.hotplug_set_irq(mask as u16);
+    }
+
+    fn hotplug_set_irq(&self, mask: u16) {
+        let cd_mask = (EMMC_INT_CARD_INSERT | EMMC_INT_CARD_REMOVE) as u16;
+
+        let stat_en = (self.read_reg16(EMMC_NORMAL_INT_STAT_EN) & !cd_mask) | mask;
+        let sig_en = (self.read_reg16(EMMC_SIGNAL_ENABLE) & !cd_mask) | mask;
+
+        self.write_reg16(EMMC_NORMAL_INT_STAT_EN, stat_en);
+        self.write_reg16(EMMC_SIGNAL_ENABLE, sig_en);
+    }
+
+    /// Service the card insertion/removal interrupt.
+    /// Returns the debounced event, if the card state actually changed.
+    pub fn handle_hotplug_irq(&mut self) -> Option<HotplugEvent> {
+        let cd_mask = (EMMC_INT_CARD_INSERT | EMMC_INT_CARD_REMOVE) as u16;
+        let masked = self.irq_take_card_events(cd_mask as u32) != 0;
+
+        let status = self.read_reg16(EMMC_NORMAL_INT_STAT) & cd_mask;
+        if status == 0 {
+            // Unmask what the interrupt handler masked
+            if masked {
+                self.hotplug_arm_irq();
+            }
+            return None;
+        }
+
+        self.write_reg16(EMMC_NORMAL_INT_STAT, status);
+
+        // Abort in-flight I/O right away, the debounce below may take a while
+        if status & EMMC_INT_CARD_REMOVE as u16 != 0 {
+            self.hotplug.removed.store(true, Ordering::SeqCst);
+        }
+
+        let event = self.hotplug_poll();
+        self.hotplug_arm_irq();
+
+        event
+    }
+
+    /// Sample the card detect state and report a debounced change.
+    /// Can be called periodically for slots without a card detect interrupt.
+    pub fn hotplug_poll(&mut self) -> Option<HotplugEvent> {
+        if !self.hotplug.enabled {
+            return None;
+        }
+
+        let present = match self.card_detect_debounced() {
+            Ok(present) => present,
+            Err(e) => {
+                warn!("Card detect debounce failed: {:?}", e);
+                return None;
+            }
+        };
+        if present == self.hotplug.present {
+            // Spurious edge, restore the I/O state
+            self.hotplug.removed.store(!present, Ordering::SeqCst);
+            return None;
+        }
+
+        self.hotplug.present = present;
+
+        let event = if present {
+            self.hotplug.removed.store(false, Ordering::SeqCst);
+            HotplugEvent::Inserted
+        } else {
+            self.card_teardown();
+            HotplugEvent::Removed
+        };
+
+        info!("Card hot-plug event: {:?}", event);
+
+        if let Some(callback) = &self.hotplug.callback {
+            callback(event);
+        }
+
+        Some(event)
+    }
+
+    /// Re-initialize the card after an insertion event
+    pub fn rescan(&mut self) -> Result<(), SdError> {
+        if !self.is_card_present() {
+            return Err(SdError::NoCard);
+        }
+
+        self.init()
+    }
+
+    fn card_detect_debounced(&self) -> Result<bool, SdError> {
+        if let Some(detect) = &self.hotplug.detect {
+            let mut last = detect.card_present();
+            let mut stable = 0;
+            let mut elapsed = 0;
+
+            while stable < CD_GPIO_STABLE_SAMPLES && elapsed < CD_DEBOUNCE_MS {
+                self.delay_us(1000)?;
+                elapsed += 1;
+
+                let now = detect.card_present();
+                if now == last {
+                    stable += 1;
+                } else {
+                    last = now;
+                    stable = 0;
+                }
+            }
+
+            return Ok(last);
+        }
+
+        // The controller debounces the CD pin itself, wait for it to settle
+        for _ in 0..CD_DEBOUNCE_MS {
+            let state = self.read_reg(EMMC_PRESENT_STATE);
+            if state & EMMC_CARD_STABLE != 0 {
+                return Ok(state & EMMC_CARD_INSERTED != 0);
+            }
+            self.delay_us(1000)?;
+        }
+
+        debug!("Card detect never became stable");
+        Ok(false)
+    }
+
+    // Drop all card state after removal, the next insertion goes through init() again
+    fn card_teardown(&mut self) {
+        self.hotplug.removed.store(true, Ordering::SeqCst);
+
+        let _ = self.set_initialized(false);
+        self.card = None;
+
+        let _ = self.reset(EMMC_RESET_CMD | EMMC_RESET_DATA);
+        self.write_reg16(EMMC_CLOCK_CONTROL, 0);
+        self.write_reg8(EMMC_POWER_CTRL, 0);
+    }
+}
@@ -1,4 +1,4 @@
-use super::{EMmcHost, block::EMmcCard, cmd::EMmcCommand, constant::*};
+use super::{EMmcHost, block::EMmcCard, cmd::EMmcCommand, constant::*, status::CardStatus};
 use crate::err::SdError;
 use core::sync::atomic::Ordering;
 
@@ -27,7 +27,7 @@ pub enum CardType {
 
 impl EMmcHost {
     // Get card status
-    pub fn get_status(&self) -> Result<u32, SdError> {
+    pub fn get_status(&self) -> Result<CardStatus, SdError> {
         // Check if card is initialized
         let card = match &self.card {
             Some(card) => card,
@@ -39,11 +39,11 @@ impl EMmcHost {
         }
 
         // Send SEND_STATUS command
-        let cmd = EMmcCommand::new(MMC_SEND_STATUS, card.rca, MMC_RSP_R1);
+        let cmd = EMmcCommand::new(MMC_SEND_STATUS, card.rca << 16, MMC_RSP_R1);
         self.send_command(&cmd, None)?;
         let response = self.get_response();
 
-        Ok(response.as_r1())
+        Ok(CardStatus::new(response.as_r1()))
     }
 
     // Get card info
@@ -129,13 +129,13 @@ macro_rules! impl_emmc_card_proxy {
 
                 paste::paste! {
                     /// Proxy setter method for setting a field on the attached EMmcCard.
-                    /// Returns `Ok(())` if successful, or an error string if no card is present.
-                    pub fn [<set_ $field>](&mut self, value: $type) -> Result<(), &'static str> {
+                    /// Returns `Ok(())` if successful, or `SdError::NoCard` if no card is present.
+                    pub fn [<set_ $field>](&mut self, value: $type) -> Result<(), SdError> {
                         if let Some(card) = self.card.as_mut() {
                             card.$field = value;
                             Ok(())
                         } else {
-                            Err("No card present")
+                            Err(SdError::NoCard)
                         }
                     }
                 }
@@ -175,6 +175,8 @@ impl_emmc_card_proxy!(
     part_support: u8,
     part_attr: u8,
     wr_rel_set: u8,
+    wr_rel_param: u8,
+    rel_wr_sec_c: u8,
     part_config: u8,
     dsr_imp: u32,
     card_caps: u32,
@@ -189,7 +191,10 @@ impl_emmc_card_proxy!(
     ext_csd_rev: u8,
     ext_csd_sectors: u64,
     hs_max_dtr: u32,
-    raw_driver_strength: u8
+    raw_driver_strength: u8,
+    locked: bool,
+    cache_ctrl: bool,
+    can_trim: bool
 );
 
 impl EMmcHost {
@@ -202,12 +207,12 @@ impl EMmcHost {
         self.card.as_ref().map(|card| card.cid)
     }
 
-    pub fn set_cid(&mut self, value: [u32; 4]) -> Result<(), &'static str> {
+    pub fn set_cid(&mut self, value: [u32; 4]) -> Result<(), SdError> {
         if let Some(card) = self.card.as_mut() {
             card.cid = value;
             Ok(())
         } else {
-            Err("No card present")
+            Err(SdError::NoCard)
         }
     }
 
@@ -216,12 +221,12 @@ impl EMmcHost {
         self.card.as_ref().map(|card| card.csd)
     }
 
-    pub fn set_csd(&mut self, value: [u32; 4]) -> Result<(), &'static str> {
+    pub fn set_csd(&mut self, value: [u32; 4]) -> Result<(), SdError> {
         if let Some(card) = self.card.as_mut() {
             card.csd = value;
             Ok(())
         } else {
-            Err("No card present")
+            Err(SdError::NoCard)
         }
     }
 
@@ -230,12 +235,12 @@ impl EMmcHost {
         self.card.as_ref().map(|card| card.capacity_gp)
     }
 
-    pub fn set_capacity_gp(&mut self, value: [u64; 4]) -> Result<(), &'static str> {
+    pub fn set_capacity_gp(&mut self, value: [u64; 4]) -> Result<(), SdError> {
         if let Some(card) = self.card.as_mut() {
             card.capacity_gp = value;
             Ok(())
         } else {
-            Err("No card present")
+            Err(SdError::NoCard)
         }
     }
 
@@ -246,12 +251,12 @@ impl EMmcHost {
             .map(|card| card.initialized.load(Ordering::Relaxed))
     }
 
-    pub fn set_initialized(&mut self, value: bool) -> Result<(), &'static str> {
+    pub fn set_initialized(&mut self, value: bool) -> Result<(), SdError> {
         if let Some(card) = self.card.as_mut() {
             card.initialized.store(value, Ordering::Relaxed);
             Ok(())
         } else {
-            Err("No card present")
+            Err(SdError::NoCard)
         }
     }
 
@@ -260,12 +265,12 @@ impl EMmcHost {
         self.card.as_ref().map(|card| card.enh_user_size)
     }
 
-    pub fn set_enh_user_size(&mut self, value: u64) -> Result<(), &'static str> {
+    pub fn set_enh_user_size(&mut self, value: u64) -> Result<(), SdError> {
         if let Some(card) = self.card.as_mut() {
             card.enh_user_size = value;
             Ok(())
         } else {
-            Err("No card present")
+            Err(SdError::NoCard)
         }
     }
 
@@ -273,12 +278,12 @@ impl EMmcHost {
         self.card.as_ref().map(|card| card.enh_user_start)
     }
 
-    pub fn set_enh_user_start(&mut self, value: u64) -> Result<(), &'static str> {
+    pub fn set_enh_user_start(&mut self, value: u64) -> Result<(), SdError> {
         if let Some(card) = self.card.as_mut() {
             card.enh_user_start = value;
             Ok(())
         } else {
-            Err("No card present")
+            Err(SdError::NoCard)
         }
     }
 }
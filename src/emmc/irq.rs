@@ -0,0 +1,281 @@
+// ===== Interrupt Driven Completion =====
+
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    task::Waker,
+};
+
+use atomic_waker::AtomicWaker;
+use log::{debug, trace};
+
+use crate::err::SdError;
+
+use super::{EMmcHost, constant::*};
+
+// Interval between two looks at the recorded status while waiting
+const IRQ_WAIT_STEP_US: u64 = 10;
+
+// Status bits owned by the command and data paths. Card detect and SDIO card
+// interrupts are left pending for their own handlers.
+pub(crate) const IRQ_CMD_DATA_MASK: u32 =
+    EMMC_INT_CMD_MASK | EMMC_INT_DATA_MASK | EMMC_INT_ERROR_MASK;
+
+// Status bits that stay asserted until `sdio_handle_irq` or
+// `handle_hotplug_irq` has serviced them. The handler masks them at the
+// signal level and leaves the status for those functions.
+const IRQ_CARD_MASK: u32 = EMMC_INT_CARD_INT | EMMC_INT_CARD_INSERT | EMMC_INT_CARD_REMOVE;
+
+// Status recorded by `EMmcHost::handle_irq`, normal status in the low and
+// error status in the high half as laid out in the register file
+pub struct IrqState {
+    enabled: AtomicBool,
+    status: AtomicU32,
+    // Card interrupt and card detect events masked by the handler
+    card_events: AtomicU32,
+    // Task waiting for the next completion, see `asynch`. Woken from the
+    // interrupt handler, so it must not take a lock the task may hold.
+    waker: AtomicWaker,
+}
+
+impl IrqState {
+    pub const fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            status: AtomicU32::new(0),
+            card_events: AtomicU32::new(0),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    fn record(&self, status: u32) {
+        self.status.fetch_or(status, Ordering::SeqCst);
+        self.waker.wake();
+    }
+
+    // Consume the recorded status once all of `mask` or an error is there
+    pub(crate) fn take(&self, mask: u32) -> Option<u32> {
+        let status = self.status.load(Ordering::SeqCst);
+        if status & mask == mask || status & EMMC_INT_ERROR_MASK != 0 {
+            let wanted = mask | EMMC_INT_ERROR_MASK;
+            self.status.fetch_and(!(status & wanted), Ordering::SeqCst);
+            return Some(status);
+        }
+        None
+    }
+
+    // Consume the recorded card events in `mask`
+    fn take_card_events(&self, mask: u32) -> u32 {
+        self.card_events.fetch_and(!mask, Ordering::SeqCst) & mask
+    }
+
+    pub(crate) fn register(&self, waker: &Waker) {
+        self.waker.register(waker);
+    }
+}
+
+impl Default for IrqState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for IrqState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "IrqState {{ enabled: {}, status: {:#x}, card_events: {:#x} }}",
+            self.enabled.load(Ordering::Relaxed),
+            self.status.load(Ordering::Relaxed),
+            self.card_events.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Interrupt side of a host, for handlers that cannot reach the host itself,
+/// e.g. while it is owned by an `AsyncEMmcHost`
+#[derive(Debug, Clone)]
+pub struct IrqHandle {
+    base_addr: usize,
+    state: Arc<IrqState>,
+}
+
+impl IrqHandle {
+    /// Same as `EMmcHost::handle_irq`
+    pub fn handle_irq(&self) -> bool {
+        irq_ack(self.base_addr, &self.state)
+    }
+
+    /// Same as `EMmcHost::card_irq_pending`
+    pub fn card_irq_pending(&self) -> u32 {
+        self.state.card_events.load(Ordering::SeqCst)
+    }
+}
+
+// Acknowledge the command and data interrupts and record them in `state`.
+// Card interrupt and card detect events are masked and recorded instead.
+fn irq_ack(base_addr: usize, state: &IrqState) -> bool {
+    let reg = (base_addr + EMMC_NORMAL_INT_STAT as usize) as *mut u32;
+    let sig_reg = (base_addr + EMMC_SIGNAL_ENABLE as usize) as *mut u32;
+
+    let pending = unsafe { core::ptr::read_volatile(reg) };
+    let sig_en = unsafe { core::ptr::read_volatile(sig_reg) };
+
+    // The SDIO card interrupt is level triggered and the card detect status
+    // stays set with the slot state, so the line only drops once they are
+    // masked. Their handlers unmask them again.
+    let card = pending & sig_en & IRQ_CARD_MASK;
+    if card != 0 {
+        unsafe { core::ptr::write_volatile(sig_reg, sig_en & !card) };
+        state.card_events.fetch_or(card, Ordering::SeqCst);
+        trace!("IRQ card events {:#x}", card);
+    }
+
+    let status = pending & IRQ_CMD_DATA_MASK;
+    if status != 0 {
+        // Write-1-to-clear covers the normal and the error status register
+        unsafe { core::ptr::write_volatile(reg, status) };
+        state.record(status);
+        trace!("IRQ status {:#x}", status);
+    }
+
+    status != 0 || card != 0
+}
+
+impl EMmcHost {
+    /// Interrupt line of the controller. With a platform attached,
+    /// `enable_irq_mode()` registers a handler for it through
+    /// `Platform::request_irq`.
+    pub fn with_irq(mut self, irq: u32) -> Self {
+        self.irq_line = Some(irq);
+        self
+    }
+
+    /// Complete commands and data transfers from the controller interrupt
+    /// instead of polling the status registers. Unless the platform took the
+    /// interrupt line given by `with_irq()`, `handle_irq()` has to be called
+    /// from the interrupt handler of the controller.
+    pub fn enable_irq_mode(&mut self) -> Result<(), SdError> {
+        self.irq.status.store(0, Ordering::SeqCst);
+
+        if let (Some(platform), Some(line), false) =
+            (self.platform, self.irq_line, self.irq_requested)
+        {
+            let handle = self.irq_handle();
+            match platform.request_irq(
+                line,
+                Box::new(move || {
+                    handle.handle_irq();
+                }),
+            ) {
+                Ok(()) => self.irq_requested = true,
+                Err(SdError::NotSupported) => {
+                    debug!("Platform cannot register IRQ {}", line);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.irq.enabled.store(true, Ordering::SeqCst);
+        self.irq_arm();
+
+        debug!("Interrupt driven completion enabled");
+        Ok(())
+    }
+
+    pub fn disable_irq_mode(&mut self) {
+        self.irq.enabled.store(false, Ordering::SeqCst);
+        self.irq_set_signal(false);
+        self.irq_release();
+    }
+
+    // Unregister the handler `enable_irq_mode()` gave to the platform
+    pub(crate) fn irq_release(&mut self) {
+        if !self.irq_requested {
+            return;
+        }
+
+        if let (Some(platform), Some(line)) = (self.platform, self.irq_line) {
+            platform.free_irq(line);
+        }
+        self.irq_requested = false;
+    }
+
+    pub fn irq_mode_enabled(&self) -> bool {
+        self.irq.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Acknowledge and record command, transfer and error interrupts. SDIO
+    /// card interrupts and card detect events are masked until
+    /// `sdio_handle_irq()` or `handle_hotplug_irq()` services them, see
+    /// `card_irq_pending()`. Returns `true` if the controller raised any of
+    /// them.
+    pub fn handle_irq(&self) -> bool {
+        irq_ack(self.base_addr, &self.irq)
+    }
+
+    /// `EMMC_INT_CARD_INT`, `EMMC_INT_CARD_INSERT` and `EMMC_INT_CARD_REMOVE`
+    /// events the interrupt handler has masked, waiting for
+    /// `sdio_handle_irq()` or `handle_hotplug_irq()`
+    pub fn card_irq_pending(&self) -> u32 {
+        self.irq.card_events.load(Ordering::SeqCst)
+    }
+
+    // Consume the card events in `mask` recorded by the interrupt handler
+    pub(crate) fn irq_take_card_events(&self, mask: u32) -> u32 {
+        self.irq.take_card_events(mask)
+    }
+
+    pub fn irq_handle(&self) -> IrqHandle {
+        IrqHandle {
+            base_addr: self.base_addr,
+            state: self.irq.clone(),
+        }
+    }
+
+    // Unmask the completion interrupts at the signal level, re-done after
+    // init() has cleared the signal enables
+    pub(crate) fn irq_arm(&self) {
+        if self.irq_mode_enabled() {
+            self.irq_set_signal(true);
+        }
+    }
+
+    fn irq_set_signal(&self, enable: bool) {
+        let mask = EMMC_INT_CMD_MASK | EMMC_INT_DATA_MASK;
+
+        let mut sig_en = self.read_reg(EMMC_SIGNAL_ENABLE);
+        if enable {
+            sig_en |= mask;
+        } else {
+            sig_en &= !mask;
+        }
+        self.write_reg(EMMC_SIGNAL_ENABLE, sig_en);
+    }
+
+    // Forget completions left over from a previous request
+    pub(crate) fn irq_clear(&self) {
+        self.irq.status.store(0, Ordering::SeqCst);
+    }
+
+    // Wait until all of `mask` or an error has been recorded and consume those
+    // bits. Returns the recorded status including both halves.
+    pub(crate) fn irq_wait(&self, mask: u32, timeout_us: u64) -> Result<u32, SdError> {
+        let mut deadline = self.deadline(timeout_us);
+
+        loop {
+            if let Some(status) = self.irq.take(mask) {
+                return Ok(status);
+            }
+
+            if deadline.expired() {
+                self.check_card_removed()?;
+                debug!("IRQ wait for {:#x} timed out", mask);
+                return Err(SdError::Timeout);
+            }
+
+            self.wait_step(&mut deadline, IRQ_WAIT_STEP_US)?;
+        }
+    }
+}
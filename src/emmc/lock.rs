@@ -0,0 +1,164 @@
+// ===== Card Lock/Unlock (CMD42) =====
+
+#[cfg(feature = "dma")]
+use dma_api::{DVec, Direction};
+use log::{info, warn};
+
+use crate::err::SdError;
+
+use super::{EMmcHost, block::DataBuffer, cmd::EMmcCommand, constant::*};
+
+// Forced erase may keep the card busy for up to 3 minutes
+const FORCE_ERASE_TIMEOUT_US: u64 = 3 * 60 * 1_000_000;
+const LOCK_UNLOCK_TIMEOUT_US: u64 = 1_000_000;
+
+impl EMmcHost {
+    /// Read CARD_IS_LOCKED from the card status and update the card state
+    pub fn refresh_lock_status(&mut self) -> Result<bool, SdError> {
+        let status = self.mmc_lock_send_status()?;
+        let locked = status & MMC_STATUS_CARD_IS_LOCKED != 0;
+
+        if let Some(card) = self.card.as_mut() {
+            card.locked = locked;
+        }
+
+        Ok(locked)
+    }
+
+    /// Set a new password. `old` is empty if the card has no password yet.
+    pub fn set_password(&mut self, old: &[u8], new: &[u8]) -> Result<(), SdError> {
+        if new.is_empty() || old.len() > MMC_LOCK_MAX_PWD_LEN || new.len() > MMC_LOCK_MAX_PWD_LEN {
+            return Err(SdError::InvalidArgument);
+        }
+
+        // Replacing a password sends the old and the new one back to back
+        let mut pwd = [0u8; 2 * MMC_LOCK_MAX_PWD_LEN];
+        pwd[..old.len()].copy_from_slice(old);
+        pwd[old.len()..old.len() + new.len()].copy_from_slice(new);
+
+        self.mmc_lock_unlock(MMC_LOCK_SET_PWD, &pwd[..old.len() + new.len()])
+    }
+
+    /// Remove the password of the card
+    pub fn clear_password(&mut self, pwd: &[u8]) -> Result<(), SdError> {
+        self.mmc_lock_unlock(MMC_LOCK_CLR_PWD, pwd)
+    }
+
+    /// Lock the card with its current password
+    pub fn lock(&mut self, pwd: &[u8]) -> Result<(), SdError> {
+        self.mmc_lock_unlock(MMC_LOCK_LOCK_UNLOCK, pwd)
+    }
+
+    /// Unlock the card
+    pub fn unlock(&mut self, pwd: &[u8]) -> Result<(), SdError> {
+        self.mmc_lock_unlock(0, pwd)
+    }
+
+    /// Erase all user data together with the password of a locked card
+    pub fn force_erase(&mut self) -> Result<(), SdError> {
+        warn!("Forced erase: all user data will be lost");
+        self.mmc_lock_unlock(MMC_LOCK_ERASE, &[])
+    }
+
+    fn mmc_lock_unlock(&mut self, flags: u8, pwd: &[u8]) -> Result<(), SdError> {
+        if self.card.is_none() {
+            return Err(SdError::NoCard);
+        }
+
+        if (flags & MMC_LOCK_ERASE == 0 && pwd.is_empty()) || pwd.len() > 2 * MMC_LOCK_MAX_PWD_LEN {
+            return Err(SdError::InvalidArgument);
+        }
+
+        // Lock card data structure: flags, PWD_LEN, password.
+        // Forced erase sends the flags byte only.
+        let mut buf = [0u8; 2 + 2 * MMC_LOCK_MAX_PWD_LEN];
+        buf[0] = flags;
+        let len = if flags & MMC_LOCK_ERASE != 0 {
+            1
+        } else {
+            buf[1] = pwd.len() as u8;
+            buf[2..2 + pwd.len()].copy_from_slice(pwd);
+            2 + pwd.len()
+        };
+
+        // The block length has to match the data structure exactly
+        let cmd16 = EMmcCommand::new(MMC_SET_BLOCKLEN, len as u32, MMC_RSP_R1);
+        self.send_command(&cmd16, None)?;
+
+        let ret = self.mmc_lock_unlock_xfer(&buf[..len]);
+
+        // A failed transfer is the error the caller wants to see
+        let cmd16 = EMmcCommand::new(MMC_SET_BLOCKLEN, MMC_MAX_BLOCK_LEN, MMC_RSP_R1);
+        let restore = self
+            .send_command(&cmd16, None)
+            .inspect_err(|e| warn!("Restoring the block length after CMD42 failed: {}", e));
+
+        ret?;
+        restore?;
+
+        let timeout = if flags & MMC_LOCK_ERASE != 0 {
+            FORCE_ERASE_TIMEOUT_US
+        } else {
+            LOCK_UNLOCK_TIMEOUT_US
+        };
+        let status = self.mmc_lock_wait_ready(timeout)?;
+
+        let locked = status & MMC_STATUS_CARD_IS_LOCKED != 0;
+        if let Some(card) = self.card.as_mut() {
+            card.locked = locked;
+        }
+
+        if status & MMC_STATUS_LOCK_UNLOCK_FAILED != 0 {
+            warn!("CMD42 rejected by card, status {:#x}", status);
+            return Err(SdError::LockUnlockFailed);
+        }
+
+        info!("CMD42 flags {:#x} done, card locked: {}", flags, locked);
+
+        Ok(())
+    }
+
+    fn mmc_lock_unlock_xfer(&self, data: &[u8]) -> Result<(), SdError> {
+        let cmd =
+            EMmcCommand::new(MMC_LOCK_UNLOCK, 0, MMC_RSP_R1).with_data(data.len() as u16, 1, false);
+
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "dma")] {
+                let mut buf: DVec<u8> = DVec::zeros(data.len(), 0x1000, Direction::ToDevice)
+                    .ok_or(SdError::MemoryError)?;
+                for (i, b) in data.iter().enumerate() {
+                    buf.set(i, *b);
+                }
+                self.send_command(&cmd, Some(DataBuffer::Write(&buf)))
+            } else if #[cfg(feature = "pio")] {
+                self.send_command(&cmd, Some(DataBuffer::Write(data)))
+            }
+        }
+    }
+
+    fn mmc_lock_send_status(&self) -> Result<u32, SdError> {
+        let rca = self.card.as_ref().ok_or(SdError::NoCard)?.rca;
+
+        let cmd = EMmcCommand::new(MMC_SEND_STATUS, rca << 16, MMC_RSP_R1);
+        self.send_command(&cmd, None)?;
+
+        Ok(self.get_response().as_r1())
+    }
+
+    // Wait until the card leaves the programming state and return its status
+    fn mmc_lock_wait_ready(&self, timeout_us: u64) -> Result<u32, SdError> {
+        let mut deadline = self.deadline(timeout_us);
+        loop {
+            let status = self.mmc_lock_send_status()?;
+            if status & MMC_STATUS_RDY_FOR_DATA != 0
+                && (status & MMC_STATUS_CURR_STATE) != MMC_STATE_PRG
+            {
+                return Ok(status);
+            }
+            if deadline.expired() {
+                return Err(SdError::Timeout);
+            }
+            self.wait_step(&mut deadline, 1000)?;
+        }
+    }
+}
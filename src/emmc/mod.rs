@@ -1,30 +1,59 @@
-extern crate alloc;
-
 mod block;
 mod cmd;
-mod config;
 mod info;
+mod lock;
+mod preset;
 mod regs;
 mod rockchip;
+mod sdio;
 
+pub mod asynch;
 pub mod aux;
 pub mod clock;
+pub mod config;
 pub mod constant;
-
-use crate::{delay_us, err::*};
+pub mod cqhci;
+pub mod drive;
+pub mod dt;
+pub mod host;
+pub mod hotplug;
+pub mod irq;
+pub mod platform;
+pub mod recovery;
+pub mod status;
+pub mod timeout;
+pub mod tuning;
+pub mod wp;
+
+use crate::err::*;
+use alloc::sync::Arc;
+#[cfg(feature = "dma")]
+use asynch::ParkedBuffer;
 use aux::{
     MMC_VERSION_1_2, MMC_VERSION_1_4, MMC_VERSION_2_2, MMC_VERSION_3, MMC_VERSION_4,
     MMC_VERSION_4_1, MMC_VERSION_4_2, MMC_VERSION_4_3, MMC_VERSION_4_5, MMC_VERSION_4_41,
     MMC_VERSION_5_0, MMC_VERSION_5_1, MMC_VERSION_UNKNOWN, generic_fls, lldiv,
 };
 use block::EMmcCard;
+use clock::HostClk;
 use cmd::*;
+use config::EMmcChipConfig;
 use constant::*;
 use core::fmt::Display;
+use cqhci::CqeState;
 #[cfg(feature = "dma")]
 use dma_api::{DVec, Direction};
+use host::{RockchipDwcmshc, SdhciHostOps};
+use hotplug::Hotplug;
 use info::CardType;
-use log::{debug, info, trace};
+use irq::IrqState;
+use log::{debug, info, trace, warn};
+use platform::{Platform, Regulator};
+use recovery::RecoveryState;
+use sdio::SdioIrqTable;
+#[cfg(feature = "dma")]
+use spin::Mutex;
+use tuning::{TuningResult, TuningState};
 
 // SD Host Controller structure
 #[derive(Debug)]
@@ -38,6 +67,39 @@ pub struct EMmcHost {
     // clock: u32,
     host_caps: u32,
     version: u16,
+    sdio_irq: SdioIrqTable,
+    hotplug: Hotplug,
+    ops: &'static dyn SdhciHostOps,
+    chip_config: EMmcChipConfig,
+    // Board capabilities from the device tree, replacing the built-in defaults
+    board_caps: Option<u32>,
+    // Upper bound of the card clock, 0 if unlimited
+    max_freq: u32,
+    // Write protect is wired to a GPIO, the controller WP pin is not connected
+    wp_gpio: bool,
+    // Skip the SD and the SDIO card probe in init()
+    no_sd: bool,
+    no_sdio: bool,
+    // Supplies switched through `Platform::regulator_set`
+    vmmc_supply: bool,
+    vqmmc_supply: bool,
+    // Driver type preferred by the board
+    fixed_driver_type: Option<u8>,
+    // Take clock and driver strength from the Preset Value registers
+    preset_values: bool,
+    clk: HostClk,
+    platform: Option<&'static dyn Platform>,
+    irq: Arc<IrqState>,
+    // Controller interrupt line, and whether its handler is registered
+    irq_line: Option<u32>,
+    irq_requested: bool,
+    cqe: Option<CqeState>,
+    // Buffer of an async DMA request dropped before the controller was done
+    // with it, freed by `dma_settle()`
+    #[cfg(feature = "dma")]
+    dma_parked: Mutex<Option<ParkedBuffer>>,
+    recovery: RecoveryState,
+    tuning: TuningState,
 }
 
 impl Display for EMmcHost {
@@ -50,8 +112,22 @@ impl Display for EMmcHost {
     }
 }
 
+impl Drop for EMmcHost {
+    fn drop(&mut self) {
+        #[cfg(feature = "dma")]
+        self.dma_settle();
+        self.irq_release();
+    }
+}
+
 impl EMmcHost {
     pub fn new(base_addr: usize) -> Self {
+        Self::new_with_ops(base_addr, &RockchipDwcmshc)
+    }
+
+    /// Create a host driven through the given controller operations,
+    /// e.g. `&StandardSdhci` for a controller without vendor extensions
+    pub fn new_with_ops(base_addr: usize, ops: &'static dyn SdhciHostOps) -> Self {
         let mut host = Self {
             base_addr,
             card: None,
@@ -62,6 +138,29 @@ impl EMmcHost {
             // clock: 0,
             host_caps: 0,
             version: 0,
+            sdio_irq: SdioIrqTable::new(),
+            hotplug: Hotplug::new(),
+            ops,
+            chip_config: EMmcChipConfig::default(),
+            board_caps: None,
+            max_freq: 0,
+            wp_gpio: false,
+            no_sd: false,
+            no_sdio: false,
+            vmmc_supply: true,
+            vqmmc_supply: true,
+            fixed_driver_type: None,
+            preset_values: false,
+            clk: HostClk::new(),
+            platform: None,
+            irq: Arc::new(IrqState::new()),
+            irq_line: None,
+            irq_requested: false,
+            cqe: None,
+            #[cfg(feature = "dma")]
+            dma_parked: Mutex::new(None),
+            recovery: RecoveryState::default(),
+            tuning: TuningState::default(),
         };
 
         // Read capabilities
@@ -76,6 +175,16 @@ impl EMmcHost {
         host
     }
 
+    /// Select the SoC specific tuning profile, e.g. `EMmcChipConfig::rk3588_config()`
+    pub fn with_chip_config(mut self, config: EMmcChipConfig) -> Self {
+        self.chip_config = config;
+        self
+    }
+
+    pub fn chip_config(&self) -> &EMmcChipConfig {
+        &self.chip_config
+    }
+
     // 获取 card 的不可变引用
     pub fn card(&self) -> Option<&EMmcCard> {
         self.card.as_ref()
@@ -92,6 +201,11 @@ impl EMmcHost {
 
         // Create card structure
         self.add_card(EMmcCard::init(CardType::Unknown));
+        self.tuning.forget();
+
+        if self.clk.is_present() {
+            self.clk.enable()?;
+        }
 
         // Reset the controller
         self.reset(EMMC_RESET_ALL)?;
@@ -99,6 +213,11 @@ impl EMmcHost {
         let is_card_inserted = self.is_card_present();
         debug!("Card inserted: {}", is_card_inserted);
 
+        // A removable slot without a card has nothing to initialize
+        if self.hotplug_enabled() && !is_card_inserted {
+            return Err(SdError::NoCard);
+        }
+
         let version = self.read_reg16(EMMC_HOST_CNTRL_VER);
         // version = 4.2
         self.version = version;
@@ -134,13 +253,18 @@ impl EMmcHost {
         }
 
         self.host_caps = MMC_MODE_HS | MMC_MODE_HS_52MHZ | MMC_MODE_4BIT;
+        if let Some(caps) = self.board_caps {
+            self.host_caps = caps;
+        }
 
         if (version & EMMC_SPEC_VER_MASK) >= EMMC_SPEC_300 && (caps1 & EMMC_CAN_DO_8BIT) == 0 {
             self.host_caps &= !MMC_MODE_8BIT;
         }
 
         // 暂时写死
-        self.host_caps |= 0x48;
+        if self.board_caps.is_none() {
+            self.host_caps |= 0x48;
+        }
 
         // debug!("self.host_caps {:#x}", self.host_caps);
 
@@ -165,6 +289,18 @@ impl EMmcHost {
             generic_fls(voltages) - 1
         );
 
+        let microvolts = if voltages & (MMC_VDD_32_33 | MMC_VDD_33_34) != 0 {
+            3_300_000
+        } else if voltages & (MMC_VDD_29_30 | MMC_VDD_30_31) != 0 {
+            3_000_000
+        } else {
+            1_800_000
+        };
+        self.platform_set_regulator(Regulator::Vmmc, microvolts);
+        // Signalling starts out at the card supply again, undoing an earlier
+        // switch to 1.8 V
+        self.platform_set_regulator(Regulator::Vqmmc, microvolts);
+
         // Perform full power cycle
         self.sdhci_set_power(generic_fls(voltages) - 1)?;
 
@@ -174,14 +310,19 @@ impl EMmcHost {
             EMMC_INT_CMD_MASK | EMMC_INT_DATA_MASK,
         );
         self.write_reg(EMMC_SIGNAL_ENABLE, 0x0);
+        self.hotplug_arm_irq();
+        if !self.no_sdio {
+            self.sdio_arm_irq();
+        }
+        self.irq_arm();
 
         // Set initial bus width to 1-bit
-        self.mmc_set_bus_width(1);
+        self.mmc_set_bus_width(1)?;
 
         // Set initial clock and wait for it to stabilize
-        self.mmc_set_clock(400000);
+        self.mmc_set_clock(400000)?;
 
-        self.mmc_set_timing(MMC_TIMING_LEGACY);
+        self.mmc_set_timing(MMC_TIMING_LEGACY)?;
 
         // Initialize the card
         self.init_card()?;
@@ -190,55 +331,39 @@ impl EMmcHost {
         Ok(())
     }
 
-    // Reset the controller
-    pub fn reset(&self, mask: u8) -> Result<(), SdError> {
-        // Request reset
-        self.write_reg8(EMMC_SOFTWARE_RESET, mask);
-
-        // Wait for reset to complete with timeout
-        let mut timeout = 20; // Increased timeout
-        while (self.read_reg8(EMMC_SOFTWARE_RESET) & mask) != 0 {
-            if timeout == 0 {
-                return Err(SdError::Timeout);
-            }
-            timeout -= 1;
-            delay_us(1000);
-        }
-
-        Ok(())
-    }
-
-    // Check if card is present
-    fn is_card_present(&self) -> bool {
-        let state = self.read_reg(EMMC_PRESENT_STATE);
-        // debug!("EMMC Present State: {:#b}", state);
-        (state & EMMC_CARD_INSERTED) != 0 && ((state & EMMC_CARD_STABLE) != 0)
-    }
-
-    // Check if card is write protected
-    fn is_write_protected(&self) -> bool {
-        let state = self.read_reg(EMMC_PRESENT_STATE);
-        (state & EMMC_WRITE_PROTECT) != 0
-    }
-
     // Initialize the eMMC card
     fn init_card(&mut self) -> Result<(), SdError> {
         info!("eMMC initialization started");
 
+        // CMD0 leaves the I/O part of an SDIO card alone
+        if !self.no_sdio {
+            self.sdio_reset();
+        }
+
         // CMD0: Put card into idle state
         self.mmc_go_idle()?;
 
+        // SD cards answer CMD8 and SDIO cards CMD5, eMMC ignores both in the
+        // idle state. Only eMMC is brought up.
+        if !self.no_sd && self.sd_send_if_cond().is_ok() {
+            warn!("SD card found, only eMMC is supported");
+            return Err(SdError::UnsupportedCard);
+        }
+        if !self.no_sdio && self.sdio_send_op_cond().is_ok() {
+            warn!("SDIO card found, only eMMC is supported");
+            return Err(SdError::UnsupportedCard);
+        }
+
         // CMD1: Send operation condition (OCR) and wait for card ready
         let ocr = 0x00; // Voltage window: 2.7V to 3.6V
-        let retry = 100;
-        let ocr = self.mmc_send_op_cond(ocr, retry)?;
+        let ocr = self.mmc_send_op_cond(ocr)?;
 
         // Set RCA (Relative Card Address)
-        self.set_rca(1).unwrap();
+        self.set_rca(1)?;
 
         // Determine if card is high capacity (SDHC/SDXC/eMMC)
         let high_capacity = (ocr & OCR_HCS) == OCR_HCS;
-        self.set_high_capacity(high_capacity).unwrap();
+        self.set_high_capacity(high_capacity)?;
 
         // CMD2: Request CID (Card Identification)
         let _cid = self.mmc_all_send_cid()?;
@@ -250,7 +375,7 @@ impl EMmcHost {
         let csd = self.mmc_send_csd()?;
 
         // Determine card version from CSD if unknown
-        let card = self.card.as_mut().unwrap();
+        let card = self.card.as_mut().ok_or(SdError::NoCard)?;
         if card.version() == MMC_VERSION_UNKNOWN {
             let csd_version = (card.csd[0] >> 26) & 0xf;
             debug!("eMMC CSD version: {}", csd_version);
@@ -283,6 +408,7 @@ impl EMmcHost {
         };
 
         card.dsr_imp = dsr_imp;
+        card.timeouts.parse_csd(&csd);
 
         // Calculate user capacity
         let _tran_speed = freq * mult as usize;
@@ -305,12 +431,12 @@ impl EMmcHost {
 
         // CMD4: Set DSR if required by card
         let dsr_needed = {
-            let card = self.card.as_ref().unwrap();
+            let card = self.card.as_ref().ok_or(SdError::NoCard)?;
             dsr_imp != 0 && 0xffffffff != card.dsr
         };
         if dsr_needed {
             let dsr_value = {
-                let card = self.card.as_ref().unwrap();
+                let card = self.card.as_ref().ok_or(SdError::NoCard)?;
                 (card.dsr & 0xffff) << 16
             };
             let cmd4 = EMmcCommand::new(MMC_SET_DSR, dsr_value, MMC_RSP_NONE);
@@ -319,30 +445,36 @@ impl EMmcHost {
 
         // CMD7: Select the card
         let rca = {
-            let card = self.card.as_ref().unwrap();
+            let card = self.card.as_ref().ok_or(SdError::NoCard)?;
             card.rca
         };
         let cmd7 = EMmcCommand::new(MMC_SELECT_CARD, rca << 16, MMC_RSP_R1);
         self.send_command(&cmd7, None)?;
         debug!("cmd7: {:#x}", self.get_response().as_r1());
 
+        // A locked card still completes identification, but rejects data access
+        // until it is unlocked with CMD42
+        if self.refresh_lock_status()? {
+            warn!("Card is locked, data access requires unlock()");
+        }
+
         // Set initial erase group size and partition config
-        self.set_erase_grp_size(1).unwrap();
-        self.set_part_config(MMCPART_NOAVAILABLE).unwrap();
+        self.set_erase_grp_size(1)?;
+        self.set_part_config(MMCPART_NOAVAILABLE)?;
 
         // For eMMC 4.0+, configure high-speed, EXT_CSD and partitions
         let is_version_4_plus = {
-            let card = self.card.as_ref().unwrap();
+            let card = self.card.as_ref().ok_or(SdError::NoCard)?;
             card.version >= MMC_VERSION_4
         };
         if is_version_4_plus {
             self.mmc_select_hs()?; // Switch to high speed
-            self.mmc_set_clock(MMC_HIGH_52_MAX_DTR); // Set high-speed clock
+            self.mmc_set_clock(MMC_HIGH_52_MAX_DTR)?; // Set high-speed clock
 
             // Allocate buffer for EXT_CSD read
             cfg_if::cfg_if! {
                 if #[cfg(feature = "dma")] {
-                    let mut ext_csd: DVec<u8> = DVec::zeros(MMC_MAX_BLOCK_LEN as usize, 0x1000, Direction::FromDevice).unwrap();
+                    let mut ext_csd: DVec<u8> = DVec::zeros(MMC_MAX_BLOCK_LEN as usize, 0x1000, Direction::FromDevice).ok_or(SdError::MemoryError)?;
                 } else if #[cfg(feature = "pio")] {
                     let mut ext_csd: [u8; 512] = [0; 512];
                 }
@@ -353,6 +485,12 @@ impl EMmcHost {
             let mut ext_csd = ext_csd.to_vec();
             trace!("EXT_CSD: {:?}", ext_csd);
 
+            // Operation timeouts
+            let card = self.card.as_mut().ok_or(SdError::NoCard)?;
+            card.timeouts.parse_ext_csd(&ext_csd);
+            card.power_classes.parse_ext_csd(&ext_csd);
+            debug!("Card timeouts: {:?}", card.timeouts);
+
             // Extract capacity and version
             if ext_csd[EXT_CSD_REV as usize] >= 2 {
                 let mut capacity: u64 = ext_csd[EXT_CSD_SEC_CNT as usize] as u64
@@ -361,34 +499,46 @@ impl EMmcHost {
                     | (ext_csd[EXT_CSD_SEC_CNT as usize + 3] as u64) << 24;
                 capacity *= MMC_MAX_BLOCK_LEN as u64;
                 if (capacity >> 20) > 2 * 1024 {
-                    self.set_capacity_user(capacity).unwrap();
+                    self.set_capacity_user(capacity)?;
                 }
+            }
 
-                let card = self.card.as_mut().unwrap();
-                match ext_csd[EXT_CSD_REV as usize] {
-                    1 => card.version = MMC_VERSION_4_1,
-                    2 => card.version = MMC_VERSION_4_2,
-                    3 => card.version = MMC_VERSION_4_3,
-                    5 => card.version = MMC_VERSION_4_41,
-                    6 => card.version = MMC_VERSION_4_5,
-                    7 => card.version = MMC_VERSION_5_0,
-                    8 => card.version = MMC_VERSION_5_1,
-                    _ => panic!("Unknown EXT_CSD revision"),
+            // Revisions newer than the driver knows are run as the newest known one
+            let rev = ext_csd[EXT_CSD_REV as usize];
+            let card = self.card.as_mut().ok_or(SdError::NoCard)?;
+            card.ext_csd_rev = rev;
+            card.version = match rev {
+                0 => MMC_VERSION_4,
+                1 => MMC_VERSION_4_1,
+                2 => MMC_VERSION_4_2,
+                3 => MMC_VERSION_4_3,
+                4 => {
+                    warn!("Obsolete EXT_CSD revision 4, treating the card as eMMC 4.3");
+                    MMC_VERSION_4_3
                 }
-            }
+                5 => MMC_VERSION_4_41,
+                6 => MMC_VERSION_4_5,
+                7 => MMC_VERSION_5_0,
+                8 => MMC_VERSION_5_1,
+                _ => {
+                    warn!(
+                        "Unknown EXT_CSD revision {}, treating the card as eMMC 5.1",
+                        rev
+                    );
+                    MMC_VERSION_5_1
+                }
+            };
 
             // Parse partition configuration info
             let part_completed = (ext_csd[EXT_CSD_PARTITION_SETTING as usize] as u32
                 & EXT_CSD_PARTITION_SETTING_COMPLETED)
                 != 0;
-            self.set_part_support(ext_csd[EXT_CSD_PARTITIONING_SUPPORT as usize])
-                .unwrap();
+            self.set_part_support(ext_csd[EXT_CSD_PARTITIONING_SUPPORT as usize])?;
 
             if (ext_csd[EXT_CSD_PARTITIONING_SUPPORT as usize] as u32 & PART_SUPPORT != 0)
                 || ext_csd[EXT_CSD_BOOT_MULT as usize] != 0
             {
-                self.set_part_config(ext_csd[EXT_CSD_PART_CONF as usize])
-                    .unwrap();
+                self.set_part_config(ext_csd[EXT_CSD_PART_CONF as usize])?;
             }
 
             // Save enhanced partition attributes
@@ -396,19 +546,23 @@ impl EMmcHost {
                 && (ext_csd[EXT_CSD_PARTITIONING_SUPPORT as usize] as u32 & ENHNCD_SUPPORT != 0)
             {
                 let part_attr = ext_csd[EXT_CSD_PARTITIONS_ATTRIBUTE as usize];
-                self.set_part_attr(part_attr).unwrap();
+                self.set_part_attr(part_attr)?;
             }
 
             // Check secure erase support
-            if ext_csd[EXT_CSD_SEC_FEATURE_SUPPORT as usize] as u32 & EXT_CSD_SEC_GB_CL_EN != 0 {
-                let _mmc_can_trim = 1;
-            }
+            let can_trim =
+                ext_csd[EXT_CSD_SEC_FEATURE_SUPPORT as usize] as u32 & EXT_CSD_SEC_GB_CL_EN != 0;
+            self.set_can_trim(can_trim)?;
+
+            // The driver never touches CACHE_CTRL, so the value read here holds
+            // until the next init
+            self.set_cache_ctrl(ext_csd[EXT_CSD_CACHE_CTRL as usize] & 0x1 != 0)?;
 
             // Calculate boot and RPMB sizes
             let capacity_boot = (ext_csd[EXT_CSD_BOOT_MULT as usize] as u64) << 17;
-            self.set_capacity_boot(capacity_boot).unwrap();
+            self.set_capacity_boot(capacity_boot)?;
             let capacity_rpmb = (ext_csd[EXT_CSD_RPMB_MULT as usize] as u64) << 17;
-            self.set_capacity_rpmb(capacity_rpmb).unwrap();
+            self.set_capacity_rpmb(capacity_rpmb)?;
             debug!("Boot partition size: {:#x}", capacity_boot);
             debug!("RPMB partition size: {:#x}", capacity_rpmb);
 
@@ -429,7 +583,7 @@ impl EMmcHost {
                 capacity_gp[i] *= ext_csd[EXT_CSD_HC_ERASE_GRP_SIZE as usize] as u64;
                 capacity_gp[i] *= ext_csd[EXT_CSD_HC_WP_GRP_SIZE as usize] as u64;
                 capacity_gp[i] <<= 19;
-                self.set_capacity_gp(capacity_gp).unwrap();
+                self.set_capacity_gp(capacity_gp)?;
             }
             debug!("GP partition sizes: {:?}", capacity_gp);
 
@@ -442,7 +596,7 @@ impl EMmcHost {
                 enh_user_size *= ext_csd[EXT_CSD_HC_ERASE_GRP_SIZE as usize] as u64;
                 enh_user_size *= ext_csd[EXT_CSD_HC_WP_GRP_SIZE as usize] as u64;
                 enh_user_size <<= 19;
-                self.set_enh_user_size(enh_user_size).unwrap();
+                self.set_enh_user_size(enh_user_size)?;
 
                 let mut enh_user_start = ((ext_csd[EXT_CSD_ENH_START_ADDR as usize + 3] as u64)
                     << 24)
@@ -452,7 +606,7 @@ impl EMmcHost {
                 if high_capacity {
                     enh_user_start <<= 9;
                 }
-                self.set_enh_user_start(enh_user_start).unwrap();
+                self.set_enh_user_start(enh_user_start)?;
             }
 
             // If partitions are configured, enable ERASE_GRP_DEF
@@ -479,41 +633,41 @@ impl EMmcHost {
             if ext_csd[EXT_CSD_ERASE_GROUP_DEF as usize] & 0x01 != 0 {
                 self.set_erase_grp_size(
                     (ext_csd[EXT_CSD_HC_ERASE_GRP_SIZE as usize] as u32) * 1024,
-                )
-                .unwrap();
+                )?;
 
                 if high_capacity && part_completed {
                     let capacity = (ext_csd[EXT_CSD_SEC_CNT as usize] as u64)
                         | ((ext_csd[EXT_CSD_SEC_CNT as usize + 1] as u64) << 8)
                         | ((ext_csd[EXT_CSD_SEC_CNT as usize + 2] as u64) << 16)
                         | ((ext_csd[EXT_CSD_SEC_CNT as usize + 3] as u64) << 24);
-                    self.set_capacity_user(capacity * (MMC_MAX_BLOCK_LEN as u64))
-                        .unwrap();
+                    self.set_capacity_user(capacity * (MMC_MAX_BLOCK_LEN as u64))?;
                 }
             } else {
                 let erase_gsz = (csd[2] & 0x00007c00) >> 10;
                 let erase_gmul = (csd[2] & 0x000003e0) >> 5;
-                self.set_erase_grp_size((erase_gsz + 1) * (erase_gmul + 1))
-                    .unwrap();
+                self.set_erase_grp_size((erase_gsz + 1) * (erase_gmul + 1))?;
             }
 
             // Set high-capacity write-protect group size
             let hc_wp_grp_size = 1024
                 * (ext_csd[EXT_CSD_HC_ERASE_GRP_SIZE as usize] as u64)
                 * (ext_csd[EXT_CSD_HC_WP_GRP_SIZE as usize] as u64);
-            self.set_hc_wp_grp_size(hc_wp_grp_size).unwrap();
+            self.set_hc_wp_grp_size(hc_wp_grp_size)?;
 
             // Set write reliability and drive strength
-            self.set_wr_rel_set(ext_csd[EXT_CSD_WR_REL_SET as usize])
-                .unwrap();
-            self.set_raw_driver_strength(ext_csd[EXT_CSD_DRIVER_STRENGTH as usize])
-                .unwrap();
+            self.set_wr_rel_set(ext_csd[EXT_CSD_WR_REL_SET as usize])?;
+            self.set_wr_rel_param(ext_csd[EXT_CSD_WR_REL_PARAM as usize])?;
+            self.set_rel_wr_sec_c(ext_csd[EXT_CSD_REL_WR_SEC_C as usize])?;
+            self.set_raw_driver_strength(ext_csd[EXT_CSD_DRIVER_STRENGTH as usize])?;
         }
 
         // Final initialization steps
         self.mmc_set_capacity(0)?;
         self.mmc_change_freq()?;
-        self.set_initialized(true).unwrap();
+        if let Err(err) = self.mmc_select_power_class() {
+            warn!("Power class not selected: {}", err);
+        }
+        self.set_initialized(true)?;
 
         Ok(())
     }
@@ -522,21 +676,20 @@ impl EMmcHost {
         // part_num 暂时设置为 0
         match part_num {
             0 => match self.capacity_user() {
-                Some(capacity_user) => self.set_capacity(capacity_user).unwrap(),
+                Some(capacity_user) => self.set_capacity(capacity_user)?,
                 None => return Err(SdError::InvalidArgument),
             },
             1 | 2 => match self.capacity_boot() {
-                Some(capacity_boot) => self.set_capacity(capacity_boot).unwrap(),
+                Some(capacity_boot) => self.set_capacity(capacity_boot)?,
                 None => return Err(SdError::InvalidArgument),
             },
             3 => match self.capacity_rpmb() {
-                Some(capacity_rpmb) => self.set_capacity(capacity_rpmb).unwrap(),
+                Some(capacity_rpmb) => self.set_capacity(capacity_rpmb)?,
                 None => return Err(SdError::InvalidArgument),
             },
             4..=7 => match self.capacity_gp() {
                 Some(capacity_gp) => self
-                    .set_capacity(capacity_gp[(part_num - 4) as usize])
-                    .unwrap(),
+                    .set_capacity(capacity_gp[(part_num - 4) as usize])?,
                 None => return Err(SdError::InvalidArgument),
             },
             _ => return Err(SdError::InvalidArgument),
@@ -553,14 +706,14 @@ impl EMmcHost {
         // Allocate buffer for EXT_CSD depending on whether DMA or PIO is enabled
         cfg_if::cfg_if! {
             if #[cfg(feature = "dma")] {
-                let mut ext_csd: DVec<u8> = DVec::zeros(MMC_MAX_BLOCK_LEN as usize, 0x1000, Direction::FromDevice).unwrap();
+                let mut ext_csd: DVec<u8> = DVec::zeros(MMC_MAX_BLOCK_LEN as usize, 0x1000, Direction::FromDevice).ok_or(SdError::MemoryError)?;
             } else if #[cfg(feature = "pio")] {
                 let mut ext_csd: [u8; 512] = [0; 512];
             }
         }
 
         // Initialize card capabilities flags
-        self.set_card_caps(0).unwrap();
+        self.set_card_caps(0)?;
 
         // Get card version (default to 0 if not available)
         let version = self.version().unwrap_or(0);
@@ -571,7 +724,7 @@ impl EMmcHost {
         }
 
         // Enable both 4-bit and 8-bit modes on the card
-        self.set_card_caps(MMC_MODE_4BIT | MMC_MODE_8BIT).unwrap();
+        self.set_card_caps(MMC_MODE_4BIT | MMC_MODE_8BIT)?;
 
         // Read the EXT_CSD register from the card
         self.mmc_send_ext_csd(&mut ext_csd)?;
@@ -594,7 +747,7 @@ impl EMmcHost {
         result?;
 
         // Configure the bus speed according to selected type
-        self.mmc_set_bus_speed(avail_type as u32);
+        self.mmc_set_bus_speed(avail_type as u32)?;
 
         // If HS200 mode was selected, perform tuning procedure
         if self.mmc_card_hs200() {
@@ -605,7 +758,7 @@ impl EMmcHost {
                 && self.bus_width().unwrap_or(0) == MMC_BUS_WIDTH_8BIT
             {
                 // self.mmc_select_hs400()?; // Currently not executed
-                self.mmc_set_bus_speed(avail_type as u32);
+                self.mmc_set_bus_speed(avail_type as u32)?;
             }
 
             tuning_result
@@ -618,9 +771,9 @@ impl EMmcHost {
                 Err(SdError::BusWidth)
             };
 
-            // If DDR52 mode is supported, implement selection (currently TODO)
+            // DDR52 is not selected yet, such cards stay in SDR high speed
             if err.is_ok() && avail_type & EXT_CSD_CARD_TYPE_DDR_52 as u16 != 0 {
-                todo!("Implement HS-DDR selection");
+                debug!("HS-DDR not supported by the driver, staying in HS");
             }
 
             err
@@ -630,7 +783,7 @@ impl EMmcHost {
         }
     }
 
-    pub fn mmc_set_bus_speed(&mut self, avail_type: u32) {
+    pub fn mmc_set_bus_speed(&mut self, avail_type: u32) -> Result<(), SdError> {
         let mut clock = 0;
 
         if self.mmc_card_hs() {
@@ -643,23 +796,23 @@ impl EMmcHost {
             clock = MMC_HS200_MAX_DTR;
         }
 
-        self.mmc_set_clock(clock);
+        self.mmc_set_clock(clock)
     }
 
     /// 检查卡是否为HS模式
     fn mmc_card_hs(&self) -> bool {
-        let timing = self.timing().unwrap();
+        let timing = self.timing().unwrap_or(MMC_TIMING_LEGACY);
         (timing == MMC_TIMING_MMC_HS) || (timing == MMC_TIMING_SD_HS)
     }
 
     fn mmc_card_hs400es(&self) -> bool {
-        let timing = self.timing().unwrap();
+        let timing = self.timing().unwrap_or(MMC_TIMING_LEGACY);
         timing == MMC_TIMING_MMC_HS400ES
     }
 
     /// 检查卡是否为HS200模式
     fn mmc_card_hs200(&self) -> bool {
-        let timing = self.timing().unwrap();
+        let timing = self.timing().unwrap_or(MMC_TIMING_LEGACY);
         timing == MMC_TIMING_MMC_HS200
     }
 
@@ -667,14 +820,21 @@ impl EMmcHost {
         let ret = self.mmc_select_bus_width()?;
 
         if ret > 0 {
+            let driver_type = self.mmc_select_driver_type(MMC_TIMING_MMC_HS200);
             self.mmc_switch(
                 EXT_CSD_CMD_SET_NORMAL,
                 EXT_CSD_HS_TIMING,
-                EXT_CSD_TIMING_HS200,
+                EXT_CSD_TIMING_HS200 | driver_type << EXT_CSD_DRV_STR_SHIFT,
                 false,
             )?;
 
-            self.mmc_set_timing(MMC_TIMING_MMC_HS200);
+            self.card.as_mut().ok_or(SdError::NoCard)?.driver_type = driver_type;
+            self.mmc_set_timing(MMC_TIMING_MMC_HS200)?;
+
+            // The switch went out without CMD13, check it now that the host
+            // runs the new timing. SWITCH_ERROR fails the status command.
+            let timeout_us = self.card_timeouts().switch_us(EXT_CSD_HS_TIMING);
+            self.mmc_poll_for_busy(true, timeout_us)?;
         }
 
         Ok(())
@@ -686,7 +846,7 @@ impl EMmcHost {
 
         cfg_if::cfg_if! {
             if #[cfg(feature = "dma")] {
-                let mut ext_csd: DVec<u8> = DVec::zeros(MMC_MAX_BLOCK_LEN as usize, 0x1000, Direction::FromDevice).unwrap();
+                let mut ext_csd: DVec<u8> = DVec::zeros(MMC_MAX_BLOCK_LEN as usize, 0x1000, Direction::FromDevice).ok_or(SdError::MemoryError)?;
                 let mut test_csd = DVec::zeros(MMC_MAX_BLOCK_LEN as usize, 0x1000, Direction::FromDevice)
         .ok_or(SdError::MemoryError)?;
             } else if #[cfg(feature = "pio")] {
@@ -723,7 +883,7 @@ impl EMmcHost {
             }
 
             let bus_width = bus_widths[idx];
-            self.mmc_set_bus_width(bus_width);
+            self.mmc_set_bus_width(bus_width)?;
 
             // 再次读取EXT_CSD进行验证
             let test_result = self.mmc_send_ext_csd(&mut test_csd);
@@ -775,7 +935,7 @@ impl EMmcHost {
     /// Perform HS200 tuning sequence (also used for HS400 initial tuning)
     fn mmc_hs200_tuning(&mut self) -> Result<(), SdError> {
         let opcode = MMC_SEND_TUNING_BLOCK_HS200;
-        let timing = self.timing().unwrap();
+        let timing = self.timing().unwrap_or(MMC_TIMING_LEGACY);
 
         match timing {
             // HS400 tuning must be issued in HS200 mode; reject direct HS400 timing
@@ -792,20 +952,28 @@ impl EMmcHost {
             }
         }
 
+        self.tune(opcode)?;
+        Ok(())
+    }
+
+    /// Standard SDHCI tuning driven by the EXEC_TUNING bit
+    pub fn sdhci_execute_tuning(&mut self, opcode: u8) -> Result<TuningResult, SdError> {
         // Set the EXEC_TUNING bit in Host Control2 to start tuning
         let mut ctrl = self.read_reg16(EMMC_HOST_CTRL2);
         ctrl |= MMC_CTRL_EXEC_TUNING;
         self.write_reg16(EMMC_HOST_CTRL2, ctrl);
 
         // Invoke the common tuning loop implementation
-        self.__emmc_execute_tuning(opcode)
+        let commands = self.__emmc_execute_tuning(opcode)?;
+        Ok(self.hardware_tuning_result(opcode, commands))
     }
 
-    /// Core tuning loop: send tuning blocks until the controller indicates success or timeout
-    fn __emmc_execute_tuning(&mut self, opcode: u8) -> Result<(), SdError> {
-        const MAX_TUNING_LOOP: usize = 40;
+    /// Core tuning loop: send tuning blocks until the controller indicates success or timeout.
+    /// Returns the number of tuning blocks sent.
+    fn __emmc_execute_tuning(&mut self, opcode: u8) -> Result<u32, SdError> {
+        const MAX_TUNING_LOOP: u32 = 40;
 
-        for _ in 0..MAX_TUNING_LOOP {
+        for sent in 1..=MAX_TUNING_LOOP {
             // Send one tuning block command
             self.emmc_send_tuning(opcode)?;
 
@@ -816,7 +984,7 @@ impl EMmcHost {
             if (ctrl & MMC_CTRL_EXEC_TUNING) == 0 {
                 // ...and the TUNED_CLK bit is set, tuning succeeded
                 if (ctrl & MMC_CTRL_TUNED_CLK) != 0 {
-                    return Ok(());
+                    return Ok(sent);
                 }
                 // EXEC_TUNING cleared but no TUNED_CLK => break and report failure
                 break;
@@ -833,7 +1001,7 @@ impl EMmcHost {
         let make_blksz = |dma: u16, blksz: u16| ((dma & 0x7) << 12) | (blksz & 0x0FFF);
 
         // Determine current bus width (1/4/8 bits)
-        let bus_width = self.bus_width().unwrap();
+        let bus_width = self.bus_width().ok_or(SdError::NoCard)?;
 
         // Choose block size: 128 bytes for HS200 on 8-bit bus, else 64 bytes
         let block_size = if opcode == MMC_SEND_TUNING_BLOCK_HS200 && bus_width == MMC_BUS_WIDTH_8BIT
@@ -857,7 +1025,7 @@ impl EMmcHost {
 
     #[allow(unused)]
     fn mmc_card_ddr(&self) -> bool {
-        let timing = self.timing().unwrap();
+        let timing = self.timing().unwrap_or(MMC_TIMING_LEGACY);
         (timing == MMC_TIMING_UHS_DDR50)
             || (timing == MMC_TIMING_MMC_DDR52)
             || (timing == MMC_TIMING_MMC_HS400)
@@ -961,32 +1129,36 @@ impl EMmcHost {
         );
 
         if ret.is_ok() {
-            self.mmc_set_timing(MMC_TIMING_MMC_HS);
+            self.mmc_set_timing(MMC_TIMING_MMC_HS)?;
         }
 
         ret
     }
 
-    fn mmc_set_bus_width(&mut self, width: u8) {
+    fn mmc_set_bus_width(&mut self, width: u8) -> Result<(), SdError> {
         /* Set bus width */
-        let card = self.card.as_mut().unwrap();
+        let card = self.card.as_mut().ok_or(SdError::NoCard)?;
         card.bus_width = width;
         debug!("Bus width set to {}", width);
-        self.sdhci_set_ios();
+        self.sdhci_set_ios()
     }
 
-    fn mmc_set_timing(&mut self, timing: u32) {
+    fn mmc_set_timing(&mut self, timing: u32) -> Result<(), SdError> {
         /* Set timing */
-        let card = self.card.as_mut().unwrap();
+        let card = self.card.as_mut().ok_or(SdError::NoCard)?;
         card.timing = timing;
-        self.sdhci_set_ios();
+        self.sdhci_set_ios()
     }
 
-    fn mmc_set_clock(&mut self, clk: u32) {
+    fn mmc_set_clock(&mut self, mut clk: u32) -> Result<(), SdError> {
+        if self.max_freq != 0 {
+            clk = clk.min(self.max_freq);
+        }
+
         /* Set clock */
-        let card = self.card.as_mut().unwrap();
+        let card = self.card.as_mut().ok_or(SdError::NoCard)?;
         card.clock = clk;
-        self.sdhci_set_ios();
+        self.sdhci_set_ios()
     }
 
     fn mmc_switch(
@@ -997,29 +1169,32 @@ impl EMmcHost {
         send_status: bool,
     ) -> Result<(), SdError> {
         let mut retries = 3;
+        let timeout_us = self.card_timeouts().switch_us(index);
         let cmd = EMmcCommand::new(
             MMC_SWITCH,
             (MMC_SWITCH_MODE_WRITE_BYTE << 24)
                 | (index << 16)
                 | ((value as u32) << 8),
             MMC_RSP_R1B,
-        );
+        )
+        .with_busy_timeout(timeout_us);
 
         loop {
-            let ret = self.send_command(&cmd, None);
-
-            if ret.is_ok() {
-                debug!("cmd6 {:#x}", self.get_response().as_r1());
-                return self.mmc_poll_for_busy(send_status);
-            }
+            let err = match self.send_command(&cmd, None) {
+                Ok(()) => {
+                    debug!("cmd6 {:#x}", self.get_response().as_r1());
+                    return self.mmc_poll_for_busy(send_status, timeout_us);
+                }
+                // The card rejected the switch, sending it again changes nothing
+                Err(err) if matches!(err.cause(), SdError::CardStatus(_)) => return Err(err),
+                Err(err) => err,
+            };
 
             retries -= 1;
             if retries <= 0 {
-                debug!("Switch command failed after 3 retries");
-                break;
+                debug!("Switch command failed after 3 retries: {}", err);
+                return Err(err);
             }
         }
-
-        Err(SdError::Timeout)
     }
 }
@@ -0,0 +1,162 @@
+// ===== Platform Services =====
+
+use alloc::boxed::Box;
+use core::{fmt, ptr::NonNull};
+
+use log::warn;
+
+use crate::err::SdError;
+
+use super::EMmcHost;
+
+/// Supplies of the card slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Regulator {
+    /// Card power (vmmc)
+    Vmmc,
+    /// I/O signalling voltage (vqmmc)
+    Vqmmc,
+}
+
+/// Board GPIO lines of the card slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpioLine {
+    CardDetect,
+    WriteProtect,
+    /// eMMC hardware reset (RST_n), active low
+    Reset,
+}
+
+/// DMA capable memory returned by `Platform::dma_alloc`
+#[derive(Debug, Clone, Copy)]
+pub struct DmaRegion {
+    pub cpu_addr: NonNull<u8>,
+    /// Address the controller uses to reach the memory
+    pub bus_addr: u64,
+    pub size: usize,
+}
+
+// Only describes memory owned by whoever allocated it, the region itself
+// is never dereferenced without the owner's synchronization
+unsafe impl Send for DmaRegion {}
+unsafe impl Sync for DmaRegion {}
+
+pub type IrqHandler = Box<dyn Fn() + Send + Sync>;
+
+/// Services the driver needs from the kernel or RTOS it runs in.
+/// Only the monotonic clock is mandatory, the other services default to
+/// "not available" and the driver falls back to polling or leaves them alone.
+pub trait Platform: fmt::Debug + Send + Sync {
+    /// Monotonic time in microseconds
+    fn now_us(&self) -> u64;
+
+    fn delay_us(&self, us: u64) {
+        let start = self.now_us();
+        while self.now_us().wrapping_sub(start) < us {
+            self.yield_now();
+        }
+    }
+
+    /// Give the CPU to other tasks while the driver busy-waits
+    fn yield_now(&self) {
+        core::hint::spin_loop();
+    }
+
+    fn dma_alloc(&self, _size: usize, _align: usize) -> Option<DmaRegion> {
+        None
+    }
+
+    fn dma_free(&self, _region: DmaRegion) {}
+
+    /// Write back CPU caches before the controller reads the memory
+    fn dma_clean(&self, _addr: NonNull<u8>, _size: usize) {}
+
+    /// Drop stale cache lines after the controller has written the memory
+    fn dma_invalidate(&self, _addr: NonNull<u8>, _size: usize) {}
+
+    fn request_irq(&self, _irq: u32, _handler: IrqHandler) -> Result<(), SdError> {
+        Err(SdError::NotSupported)
+    }
+
+    fn free_irq(&self, _irq: u32) {}
+
+    /// Set a supply to `microvolts`, 0 switches it off
+    fn regulator_set(&self, _supply: Regulator, _microvolts: u32) -> Result<(), SdError> {
+        Err(SdError::NotSupported)
+    }
+
+    /// Level of an input line, `None` if the board has no such GPIO
+    fn gpio_get(&self, _line: GpioLine) -> Option<bool> {
+        None
+    }
+
+    fn gpio_set(&self, _line: GpioLine, _high: bool) -> Result<(), SdError> {
+        Err(SdError::NotSupported)
+    }
+}
+
+impl EMmcHost {
+    /// Route time, DMA, interrupt, regulator and GPIO services through `platform`
+    pub fn with_platform(mut self, platform: &'static dyn Platform) -> Self {
+        self.platform = Some(platform);
+        self
+    }
+
+    pub fn platform(&self) -> Option<&'static dyn Platform> {
+        self.platform
+    }
+
+    // Sleep `us` through the platform, or the `kernel` hook without one.
+    // NotSupported if there is no time source at all.
+    pub(crate) fn delay_us(&self, us: u64) -> Result<(), SdError> {
+        if let Some(platform) = self.platform {
+            platform.delay_us(us);
+            return Ok(());
+        }
+
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "kernel")] {
+                crate::delay_us(us);
+                Ok(())
+            } else {
+                let _ = us;
+                Err(SdError::NotSupported)
+            }
+        }
+    }
+
+    // Apply a supply voltage if the board has a regulator for it and the
+    // platform controls it
+    pub(crate) fn platform_set_regulator(&self, supply: Regulator, microvolts: u32) {
+        let wired = match supply {
+            Regulator::Vmmc => self.vmmc_supply,
+            Regulator::Vqmmc => self.vqmmc_supply,
+        };
+        let (Some(platform), true) = (self.platform, wired) else {
+            return;
+        };
+
+        match platform.regulator_set(supply, microvolts) {
+            Ok(()) | Err(SdError::NotSupported) => {}
+            Err(e) => warn!("{:?} set to {} uV failed: {:?}", supply, microvolts, e),
+        }
+    }
+
+    pub(crate) fn platform_gpio_get(&self, line: GpioLine) -> Option<bool> {
+        self.platform.and_then(|platform| platform.gpio_get(line))
+    }
+
+    /// Pulse RST_n to hardware reset the eMMC.
+    /// The card only honours it with RST_n_FUNCTION enabled in EXT_CSD.
+    pub fn hw_reset(&self) -> Result<(), SdError> {
+        let platform = self.platform.ok_or(SdError::NotSupported)?;
+
+        // tRSTW >= 1us, tRSCA >= 200us
+        platform.gpio_set(GpioLine::Reset, false)?;
+        self.delay_us(10)?;
+        platform.gpio_set(GpioLine::Reset, true)?;
+        self.delay_us(300)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+// ===== Preset Values =====
+
+use log::{debug, info, warn};
+
+use crate::err::SdError;
+
+use super::{EMmcHost, constant::*};
+
+impl EMmcHost {
+    /// Let the controller take the clock divider and driver strength of each
+    /// bus mode from its Preset Value registers. Only used on SDHCI 3.00 and
+    /// later, and only for modes whose preset gives a clock no faster than
+    /// the card clock asked for.
+    pub fn with_preset_values(mut self, enable: bool) -> Self {
+        self.preset_values = enable;
+        self
+    }
+
+    // Preset values are wanted and the controller has them
+    pub(crate) fn preset_values_supported(&self) -> bool {
+        self.preset_values && (self.version & EMMC_SPEC_VER_MASK) >= EMMC_SPEC_300
+    }
+
+    /// Preset Value register the controller uses in `timing`, `None` in
+    /// legacy timing where presets stay off
+    pub fn sdhci_preset_value(&self, timing: u32) -> Option<u16> {
+        let reg = match timing {
+            MMC_TIMING_LEGACY => return None,
+            MMC_TIMING_MMC_HS | MMC_TIMING_SD_HS => EMMC_PRESET_HS,
+            MMC_TIMING_UHS_SDR25 => EMMC_PRESET_SDR25,
+            MMC_TIMING_UHS_SDR50 => EMMC_PRESET_SDR50,
+            MMC_TIMING_UHS_SDR104 | MMC_TIMING_MMC_HS200 => EMMC_PRESET_SDR104,
+            MMC_TIMING_UHS_DDR50 | MMC_TIMING_MMC_DDR52 => EMMC_PRESET_DDR50,
+            MMC_TIMING_MMC_HS400 | MMC_TIMING_MMC_HS400ES => EMMC_PRESET_HS400,
+            _ => EMMC_PRESET_SDR12,
+        };
+        Some(self.read_reg16(reg))
+    }
+
+    // Hand clock and driver strength back to the driver, before set_ios
+    // programs them for a new mode
+    pub(crate) fn sdhci_preset_off(&self) {
+        let ctrl = self.read_reg16(EMMC_HOST_CTRL2);
+        if ctrl & MMC_CTRL_PRESET_VAL_ENABLE != 0 {
+            self.write_reg16(EMMC_HOST_CTRL2, ctrl & !MMC_CTRL_PRESET_VAL_ENABLE);
+        }
+    }
+
+    // Switch to the presets of `timing` once the UHS mode is selected, then
+    // set the clock again so it comes from the preset
+    pub(crate) fn sdhci_preset_on(&mut self, timing: u32, clock: u32) -> Result<(), SdError> {
+        if !self.preset_values_supported() {
+            return Ok(());
+        }
+        let Some(preset) = self.sdhci_preset_value(timing) else {
+            return Ok(());
+        };
+
+        // An all-zero preset was never programmed by the vendor
+        if preset == 0 {
+            debug!("No preset value for timing {}", timing);
+            return Ok(());
+        }
+
+        // The host would drive with the preset type, the card has to match
+        let driver_type = ((preset & EMMC_PRESET_DRV_MASK) >> EMMC_PRESET_DRV_SHIFT) as u8;
+        let card_type = self.card.as_ref().map_or(0, |card| card.driver_type);
+        if matches!(
+            timing,
+            MMC_TIMING_MMC_HS200 | MMC_TIMING_MMC_HS400 | MMC_TIMING_MMC_HS400ES
+        ) && driver_type != card_type
+        {
+            warn!(
+                "Preset driver type {} does not match card type {}, presets off",
+                driver_type, card_type
+            );
+            return Ok(());
+        }
+
+        let ctrl = self.read_reg16(EMMC_HOST_CTRL2);
+        self.write_reg16(EMMC_HOST_CTRL2, ctrl | MMC_CTRL_PRESET_VAL_ENABLE);
+
+        self.set_clock(clock)
+    }
+
+    // Clock Control value from the preset of the current mode, if presets are
+    // on and the preset clock does not exceed `freq`. A preset that fails the
+    // check turns presets off so the divider is computed instead.
+    pub(crate) fn sdhci_preset_clock(&self, freq: u32, input_clk: u32) -> Option<u16> {
+        if self.read_reg16(EMMC_HOST_CTRL2) & MMC_CTRL_PRESET_VAL_ENABLE == 0 {
+            return None;
+        }
+
+        let timing = self
+            .card
+            .as_ref()
+            .map_or(MMC_TIMING_LEGACY, |card| card.timing);
+        let preset = self.sdhci_preset_value(timing)?;
+        let div = preset & EMMC_PRESET_SDCLK_FREQ_MASK;
+
+        let caps2 = self.read_reg(EMMC_CAPABILITIES2);
+        let programmable = preset & EMMC_PRESET_CLKGEN_SEL != 0
+            && (caps2 & EMMC_CLOCK_MUL_MASK) >> EMMC_CLOCK_MUL_SHIFT != 0;
+
+        let (mut clk, actual) = if programmable {
+            (EMMC_PROG_CLOCK_MODE, input_clk / (div as u32 + 1))
+        } else if div == 0 {
+            (0, input_clk)
+        } else {
+            (0, input_clk / (2 * div as u32))
+        };
+
+        if actual == 0 || actual > freq {
+            warn!(
+                "Preset clock {} Hz for {} Hz requested, presets off",
+                actual, freq
+            );
+            self.sdhci_preset_off();
+            return None;
+        }
+        info!("Preset clock {} Hz for {} Hz requested", actual, freq);
+
+        clk |= (div & 0xFF) << EMMC_DIVIDER_SHIFT;
+        clk |= ((div & 0x300) >> 8) << EMMC_DIVIDER_HI_SHIFT;
+        Some(clk)
+    }
+}
@@ -0,0 +1,369 @@
+// ===== Error Recovery =====
+
+use log::{debug, info, warn};
+
+use crate::err::{ErrorOrigin, SdError};
+
+use super::{EMmcHost, cmd::EMmcCommand, constant::*, status::CardState, status::CardStatus};
+
+/// How failed transfers are recovered by `EMmcHost::run_with_recovery`
+#[derive(Debug, Clone, Copy)]
+pub struct RecoveryPolicy {
+    retries: u32,
+    downgrade_after: u32,
+    retune: bool,
+    reinit: bool,
+}
+
+impl Default for RecoveryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            downgrade_after: 2,
+            retune: true,
+            reinit: true,
+        }
+    }
+}
+
+impl RecoveryPolicy {
+    /// Attempts after the first failure, 0 returns the first error
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Consecutive CRC errors before the bus speed or width is lowered, 0 never
+    pub fn with_downgrade_after(mut self, crc_errors: u32) -> Self {
+        self.downgrade_after = crc_errors;
+        self
+    }
+
+    /// Repeat HS200 tuning after a CRC error
+    pub fn with_retune(mut self, enable: bool) -> Self {
+        self.retune = enable;
+        self
+    }
+
+    /// Re-initialize the card once all retries have failed
+    pub fn with_reinit(mut self, enable: bool) -> Self {
+        self.reinit = enable;
+        self
+    }
+}
+
+/// Recovery steps taken since the host was created
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RecoveryStats {
+    pub crc_errors: u32,
+    pub retries: u32,
+    pub aborts: u32,
+    pub retunes: u32,
+    pub downgrades: u32,
+    pub reinits: u32,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RecoveryState {
+    policy: RecoveryPolicy,
+    stats: RecoveryStats,
+    // CRC errors since the last successful transfer
+    crc_streak: u32,
+}
+
+// Bus setup carried over a re-initialization
+struct BusConfig {
+    timing: u32,
+    bus_width: u8,
+    clock: u32,
+    part_config: u8,
+}
+
+impl EMmcHost {
+    /// Recover failed transfers according to `policy`
+    pub fn with_recovery(mut self, policy: RecoveryPolicy) -> Self {
+        self.recovery.policy = policy;
+        self
+    }
+
+    pub fn recovery_stats(&self) -> RecoveryStats {
+        self.recovery.stats
+    }
+
+    /// Run `op`, and after a bus error bring the card back to the transfer
+    /// state and try again. CRC errors lead to retuning and, when they keep
+    /// coming, a slower or narrower bus. Once the retries are used up the
+    /// card is re-initialized with its partition and bus setup and `op` gets
+    /// one last attempt. Errors reported by the card or the host are returned
+    /// right away.
+    pub fn run_with_recovery<T>(
+        &mut self,
+        mut op: impl FnMut(&mut Self) -> Result<T, SdError>,
+    ) -> Result<T, SdError> {
+        let policy = self.recovery.policy;
+        let mut attempt = 0;
+        let mut reinit_done = false;
+
+        loop {
+            let err = match op(self) {
+                Ok(value) => {
+                    self.recovery.crc_streak = 0;
+                    return Ok(value);
+                }
+                Err(err) => err,
+            };
+
+            if err.origin() != ErrorOrigin::Bus || matches!(err.cause(), SdError::NoCard) {
+                return Err(err);
+            }
+            warn!("Transfer failed: {}, recovering", err);
+
+            let crc = matches!(err.cause(), SdError::Crc | SdError::DataCrc);
+            if crc {
+                self.recovery.crc_streak += 1;
+                self.recovery.stats.crc_errors += 1;
+                if policy.retune {
+                    self.request_retune();
+                }
+            }
+
+            attempt += 1;
+            let recovered = attempt <= policy.retries
+                && self
+                    .recovery_step(crc)
+                    .inspect_err(|e| debug!("Recovery step failed: {}", e))
+                    .is_ok();
+
+            if !recovered {
+                if !policy.reinit || reinit_done {
+                    return Err(err);
+                }
+
+                reinit_done = true;
+                if let Err(reinit_err) = self.recovery_reinit() {
+                    warn!("Re-initialization failed: {}", reinit_err);
+                    return Err(err);
+                }
+                // One last attempt on the fresh card
+                attempt = policy.retries;
+            }
+
+            self.recovery.stats.retries += 1;
+        }
+    }
+
+    // Abort, then retune or downgrade after CRC errors
+    fn recovery_step(&mut self, crc: bool) -> Result<(), SdError> {
+        let policy = self.recovery.policy;
+
+        self.recovery_abort()?;
+
+        if !crc {
+            return Ok(());
+        }
+
+        if self.retune_needed() {
+            self.recovery.stats.retunes += 1;
+            self.retune()?;
+        }
+
+        if policy.downgrade_after != 0 && self.recovery.crc_streak >= policy.downgrade_after {
+            self.recovery.crc_streak = 0;
+            self.recovery_downgrade()?;
+        }
+
+        Ok(())
+    }
+
+    /// Reset the CMD and DAT lines and bring the card back to the transfer
+    /// state, stopping a transfer it is still in
+    pub fn recovery_abort(&mut self) -> Result<(), SdError> {
+        self.recovery.stats.aborts += 1;
+
+        self.reset(EMMC_RESET_CMD)?;
+        self.reset(EMMC_RESET_DATA)?;
+
+        let status = self.recovery_status()?;
+        debug!("Card status after error: {}", status);
+
+        match status.state() {
+            CardState::Tran => return Ok(()),
+            CardState::Data | CardState::Rcv => {
+                // The stop response may carry errors of the aborted transfer,
+                // CMD13 below tells whether it worked
+                let timeout_us = self.data_timeout_us(true, 1);
+                let cmd = EMmcCommand::new(MMC_STOP_TRANSMISSION, 0, MMC_RSP_R1B)
+                    .with_busy_timeout(timeout_us);
+                let _ = self.send_command(&cmd, None);
+                self.mmc_poll_for_busy(true, timeout_us)?;
+            }
+            CardState::Prg => {
+                self.mmc_poll_for_busy(true, self.data_timeout_us(true, 1))?;
+            }
+            CardState::Stby => {
+                let rca = self.card.as_ref().ok_or(SdError::NoCard)?.rca;
+                let cmd = EMmcCommand::new(MMC_SELECT_CARD, rca << 16, MMC_RSP_R1);
+                self.send_command(&cmd, None)?;
+            }
+            state => {
+                debug!("Card in {:?}, cannot abort", state);
+                return Err(SdError::InvalidResponse);
+            }
+        }
+
+        let status = self.recovery_status()?;
+        if status.state() != CardState::Tran {
+            debug!("Card still in {:?} after abort", status.state());
+            return Err(SdError::InvalidResponse);
+        }
+
+        Ok(())
+    }
+
+    // CMD13, accepting the error bits left over from the failed transfer
+    fn recovery_status(&self) -> Result<CardStatus, SdError> {
+        match self.get_status() {
+            Ok(status) => Ok(status),
+            Err(err) => match err.cause() {
+                SdError::CardStatus(status) => Ok(*status),
+                _ => Err(err),
+            },
+        }
+    }
+
+    // Step down one level: HS200/HS400 to HS at 52 MHz, then 26 MHz, then
+    // 8 to 4 to 1 data lines
+    fn recovery_downgrade(&mut self) -> Result<(), SdError> {
+        let card = self.card.as_ref().ok_or(SdError::NoCard)?;
+        let (timing, bus_width, clock) = (card.timing, card.bus_width, card.clock);
+
+        let next = match timing {
+            MMC_TIMING_MMC_HS200 | MMC_TIMING_MMC_HS400 | MMC_TIMING_MMC_HS400ES => {
+                (MMC_TIMING_MMC_HS, bus_width, MMC_HIGH_52_MAX_DTR)
+            }
+            _ if clock > MMC_HIGH_26_MAX_DTR => (timing, bus_width, MMC_HIGH_26_MAX_DTR),
+            _ if bus_width == MMC_BUS_WIDTH_8BIT => (timing, MMC_BUS_WIDTH_4BIT, clock),
+            _ if bus_width == MMC_BUS_WIDTH_4BIT => (timing, MMC_BUS_WIDTH_1BIT, clock),
+            _ => return Err(SdError::NotSupported),
+        };
+
+        info!(
+            "Lowering bus after CRC errors: timing {} -> {}, width {} -> {}, clock {} -> {}",
+            timing, next.0, bus_width, next.1, clock, next.2
+        );
+        self.recovery.stats.downgrades += 1;
+        self.recovery_apply_bus(next.0, next.1, next.2)
+    }
+
+    // Move the card and host to a bus setup no faster than the current one
+    fn recovery_apply_bus(
+        &mut self,
+        timing: u32,
+        bus_width: u8,
+        clock: u32,
+    ) -> Result<(), SdError> {
+        let card = self.card.as_ref().ok_or(SdError::NoCard)?;
+        let (cur_timing, cur_width) = (card.timing, card.bus_width);
+
+        if timing != cur_timing {
+            let (value, driver_type) = match timing {
+                MMC_TIMING_MMC_HS => (EXT_CSD_TIMING_HS, 0),
+                MMC_TIMING_MMC_HS200 => (EXT_CSD_TIMING_HS200, self.mmc_select_driver_type(timing)),
+                _ => (EXT_CSD_TIMING_BC, 0),
+            };
+            // Lower the clock first so the host never runs ahead of the card
+            self.mmc_set_clock(clock.min(card_clock_limit(timing)))?;
+            self.mmc_switch(
+                EXT_CSD_CMD_SET_NORMAL,
+                EXT_CSD_HS_TIMING,
+                value | driver_type << EXT_CSD_DRV_STR_SHIFT,
+                true,
+            )?;
+            self.card.as_mut().ok_or(SdError::NoCard)?.driver_type = driver_type;
+            self.mmc_set_timing(timing)?;
+            self.tuning.forget();
+        }
+
+        if bus_width != cur_width {
+            let value = match bus_width {
+                MMC_BUS_WIDTH_8BIT => EXT_CSD_BUS_WIDTH_8,
+                MMC_BUS_WIDTH_4BIT => EXT_CSD_BUS_WIDTH_4,
+                _ => EXT_CSD_BUS_WIDTH_1,
+            };
+            self.mmc_switch(EXT_CSD_CMD_SET_NORMAL, EXT_CSD_BUS_WIDTH, value, true)?;
+            self.mmc_set_bus_width(bus_width)?;
+        }
+
+        self.mmc_set_clock(clock)
+    }
+
+    /// Re-initialize the card, then restore the partition it had selected,
+    /// any slower bus setup chosen by earlier recovery and the command queue.
+    /// Tasks still queued are dropped without being reported.
+    pub fn recovery_reinit(&mut self) -> Result<(), SdError> {
+        let card = self.card.as_ref().ok_or(SdError::NoCard)?;
+        let saved = BusConfig {
+            timing: card.timing,
+            bus_width: card.bus_width,
+            clock: card.clock,
+            part_config: card.part_config,
+        };
+
+        // CMD0 takes the card out of command queue mode, the engine has to
+        // stop before and is started again once the card is back
+        let cqe_enabled = self.cqe_enabled();
+        self.cqe_abort();
+
+        self.recovery.stats.reinits += 1;
+        info!("Re-initializing card");
+        self.init()?;
+
+        let card = self.card.as_ref().ok_or(SdError::NoCard)?;
+        if bus_rank(saved.timing, saved.bus_width, saved.clock)
+            < bus_rank(card.timing, card.bus_width, card.clock)
+        {
+            self.recovery_apply_bus(saved.timing, saved.bus_width, saved.clock)?;
+        }
+
+        let card = self.card.as_ref().ok_or(SdError::NoCard)?;
+        if saved.part_config != MMCPART_NOAVAILABLE
+            && card.part_config != MMCPART_NOAVAILABLE
+            && card.part_config != saved.part_config
+        {
+            self.mmc_switch(
+                EXT_CSD_CMD_SET_NORMAL,
+                EXT_CSD_PART_CONF,
+                saved.part_config,
+                true,
+            )?;
+            if let Some(card) = self.card.as_mut() {
+                card.part_config = saved.part_config;
+            }
+        }
+
+        if cqe_enabled {
+            self.cqe_enable()?;
+        }
+
+        Ok(())
+    }
+}
+
+// Highest clock allowed in `timing`
+fn card_clock_limit(timing: u32) -> u32 {
+    match timing {
+        MMC_TIMING_MMC_HS200 | MMC_TIMING_MMC_HS400 | MMC_TIMING_MMC_HS400ES => MMC_HS200_MAX_DTR,
+        MMC_TIMING_MMC_HS => MMC_HIGH_52_MAX_DTR,
+        _ => MMC_HIGH_26_MAX_DTR,
+    }
+}
+
+// Order of bus setups for comparison, timing first, then clock and width
+fn bus_rank(timing: u32, bus_width: u8, clock: u32) -> (u32, u32, u8) {
+    let timing = match timing {
+        MMC_TIMING_MMC_HS200 | MMC_TIMING_MMC_HS400 | MMC_TIMING_MMC_HS400ES => 2,
+        MMC_TIMING_MMC_HS => 1,
+        _ => 0,
+    };
+    (timing, clock, bus_width)
+}
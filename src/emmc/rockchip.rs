@@ -1,89 +1,162 @@
+use core::fmt;
+
 use super::{EMmcHost, constant::*};
-use crate::{
-    delay_us,
-    emmc::{aux::dll_lock_wo_tmout, clock::emmc_set_clk, config::EMmcChipConfig},
-    err::SdError,
-};
+use crate::{emmc::aux::dll_lock_wo_tmout, err::SdError};
 use log::{debug, info};
 
-impl EMmcHost {
-    // Rockchip EMMC设置时钟函数
-    pub fn rockchip_emmc_set_clock(&mut self, freq: u32) -> Result<(), SdError> {
-        // wait for command and data inhibit to be cleared
-        let mut timeout = 20;
-        while (self.read_reg(EMMC_PRESENT_STATE) & (EMMC_CMD_INHIBIT | EMMC_DATA_INHIBIT)) != 0 {
-            if timeout == 0 {
-                debug!("Timeout waiting for cmd & data inhibit");
-                return Err(SdError::Timeout);
-            }
-            timeout -= 1;
-            delay_us(1000);
-        }
+use super::{host::SdhciHostOps, platform::Regulator, tuning::widest_window};
 
-        // first disable the clock
-        self.write_reg16(EMMC_CLOCK_CONTROL, 0x0000);
+// The DLL is bypassed below this card clock
+const DLL_MIN_FREQ: u32 = 100_000_000;
+// Delay taps of each DLL line
+const DLL_TAPS: u8 = 32;
 
-        if freq == 0 {
-            return Ok(());
+/// Synopsys DesignWare Mobile Storage Host Controller as integrated on
+/// Rockchip SoCs, with the eMMC PHY DLL in the vendor register area
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RockchipDwcmshc;
+
+impl SdhciHostOps for RockchipDwcmshc {
+    fn set_clock(&self, host: &mut EMmcHost, freq: u32) -> Result<(), SdError> {
+        host.dwcmshc_sdhci_emmc_set_clock(freq)
+    }
+
+    fn set_uhs_signaling(&self, host: &mut EMmcHost) -> Result<(), SdError> {
+        host.sdhci_set_uhs_signaling()?;
+
+        // DWCMSHC uses a vendor encoding for HS400 in the UHS mode select
+        let timing = host.card.as_ref().ok_or(SdError::NoCard)?.timing;
+        if timing == MMC_TIMING_MMC_HS400 || timing == MMC_TIMING_MMC_HS400ES {
+            let mut ctrl_2 = host.read_reg16(EMMC_HOST_CTRL2);
+            ctrl_2 &= !MMC_CTRL_UHS_MASK;
+            ctrl_2 |= DWCMSHC_CTRL_HS400;
+            host.write_reg16(EMMC_HOST_CTRL2, ctrl_2);
         }
 
-        // 计算输入时钟
-        let input_clk = emmc_set_clk(freq as u64).unwrap() as u32;
-        info!("input_clk: {}", input_clk);
+        Ok(())
+    }
 
-        let mut div = 0;
-        let mut clk = 0u16;
-        let sdhci_version = self.read_reg16(EMMC_HOST_CNTRL_VER);
+    fn reset(&self, host: &EMmcHost, mask: u8) -> Result<(), SdError> {
+        host.sdhci_reset(mask)?;
 
-        if (sdhci_version & 0xFF) >= EMMC_SPEC_300 {
-            let caps2 = self.read_reg(EMMC_CAPABILITIES2);
-            let clk_mul = (caps2 & EMMC_CLOCK_MUL_MASK) >> EMMC_CLOCK_MUL_SHIFT;
+        // The PHY DLL lives outside the SDHCI register set and survives a
+        // software reset, leave it disabled until the next clock change
+        if mask & EMMC_RESET_ALL != 0 {
+            host.write_reg(DWCMSHC_EMMC_DLL_CTRL, 0);
+        }
 
-            info!("EMMC Clock Mul: {}", clk_mul);
+        Ok(())
+    }
 
-            // Check if the Host Controller supports Programmable Clock Mode.
-            if clk_mul != 0 {
-                for i in 1..=1024 {
-                    if (input_clk / i) <= freq {
-                        div = i;
-                        break;
-                    }
-                }
-                // Set Programmable Clock Mode in the Clock Control register.
-                clk = EMMC_PROG_CLOCK_MODE;
-                div -= 1;
-            } else {
-                // Version 3.00 divisors must be a multiple of 2.
-                if input_clk <= freq {
-                    div = 1;
-                } else {
-                    for i in (2..=2046).step_by(2) {
-                        if (input_clk / i) <= freq {
-                            div = i;
-                            break;
-                        }
-                    }
-                }
-                div >>= 1;
-            }
+    fn hs400_enhanced_strobe(&self, host: &mut EMmcHost, enable: bool) -> Result<(), SdError> {
+        let mut extra = host.read_reg(DWCMSHC_EMMC_CONTROL);
+        if enable {
+            extra |= DWCMSHC_ENHANCED_STROBE;
         } else {
-            // Version 2.00 divisors must be a power of 2.
-            let mut i = 1;
-            while i < 256 && (input_clk / i) > freq {
-                i *= 2;
-            }
-            div = i >> 1;
+            extra &= !DWCMSHC_ENHANCED_STROBE;
+        }
+        host.write_reg(DWCMSHC_EMMC_CONTROL, extra);
+
+        Ok(())
+    }
+
+    fn tuning_taps(&self, host: &EMmcHost) -> u8 {
+        if host.dwcmshc_dll_active() {
+            DLL_TAPS
+        } else {
+            0
         }
+    }
 
-        info!("EMMC Clock Divisor: 0x{:x}", div);
+    fn set_tuning_tap(&self, host: &mut EMmcHost, tap: u8) -> Result<(), SdError> {
+        host.dwcmshc_set_dll_tap(DllLine::RxClk, tap)
+    }
+}
 
-        clk |= ((div as u16) & 0xFF) << EMMC_DIVIDER_SHIFT;
-        clk |= (((div as u16) & 0x300) >> 8) << EMMC_DIVIDER_HI_SHIFT;
+/// Delay line of the DWCMSHC eMMC PHY DLL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DllLine {
+    /// Receive clock sampling CMD and DAT
+    RxClk,
+    /// Transmit clock, `hs200_tx_tap` and `hs400_tx_tap`
+    TxClk,
+    /// Data strobe, `hs400_strbin_tap`, only used while the card runs HS400
+    Strobe,
+}
 
-        self.write_reg16(EMMC_CLOCK_CONTROL, clk);
-        self.enable_card_clock(clk)?;
+impl DllLine {
+    fn reg(self) -> u32 {
+        match self {
+            DllLine::RxClk => DWCMSHC_EMMC_DLL_RXCLK,
+            DllLine::TxClk => DWCMSHC_EMMC_DLL_TXCLK,
+            DllLine::Strobe => DWCMSHC_EMMC_DLL_STRBIN,
+        }
+    }
+}
 
-        Ok(())
+/// Transfer checked at each tap of a sweep
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SweepProbe {
+    /// CMD21 tuning block compared with the JEDEC pattern, HS200 only
+    TuningBlock,
+    /// CMD8 compared with the EXT_CSD read before the sweep
+    ExtCsd,
+}
+
+/// Pass/fail map of a DLL tap sweep
+#[derive(Debug, Clone, Copy)]
+pub struct TapSweep {
+    pub line: DllLine,
+    pub probe: SweepProbe,
+    /// Card clock during the sweep
+    pub clock: u32,
+    pub taps: u8,
+    /// Bit n set if the probe passed at tap n
+    pub pass_map: u32,
+    /// First tap and length of the widest passing window
+    pub window_start: u8,
+    pub window_len: u8,
+}
+
+impl TapSweep {
+    pub fn passed(&self, tap: u8) -> bool {
+        tap < self.taps && self.pass_map & (1 << tap) != 0
+    }
+
+    /// Middle of the widest passing window, `None` if no tap passed
+    pub fn best_tap(&self) -> Option<u8> {
+        (self.window_len != 0).then_some(self.window_start + self.window_len / 2)
+    }
+}
+
+// One character per tap, '#' passed and '.' failed
+impl fmt::Display for TapSweep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} {:?} at {} Hz: ", self.line, self.probe, self.clock)?;
+        for tap in 0..self.taps {
+            f.write_str(if self.passed(tap) { "#" } else { "." })?;
+        }
+        match self.best_tap() {
+            Some(tap) => write!(f, " best {}", tap),
+            None => write!(f, " no passing tap"),
+        }
+    }
+}
+
+impl EMmcHost {
+    // Rockchip EMMC设置时钟函数
+    pub fn rockchip_emmc_set_clock(&mut self, freq: u32) -> Result<(), SdError> {
+        self.sdhci_clock_off()?;
+
+        if freq == 0 {
+            return Ok(());
+        }
+
+        // 计算输入时钟
+        let input_clk = self.clk.set_rate(freq as u64)? as u32;
+        info!("input_clk: {}", input_clk);
+
+        self.sdhci_enable_clock(freq, input_clk)
     }
 
     pub fn enable_card_clock(&mut self, mut clk: u16) -> Result<(), SdError> {
@@ -91,15 +164,10 @@ impl EMmcHost {
         clk &= !EMMC_CLOCK_INT_STABLE;
         self.write_reg16(EMMC_CLOCK_CONTROL, clk);
 
-        let mut timeout = 20;
-        while (self.read_reg16(EMMC_CLOCK_CONTROL) & EMMC_CLOCK_INT_STABLE) == 0 {
-            timeout -= 1;
-            delay_us(1000);
-            if timeout == 0 {
-                info!("Internal clock never stabilised.");
-                return Err(SdError::Timeout);
-            }
-        }
+        self.poll_until(20_000, 1000, || {
+            self.read_reg16(EMMC_CLOCK_CONTROL) & EMMC_CLOCK_INT_STABLE != 0
+        })
+        .inspect_err(|_| info!("Internal clock never stabilised."))?;
 
         self.write_reg16(EMMC_CLOCK_CONTROL, clk | EMMC_CLOCK_CARD_EN);
 
@@ -145,16 +213,15 @@ impl EMmcHost {
         info!("EMMC Power Control: {:#x}", self.read_reg8(EMMC_POWER_CTRL));
 
         // Small delay for power to stabilize
-        delay_us(10000);
+        self.delay_us(10000)?;
 
         Ok(())
     }
 
     // DWCMSHC SDHCI EMMC设置时钟
     pub fn dwcmshc_sdhci_emmc_set_clock(&mut self, freq: u32) -> Result<(), SdError> {
-        let mut timeout = 500;
-        let timing = self.card.as_ref().unwrap().timing;
-        let data = EMmcChipConfig::rk3568_config();
+        let timing = self.card.as_ref().ok_or(SdError::NoCard)?.timing;
+        let data = self.chip_config;
 
         self.rockchip_emmc_set_clock(freq)?;
         // Disable output clock while config DLL
@@ -166,10 +233,10 @@ impl EMmcHost {
         );
 
         // DLL配置基于频率
-        if freq >= 100_000_000 {
+        if freq >= DLL_MIN_FREQ {
             // Enable DLL
             self.write_reg(DWCMSHC_EMMC_DLL_CTRL, DWCMSHC_EMMC_DLL_CTRL_RESET);
-            delay_us(1000);
+            self.delay_us(1000)?;
             self.write_reg(DWCMSHC_EMMC_DLL_CTRL, 0);
             let mut extra = 0x1 << 16 | 0x2 << 17 | 0x3 << 19;
             self.write_reg(DWCMSHC_EMMC_ATCTRL, extra);
@@ -180,19 +247,10 @@ impl EMmcHost {
                 | DWCMSHC_EMMC_DLL_START;
             self.write_reg(DWCMSHC_EMMC_DLL_CTRL, extra);
 
-            loop {
-                if timeout <= 0 {
-                    info!("Timeout waiting for DLL to be ready");
-                    return Err(SdError::Timeout);
-                }
-
-                if dll_lock_wo_tmout(self.read_reg(DWCMSHC_EMMC_DLL_STATUS0)) {
-                    break;
-                }
-
-                delay_us(1000);
-                timeout -= 1;
-            }
+            self.poll_until(500_000, 1000, || {
+                dll_lock_wo_tmout(self.read_reg(DWCMSHC_EMMC_DLL_STATUS0))
+            })
+            .inspect_err(|_| info!("Timeout waiting for DLL to be ready"))?;
 
             let dll_lock_value = ((self.read_reg(DWCMSHC_EMMC_DLL_STATUS0) & 0xFF) * 2) & 0xFF;
 
@@ -261,10 +319,9 @@ impl EMmcHost {
             // Before switching to hs400es mode, the driver
             // will enable enhanced strobe first. PHY needs to
             // configure the parameters of enhanced strobe first.
-            let ddr50_strbin_delay_num = 16;
             let extra = DWCMSHC_EMMC_DLL_DLYENA
                 | DLL_STRBIN_DELAY_NUM_SEL
-                | (ddr50_strbin_delay_num << DLL_STRBIN_DELAY_NUM_OFFSET);
+                | ((data.ddr50_strbin_delay_num as u32) << DLL_STRBIN_DELAY_NUM_OFFSET);
             // info!("extra: {:#b}", extra);
             self.write_reg(DWCMSHC_EMMC_DLL_STRBIN, extra);
         }
@@ -277,11 +334,11 @@ impl EMmcHost {
         Ok(())
     }
 
-    pub fn sdhci_set_uhs_signaling(&self) {
-        let timing = self.card.as_ref().unwrap().timing;
+    pub fn sdhci_set_uhs_signaling(&self) -> Result<(), SdError> {
+        let timing = self.card.as_ref().ok_or(SdError::NoCard)?.timing;
 
         let mut ctrl_2 = self.read_reg16(EMMC_HOST_CTRL2);
-        ctrl_2 &= !MMC_CTRL_UHS_MASK;
+        ctrl_2 &= !(MMC_CTRL_UHS_MASK | MMC_CTRL_DRV_TYPE_MAS);
 
         if (timing != MMC_TIMING_LEGACY)
             && (timing != MMC_TIMING_MMC_HS)
@@ -291,7 +348,7 @@ impl EMmcHost {
         }
 
         if (timing == MMC_TIMING_MMC_HS200) || (timing == MMC_TIMING_UHS_SDR104) {
-            ctrl_2 |= MMC_CTRL_UHS_SDR104 | MMC_CTRL_DRV_TYPE_A;
+            ctrl_2 |= MMC_CTRL_UHS_SDR104 | self.host_driver_type_ctrl();
         } else if timing == MMC_TIMING_UHS_SDR12 {
             ctrl_2 |= MMC_CTRL_UHS_SDR12;
         } else if timing == MMC_TIMING_UHS_SDR25 {
@@ -301,17 +358,19 @@ impl EMmcHost {
         } else if (timing == MMC_TIMING_UHS_DDR50) || (timing == MMC_TIMING_MMC_DDR52) {
             ctrl_2 |= MMC_CTRL_UHS_DDR50;
         } else if timing == MMC_TIMING_MMC_HS400 || timing == MMC_TIMING_MMC_HS400ES {
-            ctrl_2 |= MMC_CTRL_HS400 | MMC_CTRL_DRV_TYPE_A;
+            ctrl_2 |= MMC_CTRL_HS400 | self.host_driver_type_ctrl();
         }
 
         debug!("EMMC Host Control 2: {:#x}", ctrl_2);
 
         self.write_reg16(EMMC_HOST_CTRL2, ctrl_2);
+
+        Ok(())
     }
 
-    pub fn sdhci_set_ios(&mut self) {
+    pub fn sdhci_set_ios(&mut self) -> Result<(), SdError> {
         let (card_clock, bus_width, timing) = {
-            let card = self.card.as_ref().unwrap();
+            let card = self.card.as_ref().ok_or(SdError::NoCard)?;
             (card.clock, card.bus_width, card.timing)
         };
 
@@ -320,7 +379,8 @@ impl EMmcHost {
             card_clock, bus_width, timing
         );
 
-        self.dwcmshc_sdhci_emmc_set_clock(card_clock).unwrap();
+        self.sdhci_preset_off();
+        self.set_clock(card_clock)?;
 
         /* Set bus width */
         let mut ctrl = self.read_reg8(EMMC_HOST_CTRL1);
@@ -356,13 +416,132 @@ impl EMmcHost {
 
         if timing != MMC_TIMING_LEGACY && timing != MMC_TIMING_MMC_HS && timing != MMC_TIMING_SD_HS
         {
-            self.sdhci_set_power(MMC_VDD_165_195_SHIFT).unwrap();
+            self.platform_set_regulator(Regulator::Vqmmc, 1_800_000);
+            self.sdhci_set_power(MMC_VDD_165_195_SHIFT)?;
         }
 
-        self.sdhci_set_uhs_signaling();
+        self.set_uhs_signaling()?;
+        self.sdhci_preset_on(timing, card_clock)?;
+
+        Ok(())
     }
 
     fn sdhci_get_version(&self) -> u16 {
         self.read_reg16(EMMC_HOST_CNTRL_VER) & 0xFF
     }
+
+    // The DLL is started and not bypassed, i.e. the card clock is 100 MHz or more
+    fn dwcmshc_dll_active(&self) -> bool {
+        let ctrl = self.read_reg(DWCMSHC_EMMC_DLL_CTRL);
+        ctrl & DWCMSHC_EMMC_DLL_START != 0 && ctrl & DWCMSHC_EMMC_DLL_BYPASS == 0
+    }
+
+    /// Take `line` out of DLL control and delay it by `tap`. The next clock
+    /// change reprograms the taps of the chip config.
+    pub fn dwcmshc_set_dll_tap(&mut self, line: DllLine, tap: u8) -> Result<(), SdError> {
+        if tap >= DLL_TAPS {
+            return Err(SdError::InvalidArgument);
+        }
+        if !self.dwcmshc_dll_active() {
+            debug!("DLL bypassed, no taps to set");
+            return Err(SdError::NotSupported);
+        }
+
+        let from_sw = match line {
+            DllLine::RxClk => DLL_RXCLK_TAPNUM_FROM_SW,
+            DllLine::TxClk => DLL_TXCLK_TAPNUM_FROM_SW,
+            DllLine::Strobe => DLL_STRBIN_TAPNUM_FROM_SW,
+        };
+        let mut extra = self.read_reg(line.reg()) & !DLL_TAPNUM_MASK;
+        extra |= DWCMSHC_EMMC_DLL_DLYENA | from_sw | tap as u32;
+
+        // Gate the card clock while the delay changes
+        let clk = self.read_reg16(EMMC_CLOCK_CONTROL);
+        self.write_reg16(EMMC_CLOCK_CONTROL, clk & !EMMC_CLOCK_CARD_EN);
+        self.write_reg(line.reg(), extra);
+        self.write_reg16(EMMC_CLOCK_CONTROL, clk);
+
+        Ok(())
+    }
+
+    /// Run `probe` at every tap of `line` with the card clock at `freq`, for
+    /// choosing the taps of a new board. The card has to be initialized in
+    /// HS200, or HS400 for the strobe, and `freq` be at least 100 MHz for the
+    /// DLL to run. The clock and taps of the chip config are restored after,
+    /// and the card is re-tuned before its next transfer.
+    pub fn dwcmshc_dll_sweep(
+        &mut self,
+        line: DllLine,
+        freq: u32,
+        probe: SweepProbe,
+    ) -> Result<TapSweep, SdError> {
+        if freq < DLL_MIN_FREQ {
+            return Err(SdError::InvalidArgument);
+        }
+        if probe == SweepProbe::TuningBlock && !self.mmc_card_hs200() {
+            return Err(SdError::InvalidArgument);
+        }
+
+        let saved_clock = self.card.as_ref().ok_or(SdError::NoCard)?.clock;
+
+        self.mmc_set_clock(freq)?;
+        if line == DllLine::RxClk {
+            // The tuned clock would sample in place of the RxClk tap
+            let ctrl = self.read_reg16(EMMC_HOST_CTRL2);
+            self.write_reg16(
+                EMMC_HOST_CTRL2,
+                ctrl & !(MMC_CTRL_EXEC_TUNING | MMC_CTRL_TUNED_CLK),
+            );
+        }
+        let sweep = self.dwcmshc_sweep_taps(line, probe);
+        self.mmc_set_clock(saved_clock)?;
+        // The sampling point of the tuning is gone with the sweep
+        self.request_retune();
+
+        let sweep = sweep?;
+        info!("DLL sweep {}", sweep);
+        Ok(sweep)
+    }
+
+    fn dwcmshc_sweep_taps(
+        &mut self,
+        line: DllLine,
+        probe: SweepProbe,
+    ) -> Result<TapSweep, SdError> {
+        let opcode = MMC_SEND_TUNING_BLOCK_HS200;
+        let pattern = self.tuning_pattern(opcode)?;
+        let reference = match probe {
+            SweepProbe::ExtCsd => Some(self.mmc_read_ext_csd()?),
+            SweepProbe::TuningBlock => None,
+        };
+
+        let mut pass_map = 0u32;
+        for tap in 0..DLL_TAPS {
+            self.dwcmshc_set_dll_tap(line, tap)?;
+
+            let passed = match &reference {
+                None => self.tuning_tap_passes(opcode, pattern)?,
+                Some(reference) => self
+                    .mmc_read_ext_csd()
+                    .is_ok_and(|ext_csd| ext_csd == *reference),
+            };
+            debug!("{:?} tap {}: {}", line, tap, passed);
+
+            if passed {
+                pass_map |= 1 << tap;
+            }
+        }
+
+        let (window_start, window_len) = widest_window(pass_map as u128, DLL_TAPS);
+
+        Ok(TapSweep {
+            line,
+            probe,
+            clock: self.card.as_ref().map_or(0, |card| card.clock),
+            taps: DLL_TAPS,
+            pass_map,
+            window_start,
+            window_len,
+        })
+    }
 }
@@ -0,0 +1,247 @@
+// ===== SDIO Card Interrupt Support =====
+
+use alloc::boxed::Box;
+use core::fmt;
+
+use log::{debug, trace, warn};
+
+use crate::err::SdError;
+
+use super::{EMmcHost, cmd::EMmcCommand, constant::*};
+
+/// Handler invoked when an SDIO function raises its interrupt.
+/// The argument is the function number (1..=7).
+pub type SdioIrqHandler = Box<dyn Fn(u8) + Send + Sync>;
+
+// Per-function SDIO interrupt handlers, indexed by function number - 1
+pub struct SdioIrqTable {
+    handlers: [Option<SdioIrqHandler>; SDIO_MAX_FUNCS],
+}
+
+impl SdioIrqTable {
+    pub const fn new() -> Self {
+        Self {
+            handlers: [None, None, None, None, None, None, None],
+        }
+    }
+
+    // Bitmap of registered functions in CCCR IENx layout (bit N = function N)
+    fn enabled_mask(&self) -> u8 {
+        self.handlers
+            .iter()
+            .enumerate()
+            .filter(|(_, h)| h.is_some())
+            .fold(0, |mask, (i, _)| mask | (1 << (i + 1)))
+    }
+}
+
+impl Default for SdioIrqTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for SdioIrqTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "SdioIrqTable {{ enabled: {:#04x} }}",
+            self.enabled_mask()
+        )
+    }
+}
+
+impl EMmcHost {
+    /// Send CMD52 (IO_RW_DIRECT) and return the data byte from the R5 response
+    pub fn sdio_rw_direct(
+        &self,
+        write: bool,
+        func: u8,
+        addr: u32,
+        value: u8,
+    ) -> Result<u8, SdError> {
+        if func as usize > SDIO_MAX_FUNCS || addr > 0x1FFFF {
+            return Err(SdError::InvalidArgument);
+        }
+
+        let mut arg = if write { 1 << 31 } else { 0 };
+        arg |= (func as u32) << 28;
+        // Read-after-write so the response carries the new register value
+        if write {
+            arg |= 1 << 27;
+        }
+        arg |= addr << 9;
+        arg |= value as u32;
+
+        let cmd = EMmcCommand::new(SD_IO_RW_DIRECT, arg, MMC_RSP_R5);
+        self.send_command(&cmd, None)?;
+
+        let resp = self.get_response().as_r1();
+        trace!("CMD52 arg {:#x} resp {:#x}", arg, resp);
+
+        if resp & R5_ERROR_MASK != 0 {
+            return Err(SdError::CardError(resp & R5_ERROR_MASK, "SDIO R5 error"));
+        }
+
+        Ok((resp & 0xFF) as u8)
+    }
+
+    pub fn sdio_readb(&self, func: u8, addr: u32) -> Result<u8, SdError> {
+        self.sdio_rw_direct(false, func, addr, 0)
+    }
+
+    pub fn sdio_writeb(&self, func: u8, addr: u32, value: u8) -> Result<(), SdError> {
+        self.sdio_rw_direct(true, func, addr, value).map(|_| ())
+    }
+
+    // Reset the I/O part of an SDIO card, no card answering is fine
+    pub(crate) fn sdio_reset(&self) {
+        if let Err(err) = self.sdio_writeb(0, SDIO_CCCR_ABORT, SDIO_CCCR_ABORT_RES) {
+            trace!("SDIO reset not answered: {}", err);
+        }
+    }
+
+    // CMD5 (IO_SEND_OP_COND) with an empty voltage window, only answered by
+    // cards with I/O functions. Returns the R4 response.
+    pub(crate) fn sdio_send_op_cond(&self) -> Result<u32, SdError> {
+        let cmd = EMmcCommand::new(SD_IO_SEND_OP_COND, 0, MMC_RSP_R4);
+        self.send_command(&cmd, None)?;
+
+        let ocr = self.get_response().as_r3();
+        debug!("CMD5 response {:#x}", ocr);
+        Ok(ocr)
+    }
+
+    /// Register an interrupt handler for SDIO function `func` (1..=7).
+    /// Enables the function and master interrupt bits in CCCR and unmasks
+    /// the host card interrupt.
+    pub fn sdio_claim_irq(&mut self, func: u8, handler: SdioIrqHandler) -> Result<(), SdError> {
+        if func == 0 || func as usize > SDIO_MAX_FUNCS {
+            return Err(SdError::InvalidArgument);
+        }
+        if self.no_sdio {
+            return Err(SdError::NotSupported);
+        }
+
+        let slot = &mut self.sdio_irq.handlers[func as usize - 1];
+        if slot.is_some() {
+            return Err(SdError::InvalidArgument);
+        }
+        *slot = Some(handler);
+
+        // A handler without its IENx bit would never run, drop it again
+        let enable = self.sdio_readb(0, SDIO_CCCR_IENX).and_then(|ien| {
+            self.sdio_writeb(0, SDIO_CCCR_IENX, ien | (1 << func) | SDIO_CCCR_IEN_MASTER)
+        });
+        if let Err(err) = enable {
+            self.sdio_irq.handlers[func as usize - 1] = None;
+            return Err(err);
+        }
+
+        debug!("SDIO function {} IRQ claimed", func);
+
+        self.sdio_enable_card_irq(true);
+
+        Ok(())
+    }
+
+    /// Release the interrupt handler of SDIO function `func`.
+    /// The master enable bit and host card interrupt are dropped together
+    /// with the last handler.
+    pub fn sdio_release_irq(&mut self, func: u8) -> Result<(), SdError> {
+        if func == 0 || func as usize > SDIO_MAX_FUNCS {
+            return Err(SdError::InvalidArgument);
+        }
+
+        if self.sdio_irq.handlers[func as usize - 1].take().is_none() {
+            return Err(SdError::InvalidArgument);
+        }
+
+        let mut ien = self.sdio_readb(0, SDIO_CCCR_IENX)?;
+        ien &= !(1 << func);
+        if ien & !SDIO_CCCR_IEN_MASTER == 0 {
+            ien = 0;
+        }
+        self.sdio_writeb(0, SDIO_CCCR_IENX, ien)?;
+
+        if self.sdio_irq.enabled_mask() == 0 {
+            self.sdio_enable_card_irq(false);
+        }
+
+        debug!("SDIO function {} IRQ released", func);
+
+        Ok(())
+    }
+
+    // Unmask the card interrupt again after init() rewrote the enable
+    // registers, as long as a function still has a handler
+    pub(crate) fn sdio_arm_irq(&self) {
+        if self.sdio_irq.enabled_mask() != 0 {
+            self.sdio_enable_card_irq(true);
+        }
+    }
+
+    // Mask or unmask the card interrupt in both the status and signal enable registers.
+    // The card interrupt is level triggered and cannot be cleared by writing the status
+    // register, so it has to be masked at the host until the function has been serviced.
+    fn sdio_enable_card_irq(&self, enable: bool) {
+        let mut stat_en = self.read_reg16(EMMC_NORMAL_INT_STAT_EN);
+        let mut sig_en = self.read_reg16(EMMC_SIGNAL_ENABLE);
+
+        if enable {
+            stat_en |= EMMC_INT_CARD_INT as u16;
+            sig_en |= EMMC_INT_CARD_INT as u16;
+        } else {
+            stat_en &= !(EMMC_INT_CARD_INT as u16);
+            sig_en &= !(EMMC_INT_CARD_INT as u16);
+        }
+
+        self.write_reg16(EMMC_NORMAL_INT_STAT_EN, stat_en);
+        self.write_reg16(EMMC_SIGNAL_ENABLE, sig_en);
+    }
+
+    /// Service a pending SDIO card interrupt.
+    /// Returns `Ok(true)` if a card interrupt was pending and has been dispatched.
+    pub fn sdio_handle_irq(&self) -> Result<bool, SdError> {
+        let masked = self.irq_take_card_events(EMMC_INT_CARD_INT) != 0;
+
+        let status = self.read_reg16(EMMC_NORMAL_INT_STAT);
+        if status & EMMC_INT_CARD_INT as u16 == 0 {
+            // Deasserted since the interrupt handler masked it
+            if masked {
+                self.sdio_arm_irq();
+            }
+            return Ok(false);
+        }
+
+        // Mask until the functions have been serviced, otherwise the level
+        // triggered interrupt fires again as soon as we return
+        self.sdio_enable_card_irq(false);
+
+        let result = self.sdio_dispatch_irq();
+
+        if self.sdio_irq.enabled_mask() != 0 {
+            self.sdio_enable_card_irq(true);
+        }
+
+        result.map(|_| true)
+    }
+
+    fn sdio_dispatch_irq(&self) -> Result<(), SdError> {
+        let pending = self.sdio_readb(0, SDIO_CCCR_INTX)?;
+        trace!("SDIO INT_PENDING {:#04x}", pending);
+
+        for func in 1..=SDIO_MAX_FUNCS as u8 {
+            if pending & (1 << func) == 0 {
+                continue;
+            }
+
+            match &self.sdio_irq.handlers[func as usize - 1] {
+                Some(handler) => handler(func),
+                None => warn!("SDIO function {} interrupt without handler", func),
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+// ===== R1 Card Status =====
+
+use core::fmt;
+
+use bitflags::bitflags;
+
+/// CURRENT_STATE field of the card status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardState {
+    Idle,
+    Ready,
+    Ident,
+    Stby,
+    Tran,
+    Data,
+    Rcv,
+    Prg,
+    Dis,
+    Btst,
+    Slp,
+    Reserved(u8),
+}
+
+impl From<u8> for CardState {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => CardState::Idle,
+            1 => CardState::Ready,
+            2 => CardState::Ident,
+            3 => CardState::Stby,
+            4 => CardState::Tran,
+            5 => CardState::Data,
+            6 => CardState::Rcv,
+            7 => CardState::Prg,
+            8 => CardState::Dis,
+            9 => CardState::Btst,
+            10 => CardState::Slp,
+            other => CardState::Reserved(other),
+        }
+    }
+}
+
+bitflags! {
+    /// Condition and error bits of the card status (JESD84 Table 68)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct StatusFlags: u32 {
+        const OUT_OF_RANGE = 1 << 31;
+        const ADDRESS_ERROR = 1 << 30;
+        const BLOCK_LEN_ERROR = 1 << 29;
+        const ERASE_SEQ_ERROR = 1 << 28;
+        const ERASE_PARAM = 1 << 27;
+        const WP_VIOLATION = 1 << 26;
+        const CARD_IS_LOCKED = 1 << 25;
+        const LOCK_UNLOCK_FAILED = 1 << 24;
+        const COM_CRC_ERROR = 1 << 23;
+        const ILLEGAL_COMMAND = 1 << 22;
+        const CARD_ECC_FAILED = 1 << 21;
+        const CC_ERROR = 1 << 20;
+        const ERROR = 1 << 19;
+        const CID_CSD_OVERWRITE = 1 << 16;
+        const WP_ERASE_SKIP = 1 << 15;
+        const ERASE_RESET = 1 << 13;
+        const READY_FOR_DATA = 1 << 8;
+        const SWITCH_ERROR = 1 << 7;
+        const EXCEPTION_EVENT = 1 << 6;
+        const APP_CMD = 1 << 5;
+    }
+}
+
+impl StatusFlags {
+    /// Bits reporting a failed operation
+    pub const ERRORS: StatusFlags = StatusFlags::OUT_OF_RANGE
+        .union(StatusFlags::ADDRESS_ERROR)
+        .union(StatusFlags::BLOCK_LEN_ERROR)
+        .union(StatusFlags::ERASE_SEQ_ERROR)
+        .union(StatusFlags::ERASE_PARAM)
+        .union(StatusFlags::WP_VIOLATION)
+        .union(StatusFlags::LOCK_UNLOCK_FAILED)
+        .union(StatusFlags::COM_CRC_ERROR)
+        .union(StatusFlags::ILLEGAL_COMMAND)
+        .union(StatusFlags::CARD_ECC_FAILED)
+        .union(StatusFlags::CC_ERROR)
+        .union(StatusFlags::ERROR)
+        .union(StatusFlags::CID_CSD_OVERWRITE)
+        .union(StatusFlags::WP_ERASE_SKIP)
+        .union(StatusFlags::SWITCH_ERROR);
+
+    /// Errors checked on every R1/R1b response. COM_CRC_ERROR and
+    /// ILLEGAL_COMMAND belong to the previous command, which already failed
+    /// without a response, and CMD42 reports LOCK_UNLOCK_FAILED itself.
+    pub const CHECKED: StatusFlags = StatusFlags::ERRORS
+        .difference(StatusFlags::COM_CRC_ERROR)
+        .difference(StatusFlags::ILLEGAL_COMMAND)
+        .difference(StatusFlags::LOCK_UNLOCK_FAILED);
+}
+
+/// R1 card status as returned by R1/R1b responses and CMD13
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct CardStatus(u32);
+
+impl CardStatus {
+    pub fn new(raw: u32) -> Self {
+        Self(raw)
+    }
+
+    pub fn raw(&self) -> u32 {
+        self.0
+    }
+
+    pub fn state(&self) -> CardState {
+        CardState::from(((self.0 >> 9) & 0xF) as u8)
+    }
+
+    pub fn flags(&self) -> StatusFlags {
+        StatusFlags::from_bits_truncate(self.0)
+    }
+
+    pub fn errors(&self) -> StatusFlags {
+        self.flags() & StatusFlags::ERRORS
+    }
+
+    pub fn has_error(&self) -> bool {
+        !self.errors().is_empty()
+    }
+
+    pub fn ready_for_data(&self) -> bool {
+        self.flags().contains(StatusFlags::READY_FOR_DATA)
+    }
+}
+
+impl From<u32> for CardStatus {
+    fn from(raw: u32) -> Self {
+        Self(raw)
+    }
+}
+
+impl fmt::Debug for CardStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CardStatus")
+            .field("raw", &format_args!("{:#010x}", self.0))
+            .field("state", &self.state())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
+
+impl fmt::Display for CardStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#010x} ({:?}", self.0, self.state())?;
+        for (name, _) in self.errors().iter_names() {
+            write!(f, " {}", name)?;
+        }
+        write!(f, ")")
+    }
+}
@@ -0,0 +1,236 @@
+// ===== Timeouts =====
+
+use log::debug;
+
+use crate::err::SdError;
+
+use super::{EMmcHost, constant::*, platform::Platform};
+
+// Fallbacks for cards that leave the EXT_CSD fields at zero
+const DEFAULT_CMD6_TIMEOUT_US: u64 = 500_000;
+const DEFAULT_ERASE_TIMEOUT_US: u64 = 1_000_000;
+// Linux waits this long for a cache flush, EXT_CSD gives no figure
+const FLUSH_CACHE_TIMEOUT_US: u64 = 30_000_000;
+// Lower bound of a data access timeout, the CSD values are tight at low clocks
+const MIN_DATA_TIMEOUT_US: u64 = 100_000;
+
+// TAAC time unit in ns and time value in tenths (JESD84 Table 86)
+const TAAC_UNIT_NS: [u64; 8] = [1, 10, 100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+const TAAC_VALUE: [u64; 16] = [
+    0, 10, 12, 13, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 70, 80,
+];
+
+/// Timeouts of card operations, from the CSD and EXT_CSD of the card
+#[derive(Debug, Clone, Copy)]
+pub struct CardTimeouts {
+    /// Asynchronous part of the read access time (TAAC)
+    pub taac_ns: u64,
+    /// Clock dependent part of the read access time (NSAC * 100)
+    pub nsac_clks: u64,
+    /// Write time as a power of two multiple of the read time (R2W_FACTOR)
+    pub r2w_factor: u8,
+    /// GENERIC_CMD6_TIME
+    pub cmd6_us: u64,
+    /// PARTITION_SWITCH_TIME
+    pub partition_switch_us: u64,
+    /// ERASE_TIMEOUT_MULT, per erase group, 0 if not given
+    pub erase_us: u64,
+    /// TRIM_MULT, per erase group, 0 if not given
+    pub trim_us: u64,
+    /// SLEEP_NOTIFICATION_TIME
+    pub sleep_notification_us: u64,
+}
+
+impl Default for CardTimeouts {
+    fn default() -> Self {
+        Self {
+            taac_ns: 0,
+            nsac_clks: 0,
+            r2w_factor: 0,
+            cmd6_us: DEFAULT_CMD6_TIMEOUT_US,
+            partition_switch_us: DEFAULT_CMD6_TIMEOUT_US,
+            erase_us: 0,
+            trim_us: 0,
+            sleep_notification_us: DEFAULT_CMD6_TIMEOUT_US,
+        }
+    }
+}
+
+impl CardTimeouts {
+    /// Take the data access times from the CSD
+    pub fn parse_csd(&mut self, csd: &[u32; 4]) {
+        let taac = (csd[0] >> 16) & 0xff;
+        self.taac_ns =
+            TAAC_UNIT_NS[(taac & 0x7) as usize] * TAAC_VALUE[((taac >> 3) & 0xf) as usize] / 10;
+        self.nsac_clks = ((csd[0] >> 8) & 0xff) as u64 * 100;
+        self.r2w_factor = ((csd[3] >> 26) & 0x7) as u8;
+    }
+
+    /// Take the operation times from the EXT_CSD, fields a revision does not
+    /// define keep their defaults
+    pub fn parse_ext_csd(&mut self, ext_csd: &[u8]) {
+        let rev = ext_csd[EXT_CSD_REV as usize];
+
+        if rev >= 3 {
+            self.erase_us = ext_csd[EXT_CSD_ERASE_TIMEOUT_MULT as usize] as u64 * 300_000;
+            self.trim_us = ext_csd[EXT_CSD_TRIM_MULT as usize] as u64 * 300_000;
+        }
+
+        if rev >= 6 {
+            let cmd6 = ext_csd[EXT_CSD_GENERIC_CMD6_TIME as usize] as u64 * 10_000;
+            if cmd6 != 0 {
+                self.cmd6_us = cmd6;
+            }
+
+            let part = ext_csd[EXT_CSD_PART_SWITCH_TIME as usize] as u64 * 10_000;
+            self.partition_switch_us = if part != 0 { part } else { self.cmd6_us };
+        }
+
+        // 10us * 2^SLEEP_NOTIFICATION_TIME, valid up to 0x17
+        let sleep = ext_csd[EXT_CSD_SLEEP_NOTIFICATION_TIME as usize];
+        if rev >= 7 && (1..=0x17).contains(&sleep) {
+            self.sleep_notification_us = 10 << sleep;
+        }
+    }
+
+    /// Access time of one block with the card clock at `clock` Hz
+    pub fn data_us(&self, write: bool, clock: u32) -> u64 {
+        // The CSD gives typical times, the maximum is ten times as long
+        let mut mult = 10;
+        if write {
+            mult <<= self.r2w_factor;
+        }
+
+        let mut us = self.taac_ns * mult / 1000;
+        if clock != 0 {
+            us += self.nsac_clks * mult * 1_000_000 / clock as u64;
+        }
+
+        us.max(MIN_DATA_TIMEOUT_US)
+    }
+
+    /// Busy time of CMD6 writing EXT_CSD byte `index`
+    pub fn switch_us(&self, index: u32) -> u64 {
+        match index {
+            EXT_CSD_PART_CONF => self.partition_switch_us,
+            EXT_CSD_FLUSH_CACHE => FLUSH_CACHE_TIMEOUT_US,
+            _ => self.cmd6_us,
+        }
+    }
+
+    /// Busy time of CMD38 with `arg` over `groups` erase groups
+    pub fn erase_us(&self, arg: u32, groups: u64, clock: u32) -> u64 {
+        let per_group = if arg & MMC_TRIM_ARG != 0 && self.trim_us != 0 {
+            self.trim_us
+        } else if self.erase_us != 0 {
+            self.erase_us
+        } else {
+            // Legacy cards: derive it from the write time
+            self.data_us(true, clock).max(DEFAULT_ERASE_TIMEOUT_US)
+        };
+
+        per_group * groups.max(1)
+    }
+}
+
+/// Wall-clock limit of a wait. Time is taken from the platform clock, or
+/// without one, by adding up the delays spent in `EMmcHost::wait_step`.
+/// Waits fail with NotSupported if there is no time source at all.
+pub(crate) struct Deadline {
+    clock: Option<&'static dyn Platform>,
+    start: u64,
+    waited: u64,
+    timeout_us: u64,
+}
+
+impl Deadline {
+    pub(crate) fn expired(&self) -> bool {
+        let elapsed = match self.clock {
+            Some(platform) => platform.now_us().wrapping_sub(self.start),
+            None => self.waited,
+        };
+        elapsed >= self.timeout_us
+    }
+}
+
+impl EMmcHost {
+    pub(crate) fn deadline(&self, timeout_us: u64) -> Deadline {
+        Deadline {
+            clock: self.platform,
+            start: self.platform.map_or(0, |p| p.now_us()),
+            waited: 0,
+            timeout_us,
+        }
+    }
+
+    // Sleep `us` on the way to `deadline`
+    pub(crate) fn wait_step(&self, deadline: &mut Deadline, us: u64) -> Result<(), SdError> {
+        self.delay_us(us)?;
+        deadline.waited += us;
+        Ok(())
+    }
+
+    // Poll `done` every `step_us` until it holds, Timeout after `timeout_us`
+    pub(crate) fn poll_until(
+        &self,
+        timeout_us: u64,
+        step_us: u64,
+        mut done: impl FnMut() -> bool,
+    ) -> Result<(), SdError> {
+        let mut deadline = self.deadline(timeout_us);
+        while !done() {
+            if deadline.expired() {
+                return Err(SdError::Timeout);
+            }
+            self.wait_step(&mut deadline, step_us)?;
+        }
+        Ok(())
+    }
+
+    /// Timeouts of the current card, defaults without one
+    pub fn card_timeouts(&self) -> CardTimeouts {
+        self.card
+            .as_ref()
+            .map_or_else(CardTimeouts::default, |card| card.timeouts)
+    }
+
+    // Timeout of a data transfer of `blocks` blocks at the current clock
+    pub(crate) fn data_timeout_us(&self, write: bool, blocks: u64) -> u64 {
+        let clock = self.card.as_ref().map_or(0, |card| card.clock);
+        self.card_timeouts().data_us(write, clock) * blocks.max(1)
+    }
+
+    // DATA_TIMEOUT_CONTROL value for a data or busy timeout of `timeout_us`.
+    // The counter runs 2^(13 + n) cycles of the timeout clock reported in the
+    // capabilities, or of the card clock if the host leaves that field at 0.
+    pub(crate) fn timeout_control(&self, timeout_us: u64) -> u8 {
+        let freq = (self.caps & EMMC_TIMEOUT_CLK_MASK) >> EMMC_TIMEOUT_CLK_SHIFT;
+        let tmclk_khz = if freq == 0 {
+            self.card.as_ref().map_or(0, |card| card.clock) / 1000
+        } else if self.caps & EMMC_TIMEOUT_CLK_UNIT != 0 {
+            freq * 1000
+        } else {
+            freq
+        };
+
+        if tmclk_khz == 0 {
+            return 0xe;
+        }
+
+        let mut count = 0;
+        let mut current_us = (1u64 << 13) * 1000 / tmclk_khz as u64;
+        while current_us < timeout_us {
+            count += 1;
+            current_us <<= 1;
+            if count >= 0xe {
+                debug!(
+                    "Timeout of {} us beyond the data timeout counter",
+                    timeout_us
+                );
+                return 0xe;
+            }
+        }
+
+        count
+    }
+}
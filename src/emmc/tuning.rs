@@ -0,0 +1,324 @@
+// ===== Tuning and Re-tuning =====
+
+use log::{debug, info, warn};
+
+use crate::err::SdError;
+
+use super::{EMmcHost, constant::*};
+
+// Sampling phases a software sweep can record
+const MAX_TUNING_TAPS: u8 = 128;
+
+// Tuning block of CMD19 and of CMD21 on a 4-bit bus (JESD84 Table 33)
+const TUNING_BLOCK_4BIT: [u8; 64] = [
+    0xff, 0x0f, 0xff, 0x00, 0xff, 0xcc, 0xc3, 0xcc, 0xc3, 0x3c, 0xcc, 0xff, 0xfe, 0xff, 0xfe, 0xef,
+    0xff, 0xdf, 0xff, 0xdd, 0xff, 0xfb, 0xff, 0xfb, 0xbf, 0xff, 0x7f, 0xff, 0x77, 0xf7, 0xbd, 0xef,
+    0xff, 0xf0, 0xff, 0xf0, 0x0f, 0xfc, 0xcc, 0x3c, 0xcc, 0x33, 0xcc, 0xcf, 0xff, 0xef, 0xff, 0xee,
+    0xff, 0xfd, 0xff, 0xfd, 0xdf, 0xff, 0xbf, 0xff, 0xbb, 0xff, 0xf7, 0xff, 0xf7, 0x7f, 0x7b, 0xde,
+];
+
+// Tuning block of CMD21 on an 8-bit bus (JESD84 Table 34)
+const TUNING_BLOCK_8BIT: [u8; 128] = [
+    0xff, 0xff, 0x00, 0xff, 0xff, 0xff, 0x00, 0x00, 0xff, 0xff, 0xcc, 0xcc, 0xcc, 0x33, 0xcc, 0xcc,
+    0xcc, 0x33, 0x33, 0xcc, 0xcc, 0xcc, 0xff, 0xff, 0xff, 0xee, 0xff, 0xff, 0xff, 0xee, 0xee, 0xff,
+    0xff, 0xff, 0xdd, 0xff, 0xff, 0xff, 0xdd, 0xdd, 0xff, 0xff, 0xff, 0xbb, 0xff, 0xff, 0xff, 0xbb,
+    0xbb, 0xff, 0xff, 0xff, 0x77, 0xff, 0xff, 0xff, 0x77, 0x77, 0xff, 0x77, 0xbb, 0xdd, 0xee, 0xff,
+    0xff, 0xff, 0xff, 0x00, 0xff, 0xff, 0xff, 0x00, 0x00, 0xff, 0xff, 0xcc, 0xcc, 0xcc, 0x33, 0xcc,
+    0xcc, 0xcc, 0x33, 0x33, 0xcc, 0xcc, 0xcc, 0xff, 0xff, 0xff, 0xee, 0xff, 0xff, 0xff, 0xee, 0xee,
+    0xff, 0xff, 0xff, 0xdd, 0xff, 0xff, 0xff, 0xdd, 0xdd, 0xff, 0xff, 0xff, 0xbb, 0xff, 0xff, 0xff,
+    0xbb, 0xbb, 0xff, 0xff, 0xff, 0x77, 0xff, 0xff, 0xff, 0x77, 0x77, 0xff, 0x77, 0xbb, 0xdd, 0xee,
+];
+
+/// How the sampling point was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TuningMethod {
+    /// The controller's EXEC_TUNING procedure
+    Hardware,
+    /// A driver sweep over the sampling phases of the controller
+    Software,
+}
+
+/// Outcome of a tuning run
+#[derive(Debug, Clone, Copy)]
+pub struct TuningResult {
+    pub method: TuningMethod,
+    /// CMD19 or CMD21
+    pub opcode: u8,
+    /// Card clock the sampling point is valid for
+    pub clock: u32,
+    /// Tuning blocks sent
+    pub commands: u32,
+    /// Phases swept, 0 for hardware tuning
+    pub taps: u8,
+    /// Bit n set if phase n returned the tuning block intact
+    pub pass_map: u128,
+    /// First phase and length of the widest passing window
+    pub window_start: u8,
+    pub window_len: u8,
+    /// Phase selected in the middle of the window, `None` if the controller
+    /// keeps it to itself
+    pub tap: Option<u8>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TuningState {
+    // Sweep the phases even if the controller can tune by itself
+    software: bool,
+    // Re-tuning period set by the user, instead of the one in the capabilities
+    period_override: Option<u64>,
+    period_us: u64,
+    // Platform time of the last tuning
+    tuned_at_us: u64,
+    // Set by CRC errors and `request_retune`
+    pending: bool,
+    result: Option<TuningResult>,
+    retunes: u32,
+}
+
+impl TuningState {
+    // Forget the sampling point of a card that is being re-initialized
+    pub(crate) fn forget(&mut self) {
+        self.result = None;
+        self.pending = false;
+    }
+}
+
+impl EMmcHost {
+    /// Tune by sweeping the sampling phases in the driver, even if the
+    /// controller has a tuning procedure of its own. Controllers before
+    /// SDHCI 3.00 are always tuned this way.
+    pub fn with_software_tuning(mut self, enable: bool) -> Self {
+        self.tuning.software = enable;
+        self
+    }
+
+    /// Re-tune every `period_us` instead of the period of the re-tuning timer
+    /// in the capabilities, 0 disables periodic re-tuning. Needs the platform
+    /// clock, without one only re-tuning events and CRC errors trigger it.
+    pub fn with_retune_period(mut self, period_us: u64) -> Self {
+        self.tuning.period_override = Some(period_us);
+        self
+    }
+
+    /// Result of the last tuning, `None` if the card runs in a mode without
+    /// tuning
+    pub fn tuning_result(&self) -> Option<TuningResult> {
+        self.tuning.result
+    }
+
+    /// Times the card has been re-tuned after the initial tuning
+    pub fn retune_count(&self) -> u32 {
+        self.tuning.retunes
+    }
+
+    /// Re-tune before the next transfer
+    pub fn request_retune(&mut self) {
+        if self.tuning.result.is_some() {
+            self.tuning.pending = true;
+        }
+    }
+
+    /// Whether the sampling point should be re-tuned: on request, after a
+    /// re-tuning event of the controller or when the re-tuning period is over
+    pub fn retune_needed(&self) -> bool {
+        if self.tuning.result.is_none() || !self.mmc_card_hs200() {
+            return false;
+        }
+
+        if self.tuning.pending
+            || self.read_reg16(EMMC_NORMAL_INT_STAT) & EMMC_INT_RETUNE as u16 != 0
+            || self.read_reg(EMMC_PRESENT_STATE) & EMMC_RETUNE_REQUEST != 0
+        {
+            return true;
+        }
+
+        match self.platform {
+            Some(platform) if self.tuning.period_us != 0 => {
+                platform.now_us().wrapping_sub(self.tuning.tuned_at_us) >= self.tuning.period_us
+            }
+            _ => false,
+        }
+    }
+
+    /// Re-tune if `retune_needed()`, returns whether it did
+    pub fn retune_if_needed(&mut self) -> Result<bool, SdError> {
+        if !self.retune_needed() {
+            return Ok(false);
+        }
+
+        self.retune()?;
+        Ok(true)
+    }
+
+    /// Repeat the tuning of an HS200 card at its current clock
+    pub fn retune(&mut self) -> Result<TuningResult, SdError> {
+        let timing = self.timing().ok_or(SdError::NoCard)?;
+        if timing != MMC_TIMING_MMC_HS200 {
+            // HS400 would have to drop back to HS200 for the tuning
+            debug!("No re-tuning in timing {}", timing);
+            self.tuning.pending = false;
+            return Err(SdError::NotSupported);
+        }
+
+        self.tuning.retunes += 1;
+        self.tune(MMC_SEND_TUNING_BLOCK_HS200)
+            .inspect_err(|e| warn!("Re-tuning failed: {}", e))
+    }
+
+    // Tune with `opcode`, keep the result and start watching for re-tuning
+    pub(crate) fn tune(&mut self, opcode: u8) -> Result<TuningResult, SdError> {
+        let software = self.tuning.software || (self.version & EMMC_SPEC_VER_MASK) < EMMC_SPEC_300;
+
+        let result = if software {
+            self.sdhci_software_tuning(opcode)?
+        } else {
+            self.execute_tuning(opcode)?
+        };
+        info!(
+            "Tuned at {} Hz: {:?}, tap {:?}",
+            result.clock, result.method, result.tap
+        );
+
+        self.tuning.result = Some(result);
+        self.tuning.pending = false;
+        self.tuning.tuned_at_us = self.platform.map_or(0, |p| p.now_us());
+        self.tuning_arm();
+
+        Ok(result)
+    }
+
+    // Latch re-tuning events and take the period from the re-tuning timer
+    fn tuning_arm(&mut self) {
+        self.write_reg16(EMMC_NORMAL_INT_STAT, EMMC_INT_RETUNE as u16);
+        let int_en = self.read_reg16(EMMC_NORMAL_INT_STAT_EN);
+        self.write_reg16(EMMC_NORMAL_INT_STAT_EN, int_en | EMMC_INT_RETUNE as u16);
+
+        // 2^(count - 1) seconds, 0 is off and 0xf refers to other sources
+        let count = (self.read_reg(EMMC_CAPABILITIES2) & EMMC_RETUNING_TIMER_COUNT_MASK)
+            >> EMMC_RETUNING_TIMER_COUNT_SHIFT;
+        self.tuning.period_us = self.tuning.period_override.unwrap_or(match count {
+            1..=0xb => 1_000_000 << (count - 1),
+            _ => 0,
+        });
+
+        if self.tuning.period_us != 0 && self.platform.is_none() {
+            debug!("No platform clock, periodic re-tuning disabled");
+        }
+    }
+
+    /// Sweep the sampling phases of the controller, see
+    /// `SdhciHostOps::tuning_taps`, and settle in the middle of the widest
+    /// window that returns the tuning block intact
+    pub fn sdhci_software_tuning(&mut self, opcode: u8) -> Result<TuningResult, SdError> {
+        let ops = self.ops;
+        let taps = ops.tuning_taps(self).min(MAX_TUNING_TAPS);
+        if taps == 0 {
+            debug!("Controller has no software tuning taps");
+            return Err(SdError::NotSupported);
+        }
+
+        let pattern = self.tuning_pattern(opcode)?;
+
+        // Sample with the fixed clock while sweeping
+        let mut ctrl = self.read_reg16(EMMC_HOST_CTRL2);
+        ctrl &= !(MMC_CTRL_EXEC_TUNING | MMC_CTRL_TUNED_CLK);
+        self.write_reg16(EMMC_HOST_CTRL2, ctrl);
+
+        let mut pass_map = 0u128;
+        for tap in 0..taps {
+            ops.set_tuning_tap(self, tap)?;
+            if self.tuning_tap_passes(opcode, pattern)? {
+                pass_map |= 1 << tap;
+            }
+        }
+
+        let (window_start, window_len) = widest_window(pass_map, taps);
+        debug!(
+            "Tuning sweep {:#x}, window {}+{}",
+            pass_map, window_start, window_len
+        );
+        if window_len == 0 {
+            return Err(SdError::TuningFailed);
+        }
+
+        let tap = window_start + window_len / 2;
+        ops.set_tuning_tap(self, tap)?;
+
+        Ok(TuningResult {
+            method: TuningMethod::Software,
+            opcode,
+            clock: self.card.as_ref().map_or(0, |card| card.clock),
+            commands: taps as u32,
+            taps,
+            pass_map,
+            window_start,
+            window_len,
+            tap: Some(tap),
+        })
+    }
+
+    // Result of the hardware tuning procedure after `commands` tuning blocks
+    pub(crate) fn hardware_tuning_result(&self, opcode: u8, commands: u32) -> TuningResult {
+        TuningResult {
+            method: TuningMethod::Hardware,
+            opcode,
+            clock: self.card.as_ref().map_or(0, |card| card.clock),
+            commands,
+            taps: 0,
+            pass_map: 0,
+            window_start: 0,
+            window_len: 0,
+            tap: None,
+        }
+    }
+
+    pub(crate) fn tuning_pattern(&self, opcode: u8) -> Result<&'static [u8], SdError> {
+        let bus_width = self.bus_width().ok_or(SdError::NoCard)?;
+        if opcode == MMC_SEND_TUNING_BLOCK_HS200 && bus_width == MMC_BUS_WIDTH_8BIT {
+            Ok(&TUNING_BLOCK_8BIT)
+        } else {
+            Ok(&TUNING_BLOCK_4BIT)
+        }
+    }
+
+    // Send one tuning block at the current phase and check its contents
+    pub(crate) fn tuning_tap_passes(
+        &mut self,
+        opcode: u8,
+        pattern: &[u8],
+    ) -> Result<bool, SdError> {
+        let passed = match self.emmc_send_tuning(opcode) {
+            // Drain the whole block so the buffer is empty either way
+            Ok(()) => pattern.chunks(4).fold(true, |ok, word| {
+                let data = self.read_reg(EMMC_BUF_DATA).to_le_bytes();
+                ok && data[..] == *word
+            }),
+            Err(_) => false,
+        };
+
+        // Leave no transfer or error state behind for the next phase
+        self.reset(EMMC_RESET_CMD)?;
+        self.reset(EMMC_RESET_DATA)?;
+
+        Ok(passed)
+    }
+}
+
+// Longest run of set bits among the first `taps` bits of `pass_map`
+pub(crate) fn widest_window(pass_map: u128, taps: u8) -> (u8, u8) {
+    let (mut best_start, mut best_len) = (0, 0);
+    let mut start = 0;
+
+    for tap in 0..=taps {
+        let passed = tap < taps && pass_map & (1 << tap) != 0;
+        if !passed {
+            if tap - start > best_len {
+                best_start = start;
+                best_len = tap - start;
+            }
+            start = tap + 1;
+        }
+    }
+
+    (best_start, best_len)
+}
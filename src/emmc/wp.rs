@@ -0,0 +1,287 @@
+// ===== Write Protection =====
+
+#[cfg(feature = "dma")]
+use dma_api::{DVec, Direction};
+use log::{debug, info};
+
+use crate::err::SdError;
+
+use super::{EMmcHost, block::DataBuffer, cmd::EMmcCommand, constant::*, platform::GpioLine};
+
+/// Protection applied by CMD28 to a write protect group, selected through USER_WP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WpType {
+    /// Cleared again with CMD29
+    Temporary,
+    /// Stays active until the next power cycle
+    PowerOn,
+    /// Can never be cleared
+    Permanent,
+}
+
+/// Protection state of one write protect group as reported by CMD31
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WpStatus {
+    None,
+    Temporary,
+    PowerOn,
+    Permanent,
+}
+
+impl From<u8> for WpStatus {
+    fn from(bits: u8) -> Self {
+        match bits & 0x3 {
+            0 => WpStatus::None,
+            1 => WpStatus::Temporary,
+            2 => WpStatus::PowerOn,
+            _ => WpStatus::Permanent,
+        }
+    }
+}
+
+impl EMmcHost {
+    // Check if card is write protected
+    pub fn is_write_protected(&self) -> bool {
+        if let Some(protected) = self.platform_gpio_get(GpioLine::WriteProtect) {
+            return protected;
+        }
+
+        // The floating controller pin says nothing about the card
+        if self.wp_gpio {
+            return false;
+        }
+
+        let state = self.read_reg(EMMC_PRESENT_STATE);
+        (state & EMMC_WRITE_PROTECT) != 0
+    }
+
+    /// Size of a write protect group in 512-byte blocks
+    pub fn wp_group_size(&self) -> Result<u64, SdError> {
+        let card = self.card.as_ref().ok_or(SdError::NoCard)?;
+        if card.hc_wp_grp_size == 0 {
+            return Err(SdError::UnsupportedCard);
+        }
+
+        Ok(card.hc_wp_grp_size)
+    }
+
+    // Block number to the address expected by class 6 commands
+    fn wp_card_addr(&self, block_id: u32) -> Result<u32, SdError> {
+        let card = self.card.as_ref().ok_or(SdError::NoCard)?;
+
+        if card.state & MMC_STATE_HIGHCAPACITY != 0 {
+            Ok(block_id)
+        } else {
+            block_id.checked_mul(512).ok_or(SdError::InvalidArgument)
+        }
+    }
+
+    /// Protect the write protect group containing `block_id` (CMD28)
+    pub fn set_write_protect(&mut self, block_id: u32, wp_type: WpType) -> Result<(), SdError> {
+        let addr = self.wp_card_addr(block_id)?;
+
+        let user_wp = self.mmc_read_ext_csd()?[EXT_CSD_USER_WP as usize];
+        let wanted = match wp_type {
+            WpType::Temporary => user_wp & !(EXT_CSD_US_PWR_WP_EN | EXT_CSD_US_PERM_WP_EN),
+            WpType::PowerOn => {
+                if user_wp & EXT_CSD_US_PWR_WP_DIS != 0 {
+                    return Err(SdError::UnsupportedCard);
+                }
+                (user_wp & !EXT_CSD_US_PERM_WP_EN) | EXT_CSD_US_PWR_WP_EN
+            }
+            WpType::Permanent => {
+                if user_wp & EXT_CSD_US_PERM_WP_DIS != 0 {
+                    return Err(SdError::UnsupportedCard);
+                }
+                user_wp | EXT_CSD_US_PERM_WP_EN
+            }
+        };
+
+        if wanted != user_wp {
+            self.mmc_switch(EXT_CSD_CMD_SET_NORMAL, EXT_CSD_USER_WP, wanted, true)?;
+        }
+
+        let timeout_us = self.data_timeout_us(true, 1);
+        let cmd =
+            EMmcCommand::new(MMC_SET_WRITE_PROT, addr, MMC_RSP_R1B).with_busy_timeout(timeout_us);
+        let ret = self
+            .send_command(&cmd, None)
+            .and_then(|_| self.mmc_poll_for_busy(true, timeout_us));
+
+        // Leave USER_WP as found so later CMD28s default to temporary protection
+        if wanted != user_wp {
+            self.mmc_switch(EXT_CSD_CMD_SET_NORMAL, EXT_CSD_USER_WP, user_wp, true)?;
+        }
+
+        ret?;
+
+        debug!("Write protect ({:?}) set at block {:#x}", wp_type, block_id);
+        Ok(())
+    }
+
+    /// Clear temporary protection of the group containing `block_id` (CMD29)
+    pub fn clear_write_protect(&self, block_id: u32) -> Result<(), SdError> {
+        let addr = self.wp_card_addr(block_id)?;
+
+        let timeout_us = self.data_timeout_us(true, 1);
+        let cmd =
+            EMmcCommand::new(MMC_CLR_WRITE_PROT, addr, MMC_RSP_R1B).with_busy_timeout(timeout_us);
+        self.send_command(&cmd, None)?;
+        self.mmc_poll_for_busy(true, timeout_us)?;
+
+        debug!("Write protect cleared at block {:#x}", block_id);
+        Ok(())
+    }
+
+    /// Query the protection bits of the 32 groups starting at `block_id` (CMD30).
+    /// Bit N of the result is set if group N is protected.
+    pub fn send_write_protect(&self, block_id: u32) -> Result<u32, SdError> {
+        let addr = self.wp_card_addr(block_id)?;
+        let mut buf = [0u8; 4];
+
+        self.wp_read_data(MMC_SEND_WRITE_PROT, addr, &mut buf)?;
+
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    /// Query the protection type of the 32 groups starting at `block_id` (CMD31)
+    pub fn send_write_protect_type(&self, block_id: u32) -> Result<[WpStatus; 32], SdError> {
+        let addr = self.wp_card_addr(block_id)?;
+        let mut buf = [0u8; 8];
+
+        self.wp_read_data(MMC_SEND_WRITE_PROT_TYPE, addr, &mut buf)?;
+
+        // Two bits per group, the first group in the least significant bits
+        let bits = u64::from_be_bytes(buf);
+        let mut status = [WpStatus::None; 32];
+        for (i, s) in status.iter_mut().enumerate() {
+            *s = WpStatus::from((bits >> (i * 2)) as u8);
+        }
+
+        Ok(status)
+    }
+
+    /// Apply `wp_type` to every write protect group in `[block_id, block_id + blocks)`.
+    /// The range must be aligned to the write protect group size.
+    pub fn write_protect_range(
+        &mut self,
+        block_id: u32,
+        blocks: u32,
+        wp_type: WpType,
+    ) -> Result<(), SdError> {
+        let (grp, end) = self.wp_check_range(block_id, blocks)?;
+
+        let mut block = block_id as u64;
+        while block < end {
+            self.set_write_protect(block as u32, wp_type)?;
+            block += grp;
+        }
+
+        Ok(())
+    }
+
+    /// Clear temporary protection of every group in `[block_id, block_id + blocks)`
+    pub fn clear_write_protect_range(&self, block_id: u32, blocks: u32) -> Result<(), SdError> {
+        let (grp, end) = self.wp_check_range(block_id, blocks)?;
+
+        let mut block = block_id as u64;
+        while block < end {
+            self.clear_write_protect(block as u32)?;
+            block += grp;
+        }
+
+        Ok(())
+    }
+
+    /// Disable power-on or permanent write protection of the user area for good
+    /// by setting US_PWR_WP_DIS / US_PERM_WP_DIS in USER_WP
+    pub fn disable_user_write_protect(&mut self, wp_type: WpType) -> Result<(), SdError> {
+        let user_wp = self.mmc_read_ext_csd()?[EXT_CSD_USER_WP as usize];
+        let value = match wp_type {
+            WpType::Temporary => return Err(SdError::InvalidArgument),
+            WpType::PowerOn => user_wp | EXT_CSD_US_PWR_WP_DIS,
+            WpType::Permanent => user_wp | EXT_CSD_US_PERM_WP_DIS,
+        };
+
+        self.mmc_switch(EXT_CSD_CMD_SET_NORMAL, EXT_CSD_USER_WP, value, true)
+    }
+
+    /// Write protect both boot partitions through BOOT_WP.
+    /// `WpType::Temporary` is not available for boot areas.
+    pub fn set_boot_write_protect(&mut self, wp_type: WpType) -> Result<(), SdError> {
+        let boot_wp = self.mmc_read_ext_csd()?[EXT_CSD_BOOT_WP as usize];
+
+        // Apply to both boot partitions
+        let mut value = boot_wp & !(EXT_CSD_BOOT_WP_B_SEC_WP_SEL);
+        value = match wp_type {
+            WpType::Temporary => return Err(SdError::InvalidArgument),
+            WpType::PowerOn => {
+                if boot_wp & EXT_CSD_BOOT_WP_B_PWR_WP_DIS != 0 {
+                    return Err(SdError::UnsupportedCard);
+                }
+                value | EXT_CSD_BOOT_WP_B_PWR_WP_EN
+            }
+            WpType::Permanent => {
+                if boot_wp & EXT_CSD_BOOT_WP_B_PERM_WP_DIS != 0 {
+                    return Err(SdError::UnsupportedCard);
+                }
+                value | EXT_CSD_BOOT_WP_B_PERM_WP_EN
+            }
+        };
+
+        self.mmc_switch(EXT_CSD_CMD_SET_NORMAL, EXT_CSD_BOOT_WP, value, true)?;
+
+        info!("Boot area write protect set: {:?}", wp_type);
+        Ok(())
+    }
+
+    /// BOOT_WP_STATUS: protection of boot area 1 and 2
+    pub fn boot_write_protect_status(&mut self) -> Result<[WpStatus; 2], SdError> {
+        let status = self.mmc_read_ext_csd()?[EXT_CSD_BOOT_WP_STATUS as usize];
+
+        // 0: none, 1: power-on, 2: permanent
+        let decode = |bits: u8| match bits & 0x3 {
+            0 => WpStatus::None,
+            1 => WpStatus::PowerOn,
+            _ => WpStatus::Permanent,
+        };
+
+        Ok([decode(status), decode(status >> 2)])
+    }
+
+    // Validate a group aligned range, returns the group size and the end block
+    fn wp_check_range(&self, block_id: u32, blocks: u32) -> Result<(u64, u64), SdError> {
+        let grp = self.wp_group_size()?;
+        if blocks == 0
+            || !(block_id as u64).is_multiple_of(grp)
+            || !(blocks as u64).is_multiple_of(grp)
+        {
+            return Err(SdError::InvalidArgument);
+        }
+
+        let end = block_id as u64 + blocks as u64;
+        if end > self.get_block_num() {
+            return Err(SdError::InvalidArgument);
+        }
+
+        Ok((grp, end))
+    }
+
+    // Single block read of a class 6 status register
+    fn wp_read_data(&self, opcode: u8, addr: u32, buf: &mut [u8]) -> Result<(), SdError> {
+        let cmd = EMmcCommand::new(opcode, addr, MMC_RSP_R1).with_data(buf.len() as u16, 1, true);
+
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "dma")] {
+                let mut data: DVec<u8> = DVec::zeros(buf.len(), 0x1000, Direction::FromDevice)
+                    .ok_or(SdError::MemoryError)?;
+                self.send_command(&cmd, Some(DataBuffer::Read(&mut data)))?;
+                buf.copy_from_slice(&data.to_vec());
+            } else if #[cfg(feature = "pio")] {
+                self.send_command(&cmd, Some(DataBuffer::Read(buf)))?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,7 +1,10 @@
 // ===== Types and Structures =====
 
+use alloc::boxed::Box;
 use core::fmt;
 
+use crate::emmc::{clock::ClkError, status::CardStatus};
+
 #[derive(Debug)]
 pub enum SdError {
     Timeout,
@@ -30,7 +33,98 @@ pub enum SdError {
     BufferOverflow,
     MemoryError,
     BusWidth,
+    WriteProtected,
+    CardLocked,
+    LockUnlockFailed,
+    NotSupported,
+    QueueActive,
+    QueueFull,
+    Clock(ClkError),
     CardError(u32, &'static str), // 包含错误状态和描述
+    /// Error bits set in the R1 status of a response
+    CardStatus(CardStatus),
+    /// Failure of a command on the bus, with the state it left behind
+    Command(Box<CommandError>),
+}
+
+/// Where a failure was detected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorOrigin {
+    /// The controller or the driver, e.g. bad arguments or a stuck reset
+    Host,
+    /// Signalling on the CMD or DAT lines, e.g. CRC errors and timeouts
+    Bus,
+    /// The card, through its R1 status or an error response
+    Card,
+}
+
+/// Interrupt and state registers captured when a command failed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegSnapshot {
+    pub normal_int_stat: u16,
+    pub error_int_stat: u16,
+    pub auto_cmd_stat: u16,
+    pub adma_err_stat: u8,
+    pub present_state: u32,
+}
+
+#[derive(Debug)]
+pub struct CommandError {
+    /// Error decoded from the registers
+    pub cause: SdError,
+    /// Opcode and argument, unknown for a data phase seen on its own
+    pub command: Option<(u8, u32)>,
+    /// Response registers, `[0]` holding the short response
+    pub response: [u32; 4],
+    pub regs: RegSnapshot,
+    /// R1 status for commands with an R1/R1b response
+    pub card_status: Option<CardStatus>,
+}
+
+impl SdError {
+    /// The error without command context
+    pub fn cause(&self) -> &SdError {
+        match self {
+            SdError::Command(err) => err.cause.cause(),
+            err => err,
+        }
+    }
+
+    pub fn command(&self) -> Option<&CommandError> {
+        match self {
+            SdError::Command(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    pub fn origin(&self) -> ErrorOrigin {
+        match self {
+            SdError::Command(err) => {
+                if err.card_status.is_some_and(|s| s.has_error()) {
+                    ErrorOrigin::Card
+                } else {
+                    err.cause.origin()
+                }
+            }
+            SdError::Timeout
+            | SdError::Crc
+            | SdError::EndBit
+            | SdError::Index
+            | SdError::DataTimeout
+            | SdError::DataCrc
+            | SdError::DataEndBit
+            | SdError::Acmd12Error
+            | SdError::InvalidResponse
+            | SdError::CommandError
+            | SdError::TransferError
+            | SdError::DataError => ErrorOrigin::Bus,
+            SdError::CardError(..)
+            | SdError::CardStatus(_)
+            | SdError::CardLocked
+            | SdError::LockUnlockFailed => ErrorOrigin::Card,
+            _ => ErrorOrigin::Host,
+        }
+    }
 }
 
 impl fmt::Display for SdError {
@@ -62,7 +156,52 @@ impl fmt::Display for SdError {
             SdError::BufferOverflow => write!(f, "Buffer overflow"),
             SdError::MemoryError => write!(f, "Memory error"),
             SdError::BusWidth => write!(f, "Bus width error"),
+            SdError::WriteProtected => write!(f, "Card is write protected"),
+            SdError::CardLocked => write!(f, "Card is locked"),
+            SdError::LockUnlockFailed => write!(f, "Lock/unlock operation failed"),
+            SdError::NotSupported => write!(f, "Operation not supported by host"),
+            SdError::QueueActive => write!(f, "Command queue is running"),
+            SdError::QueueFull => write!(f, "No free command queue slot"),
+            SdError::Clock(err) => write!(f, "Clock error: {:?}", err),
             SdError::CardError(status, desc) => write!(f, "Card error: 0x{:X} ({})", status, desc),
+            SdError::CardStatus(status) => write!(f, "Card status error: {}", status),
+            SdError::Command(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.command {
+            Some((opcode, arg)) => write!(f, "CMD{} (arg {:#x}): {}", opcode, arg, self.cause)?,
+            None => write!(f, "Data transfer: {}", self.cause)?,
+        }
+        if let Some(status) = self.card_status {
+            write!(f, ", R1 {}", status)?;
         }
+        write!(
+            f,
+            " [int {:#06x}, err {:#06x}, auto cmd {:#06x}, adma {:#04x}, state {:#010x}]",
+            self.regs.normal_int_stat,
+            self.regs.error_int_stat,
+            self.regs.auto_cmd_stat,
+            self.regs.adma_err_stat,
+            self.regs.present_state
+        )
+    }
+}
+
+impl core::error::Error for SdError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            SdError::Command(err) => Some(&err.cause),
+            _ => None,
+        }
+    }
+}
+
+impl From<ClkError> for SdError {
+    fn from(err: ClkError) -> Self {
+        SdError::Clock(err)
     }
 }
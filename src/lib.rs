@@ -2,6 +2,9 @@
 #![no_main]
 #![feature(alloc_error_handler)]
 
+extern crate alloc;
+
+pub mod blockdev;
 pub mod emmc;
 pub mod err;
 
@@ -35,6 +38,7 @@ pub trait Kernel {
     fn sleep(us: u64);
 }
 
+#[cfg(feature = "kernel")]
 pub(crate) fn delay_us(us: u64) {
     unsafe extern "Rust" {
         fn delay_us(us: u64);
@@ -6,7 +6,7 @@ extern crate alloc;
 
 #[bare_test::tests]
 mod tests {
-    use alloc::{boxed::Box, vec::Vec};
+    use alloc::{boxed::Box, vec, vec::Vec};
     use bare_test::{
         globals::{PlatformInfoKind, global_val},
         mem::{iomap, page_size},
@@ -14,16 +14,26 @@ mod tests {
         time::since_boot,
     };
     use dma_api::{DVec, Direction};
+    use fdt_parser::PciSpace;
     use log::{info, warn};
+    use pcie::{BarVec, CommandRegister, Header, RootComplexGeneric, SimpleBarAllocator};
     use rk3588_clk::{constant::*, Rk3588Cru};
+    use sdmmc::blockdev::partition::{Partition, PartitionDevice, find_partition, read_partitions};
+    use sdmmc::blockdev::{BlockCache, BlockDevice, CacheConfig};
+    #[cfg(feature = "fs")]
+    use sdmmc::blockdev::{BootFs, FsType};
     use sdmmc::emmc::EMmcHost;
+    use sdmmc::emmc::config::EMmcChipConfig;
     use sdmmc::emmc::constant::*;
+    use sdmmc::emmc::host::StandardSdhci;
     use sdmmc::{
         Kernel,
-        emmc::clock::{Clk, ClkError, init_global_clk},
+        emmc::clock::{Clk, ClkError},
+        err::SdError,
         set_impl,
     };
 
+    use core::cell::Cell;
     use core::ptr::NonNull;
 
     struct SKernel;
@@ -41,6 +51,192 @@ mod tests {
 
     set_impl!(SKernel);
 
+    #[test]
+    fn test_qemu() {
+        let Some(sdhci_addr) = get_sdhci_pci_addr() else {
+            info!("No sdhci-pci device, skipping");
+            return;
+        };
+
+        info!("sdhci-pci addr: {:#x}", sdhci_addr);
+
+        // QEMU's sdhci-pci has none of the DWCMSHC vendor registers
+        let mut emmc = EMmcHost::new_with_ops(sdhci_addr, &StandardSdhci);
+        test_card(&mut emmc);
+
+        // Layout of the image built by `make disk_img`
+        let part = test_image_partition(&mut emmc);
+        test_block_cache(&mut emmc);
+
+        #[cfg(feature = "fs")]
+        test_boot_fs(emmc, &part);
+        #[cfg(not(feature = "fs"))]
+        let _ = part;
+    }
+
+    // Find the `rootfs` partition and read its ext4 superblock through
+    // `PartitionDevice`
+    fn test_image_partition(emmc: &mut EMmcHost) -> Partition {
+        let parts = read_partitions(&mut *emmc).expect("Partition table read failed");
+        let part = find_partition(&parts, "rootfs")
+            .expect("No rootfs partition")
+            .clone();
+        println!(
+            "Partition {} '{}' at block {}, {} blocks",
+            part.number, part.name, part.start, part.blocks
+        );
+        assert_eq!(part.start, 2048);
+
+        // The superblock starts 1024 bytes into the filesystem
+        let mut block = [0u8; 512];
+        let mut dev = PartitionDevice::new(&mut *emmc, &part).expect("Partition out of range");
+        BlockDevice::read_blocks(&mut dev, 2, &mut block).expect("Partition read failed");
+        assert_eq!(
+            u16::from_le_bytes([block[56], block[57]]),
+            0xEF53,
+            "No ext4 superblock in the partition"
+        );
+
+        part
+    }
+
+    // Card access through `BlockCache`, counting the commands it issues
+    struct Counting<'a> {
+        dev: &'a mut EMmcHost,
+        reads: &'a Cell<u32>,
+        writes: &'a Cell<u32>,
+    }
+
+    impl BlockDevice for Counting<'_> {
+        fn block_size(&self) -> usize {
+            self.dev.block_size()
+        }
+
+        fn num_blocks(&self) -> u64 {
+            self.dev.num_blocks()
+        }
+
+        fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Result<(), SdError> {
+            self.reads.set(self.reads.get() + 1);
+            BlockDevice::read_blocks(&mut *self.dev, block_id, buf)
+        }
+
+        fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> Result<(), SdError> {
+            self.writes.set(self.writes.get() + 1);
+            BlockDevice::write_blocks(&mut *self.dev, block_id, buf)
+        }
+
+        fn write_blocks_fua(&mut self, block_id: u64, buf: &[u8]) -> Result<(), SdError> {
+            self.writes.set(self.writes.get() + 1);
+            BlockDevice::write_blocks_fua(&mut *self.dev, block_id, buf)
+        }
+
+        fn flush(&mut self) -> Result<(), SdError> {
+            BlockDevice::flush(&mut *self.dev)
+        }
+    }
+
+    // Blocks 1024..2048 lie between the GPT and the rootfs partition
+    fn test_block_cache(emmc: &mut EMmcHost) {
+        const BASE: u64 = 1024;
+
+        let reads = Cell::new(0);
+        let writes = Cell::new(0);
+        let block = |fill: u8| [fill; 512];
+
+        // Scattered write-back, merged into one write per run on flush
+        let config = CacheConfig::default().with_write_back(true);
+        let dev = Counting {
+            dev: &mut *emmc,
+            reads: &reads,
+            writes: &writes,
+        };
+        let mut cache = BlockCache::new(dev, config);
+        for (offset, fill) in [(0, 0x11), (1, 0x22), (2, 0x33), (6, 0x44)] {
+            cache
+                .write_blocks(BASE + offset, &block(fill))
+                .expect("Cached write failed");
+        }
+        assert_eq!(reads.get(), 1, "Partial chunk not fetched once");
+        assert_eq!(writes.get(), 0, "Write-back cache wrote through");
+        cache.flush().expect("Cache flush failed");
+        assert_eq!(writes.get(), 2, "Adjacent dirty blocks not merged");
+        drop(cache);
+
+        let mut buf = [0u8; 8 * 512];
+        emmc.read_blocks(BASE as u32, 8, &mut buf)
+            .expect("Read back failed");
+        for (offset, fill) in [(0, 0x11), (1, 0x22), (2, 0x33), (6, 0x44)] {
+            assert_eq!(
+                buf[offset * 512..][..512],
+                block(fill),
+                "Block {} lost",
+                offset
+            );
+        }
+
+        // Sequential reads fetch the following chunks in one command
+        let mut expected = vec![0u8; 48 * 512];
+        emmc.read_blocks(BASE as u32 + 16, 48, &mut expected)
+            .expect("Direct read failed");
+
+        reads.set(0);
+        let dev = Counting {
+            dev: &mut *emmc,
+            reads: &reads,
+            writes: &writes,
+        };
+        let mut cache = BlockCache::new(dev, CacheConfig::default());
+        let mut data = vec![0u8; 48 * 512];
+        for (i, buf) in data.chunks_mut(512).enumerate() {
+            cache
+                .read_blocks(BASE + 16 + i as u64, buf)
+                .expect("Cached read failed");
+        }
+        // The first chunk, then the second with four chunks of read-ahead
+        assert_eq!(reads.get(), 2, "Read-ahead did not kick in");
+        assert_eq!(data, expected, "Read-ahead data mismatch");
+        drop(cache);
+
+        // A FUA write over a dirty block leaves nothing to write back
+        reads.set(0);
+        writes.set(0);
+        let config = CacheConfig::default().with_write_back(true);
+        let dev = Counting {
+            dev: &mut *emmc,
+            reads: &reads,
+            writes: &writes,
+        };
+        let mut cache = BlockCache::new(dev, config);
+        cache
+            .write_blocks(BASE, &block(0x55))
+            .expect("Cached write failed");
+        cache
+            .write_blocks_fua(BASE, &block(0x66))
+            .expect("FUA write failed");
+        assert_eq!(writes.get(), 1);
+        cache.flush().expect("Cache flush failed");
+        assert_eq!(writes.get(), 1, "Block still dirty after FUA write");
+        drop(cache);
+
+        let mut buf = [0u8; 512];
+        emmc.read_blocks(BASE as u32, 1, &mut buf)
+            .expect("Read back failed");
+        assert_eq!(buf, block(0x66));
+        println!("Block cache test passed");
+    }
+
+    // Read back the file `make disk_img` puts in the rootfs partition
+    #[cfg(feature = "fs")]
+    fn test_boot_fs(emmc: EMmcHost, part: &Partition) {
+        let mut fs = BootFs::mount_partition(emmc, part).expect("Mount failed");
+        assert_eq!(fs.fs_type(), FsType::Ext4);
+
+        let data = fs.read("/boot/hello.txt").expect("File read failed");
+        println!("Read {} bytes from /boot/hello.txt", data.len());
+        assert_eq!(data.as_slice(), b"sdmmc boot file\n");
+    }
+
     #[test]
     fn test_platform() {
         let emmc_addr_ptr = get_device_addr("rockchip,dwcmshc-sdhci");
@@ -62,6 +258,10 @@ mod tests {
 
     pub struct ClkUnit(Rk3588Cru);
 
+    // The CRU registers are only touched from the test thread
+    unsafe impl Send for ClkUnit {}
+    unsafe impl Sync for ClkUnit {}
+
     impl ClkUnit {
         pub fn new(cru: Rk3588Cru) -> Self {
             ClkUnit(cru)
@@ -69,16 +269,16 @@ mod tests {
     }
 
     impl Clk for ClkUnit {
-        fn emmc_get_clk(&self) -> Result<u64, ClkError> {
-            if let Ok(rate) = self.0.mmc_get_clk(CCLK_EMMC) {
+        fn get_rate(&self, id: u32) -> Result<u64, ClkError> {
+            if let Ok(rate) = self.0.mmc_get_clk(id) {
                 Ok(rate as u64)
             } else {
                 Err(ClkError::InvalidClockRate)
             }
         }
 
-        fn emmc_set_clk(&self, rate: u64) -> Result<u64, ClkError> {
-            if let Ok(rate) = self.0.mmc_set_clk(CCLK_EMMC, rate as usize) {
+        fn set_rate(&self, id: u32, rate: u64) -> Result<u64, ClkError> {
+            if let Ok(rate) = self.0.mmc_set_clk(id, rate as usize) {
                 Ok(rate as u64)
             } else {
                 Err(ClkError::InvalidClockRate)
@@ -86,21 +286,25 @@ mod tests {
         }
     }
 
-    fn init_clk(clk_addr: usize) -> Result<(), ClkError> {
+    fn init_clk(clk_addr: usize) -> Box<dyn Clk> {
         let cru = ClkUnit::new(Rk3588Cru::new(
             core::ptr::NonNull::new(clk_addr as *mut u8).unwrap(),
         ));
 
-        let static_clk: &'static dyn Clk = Box::leak(Box::new(cru));
-        init_global_clk(static_clk);
-        Ok(())
+        Box::new(cru)
     }
 
     fn test_emmc(emmc_addr: usize, clock: usize) {
         // Initialize custom SDHCI controller
-        let mut emmc = EMmcHost::new(emmc_addr);
-        let _ = init_clk(clock);
+        let mut emmc = EMmcHost::new(emmc_addr)
+            .with_chip_config(EMmcChipConfig::rk3588_config())
+            .with_clk(init_clk(clock))
+            .with_clk_id(CCLK_EMMC);
 
+        test_card(&mut emmc);
+    }
+
+    fn test_card(emmc: &mut EMmcHost) {
         // Try to initialize the SD card
         match emmc.init() {
             Ok(_) => {
@@ -119,18 +323,34 @@ mod tests {
                     }
                 }
 
-                // Test reading the first block
-                println!("Attempting to read first block...");
+                // Test reading the first block of the first partition
+                println!("Attempting to read first partition block...");
                 let mut buffer: [u8; 512] = [0; 512];
 
-                match emmc.read_blocks(5034498, 1, &mut buffer) {
-                    Ok(_) => {
-                        println!("Successfully read first block!");
-                        let block_bytes: Vec<u8> = (0..512).map(|i| buffer[i]).collect();
-                        println!("First 16 bytes of first block: {:02X?}", block_bytes);
-                    }
+                let part = match read_partitions(&mut *emmc) {
+                    Ok(parts) => parts.into_iter().next(),
                     Err(e) => {
-                        warn!("Block read failed: {:?}", e);
+                        warn!("Partition table read failed: {:?}", e);
+                        None
+                    }
+                };
+
+                if let Some(part) = part {
+                    println!(
+                        "Partition {} '{}' at block {}",
+                        part.number, part.name, part.start
+                    );
+                    let read = PartitionDevice::new(&mut *emmc, &part)
+                        .and_then(|mut dev| BlockDevice::read_blocks(&mut dev, 0, &mut buffer));
+
+                    match read {
+                        Ok(_) => {
+                            println!("Successfully read first partition block!");
+                            println!("First 16 bytes of first block: {:02X?}", &buffer[..16]);
+                        }
+                        Err(e) => {
+                            warn!("Block read failed: {:?}", e);
+                        }
                     }
                 }
 
@@ -232,6 +452,71 @@ mod tests {
         println!("SD card test complete");
     }
 
+    // PCI class and subclass of an SD host controller
+    const PCI_CLASS_SYSTEM: u8 = 0x08;
+    const PCI_SUBCLASS_SDHCI: u8 = 0x05;
+
+    // Assign the PCI BARs and map the registers of the first SD host controller
+    fn get_sdhci_pci_addr() -> Option<usize> {
+        let PlatformInfoKind::DeviceTree(fdt) = &global_val().platform_info;
+        let fdt = fdt.get();
+
+        let pcie = fdt
+            .find_compatible(&["pci-host-ecam-generic"])
+            .next()?
+            .into_pci()?;
+
+        let mut bar_alloc = SimpleBarAllocator::default();
+        for range in pcie.ranges().ok()? {
+            match range.space {
+                PciSpace::Memory32 => bar_alloc.set_mem32(range.cpu_address as _, range.size as _),
+                PciSpace::Memory64 => bar_alloc.set_mem64(range.cpu_address, range.size),
+                _ => {}
+            }
+        }
+
+        let reg = pcie.node.reg()?.next()?;
+        let ecam = iomap((reg.address as usize).into(), reg.size.unwrap_or(0));
+        let mut root = RootComplexGeneric::new(ecam);
+
+        for elem in root.enumerate(None, Some(bar_alloc)) {
+            let Header::Endpoint(ep) = elem.header else {
+                continue;
+            };
+            if ep.base_class != PCI_CLASS_SYSTEM || ep.sub_class != PCI_SUBCLASS_SDHCI {
+                continue;
+            }
+
+            info!("Found SDHCI {:#06x}:{:#06x}", ep.vendor_id, ep.device_id);
+
+            let (addr, size) = match &ep.bar {
+                BarVec::Memory32(bars) => bars
+                    .iter()
+                    .flatten()
+                    .next()
+                    .map(|bar| (bar.address as usize, bar.size as usize))?,
+                BarVec::Memory64(bars) => bars
+                    .iter()
+                    .flatten()
+                    .next()
+                    .map(|bar| (bar.address as usize, bar.size as usize))?,
+                BarVec::Io(_) => continue,
+            };
+
+            // Enumeration leaves memory decoding off after assigning the BARs
+            ep.update_command(elem.root, |cmd| {
+                cmd | CommandRegister::MEMORY_ENABLE | CommandRegister::BUS_MASTER_ENABLE
+            });
+
+            let start = addr & !(page_size() - 1);
+            let end = (addr + size + page_size() - 1) & !(page_size() - 1);
+            let base = iomap(start.into(), end - start).as_ptr() as usize;
+            return Some(base + (addr - start));
+        }
+
+        None
+    }
+
     fn get_device_addr(dtc_str: &str) -> NonNull<u8> {
         let PlatformInfoKind::DeviceTree(fdt) = &global_val().platform_info;
         let fdt = fdt.get();